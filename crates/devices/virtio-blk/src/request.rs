@@ -24,6 +24,7 @@
 //! approach.
 
 use std::fmt::{self, Display};
+use std::mem::size_of;
 use std::result;
 
 use crate::defs::{
@@ -242,6 +243,72 @@ impl Request {
     }
 }
 
+/// Describes the layout of a descriptor chain that holds a virtio block request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlkChainShape {
+    /// The address of the request header.
+    header_addr: GuestAddress,
+    /// The total length of the data descriptors.
+    data_len: u64,
+    /// The address where the device should write the request status.
+    status_addr: GuestAddress,
+}
+
+impl BlkChainShape {
+    /// Returns the address of the request header.
+    pub fn header_addr(&self) -> GuestAddress {
+        self.header_addr
+    }
+
+    /// Returns the total length of the data descriptors.
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    /// Returns the status address.
+    pub fn status_addr(&self) -> GuestAddress {
+        self.status_addr
+    }
+}
+
+/// Checks that `chain` has the structure of a virtio block request, and returns its shape.
+///
+/// The chain has to start with a device-readable descriptor that can hold the request header,
+/// followed by zero or more data descriptors, and end with a device-writable descriptor for the
+/// status byte. The chain itself is not consumed, and the contents of the header are not
+/// validated (see [`Request::parse`](struct.Request.html#method.parse) for that).
+pub fn validate_blk_request<M: GuestAddressSpace>(
+    chain: &DescriptorChain<M>,
+) -> Result<BlkChainShape> {
+    let mut descs = chain.clone();
+
+    let header = descs.next().ok_or(Error::DescriptorChainTooShort)?;
+    if header.is_write_only() {
+        return Err(Error::UnexpectedWriteOnlyDescriptor);
+    }
+    if (header.len() as usize) < size_of::<RequestHeader>() {
+        return Err(Error::DescriptorLengthTooSmall);
+    }
+
+    // The last descriptor of the chain holds the status, and everything in between the header
+    // and the status is data. Like in `Request::parse`, a descriptor that points to a next one
+    // which can't be read doesn't end the chain.
+    let mut status = descs.next().ok_or(Error::DescriptorChainTooShort)?;
+    let mut data_len = 0;
+    while status.has_next() {
+        data_len += u64::from(status.len());
+        status = descs.next().ok_or(Error::DescriptorChainTooShort)?;
+    }
+
+    Request::check_status_desc::<<M>::M>(chain.memory(), status)?;
+
+    Ok(BlkChainShape {
+        header_addr: header.addr(),
+        data_len,
+        status_addr: status.addr(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -501,4 +568,117 @@ mod tests {
         let mut chain = build_desc_chain(&mem, &v[..2]);
         assert!(Request::parse(&mut chain).is_ok());
     }
+
+    #[test]
+    fn test_validate_blk_request() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+
+        // Valid chain with two data descriptors.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, 0, 0),
+            Descriptor::new(0x20_0000, 0x100, 0, 0),
+            Descriptor::new(0x30_0000, 0x200, VIRTQ_DESC_F_WRITE, 0),
+            Descriptor::new(0x40_0000, 0x1, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..4]);
+        let shape = validate_blk_request(&chain).unwrap();
+        assert_eq!(shape.header_addr(), GuestAddress(0x10_0000));
+        assert_eq!(shape.data_len(), 0x300);
+        assert_eq!(shape.status_addr(), GuestAddress(0x40_0000));
+        // The chain is not consumed.
+        assert_eq!(chain.count(), 4);
+
+        // Chain without a status descriptor.
+        let chain = build_desc_chain(&mem, &v[..1]);
+        assert_eq!(
+            validate_blk_request(&chain).unwrap_err(),
+            Error::DescriptorChainTooShort
+        );
+
+        // Valid chain without data descriptors.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, 0, 0),
+            Descriptor::new(0x40_0000, 0x1, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..2]);
+        let shape = validate_blk_request(&chain).unwrap();
+        assert_eq!(shape.data_len(), 0);
+        assert_eq!(shape.status_addr(), GuestAddress(0x40_0000));
+
+        // Device-writable header.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, VIRTQ_DESC_F_WRITE, 0),
+            Descriptor::new(0x40_0000, 0x1, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..2]);
+        assert_eq!(
+            validate_blk_request(&chain).unwrap_err(),
+            Error::UnexpectedWriteOnlyDescriptor
+        );
+
+        // Header descriptor too short.
+        let v = [
+            Descriptor::new(0x10_0000, 0x8, 0, 0),
+            Descriptor::new(0x40_0000, 0x1, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..2]);
+        assert_eq!(
+            validate_blk_request(&chain).unwrap_err(),
+            Error::DescriptorLengthTooSmall
+        );
+
+        // Device-readable status.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, 0, 0),
+            Descriptor::new(0x20_0000, 0x100, VIRTQ_DESC_F_WRITE, 0),
+            Descriptor::new(0x40_0000, 0x1, 0, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..3]);
+        assert_eq!(
+            validate_blk_request(&chain).unwrap_err(),
+            Error::UnexpectedReadOnlyDescriptor
+        );
+
+        // Empty status.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, 0, 0),
+            Descriptor::new(0x40_0000, 0x0, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..2]);
+        assert_eq!(
+            validate_blk_request(&chain).unwrap_err(),
+            Error::DescriptorLengthTooSmall
+        );
+
+        // Status descriptor pointing to an out of bounds next descriptor.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, 0, 0),
+            Descriptor::new(0x40_0000, 0x1, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..2]);
+        // The descriptor table starts at address 0, and holds 16 entries.
+        mem.write_obj(
+            VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+            GuestAddress(0x10 + 12),
+        )
+        .unwrap();
+        mem.write_obj(16u16, GuestAddress(0x10 + 14)).unwrap();
+        assert_eq!(
+            validate_blk_request(&chain).unwrap_err(),
+            Error::DescriptorChainTooShort
+        );
+
+        // Status outside of guest memory.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, 0, 0),
+            Descriptor::new(0x1100_0000, 0x1, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        let chain = build_desc_chain(&mem, &v[..2]);
+        assert_eq!(
+            validate_blk_request(&chain).unwrap_err(),
+            Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(GuestAddress(
+                0x1100_0000,
+            )))
+        );
+    }
 }
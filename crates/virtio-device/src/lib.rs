@@ -25,10 +25,6 @@ use virtio_queue::Queue;
 pub use mmio::VirtioMmioDevice;
 pub use virtio_config::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType};
 
-// TODO: Bring this (and other feature definitions) to the vm-virtio crate proper.
-// Using a local const temporarily until then.
-const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
-
 /// When the driver initializes the device, it lets the device know about the completed stages
 /// using the Device Status field.
 ///
@@ -143,14 +139,13 @@ pub trait VirtioDevice<M: GuestAddressSpace> {
                     return;
                 }
 
-                // Set the appropriate configuration flag for all queues if we offered the
-                //`VIRTIO_F_RING_EVENT_IDX` feature and the driver acknowledged it.
-                if self.driver_features() & (1 << VIRTIO_F_RING_EVENT_IDX) != 0 {
-                    for i in 0..self.num_queues() {
-                        // The unwrap is ok to use here because we're requesting mutable
-                        // references for queues at valid indices only.
-                        self.queue_mut(i).unwrap().set_event_idx(true);
-                    }
+                // Apply the negotiated features (such as `VIRTIO_F_RING_EVENT_IDX` or
+                // `VIRTIO_F_IN_ORDER`) that change the behavior of the queues.
+                let features = self.driver_features();
+                for i in 0..self.num_queues() {
+                    // The unwrap is ok to use here because we're requesting mutable
+                    // references for queues at valid indices only.
+                    self.queue_mut(i).unwrap().set_features(features);
                 }
 
                 self.set_device_status(status);
@@ -245,6 +240,7 @@ mod tests {
     use crate::virtio_config::tests::Dummy;
 
     use super::*;
+    use virtio_queue::{VIRTIO_F_IN_ORDER, VIRTIO_F_ORDER_PLATFORM, VIRTIO_F_RING_EVENT_IDX};
 
     #[test]
     fn test_ack_device_status() {
@@ -284,7 +280,8 @@ mod tests {
             assert_eq!(d.cfg.device_features & (1 << VIRTIO_F_RING_EVENT_IDX), 0);
 
             for q in d.cfg.queues.iter() {
-                assert_eq!(q.event_idx_enabled, false);
+                assert!(!q.features().has_event_idx());
+                assert!(!q.features().has_indirect());
             }
 
             // Revert status.
@@ -302,7 +299,33 @@ mod tests {
             assert_eq!(d.cfg.device_status, status);
 
             for q in d.cfg.queues.iter() {
-                assert_eq!(q.event_idx_enabled, true);
+                assert!(q.features().has_event_idx());
+                assert!(!q.order_platform());
+            }
+
+            // Revert status.
+            d.cfg.device_status = old_status;
+
+            d.cfg.device_features |= 1 << VIRTIO_F_ORDER_PLATFORM;
+            d.cfg.driver_features |= 1 << VIRTIO_F_ORDER_PLATFORM;
+            d.ack_device_status(status);
+            assert_eq!(d.cfg.device_status, status);
+
+            for q in d.cfg.queues.iter() {
+                assert!(q.order_platform());
+                assert!(!q.in_order());
+            }
+
+            // Revert status.
+            d.cfg.device_status = old_status;
+
+            d.cfg.device_features |= 1 << VIRTIO_F_IN_ORDER;
+            d.cfg.driver_features |= 1 << VIRTIO_F_IN_ORDER;
+            d.ack_device_status(status);
+            assert_eq!(d.cfg.device_status, status);
+
+            for q in d.cfg.queues.iter() {
+                assert!(q.in_order());
             }
         }
 
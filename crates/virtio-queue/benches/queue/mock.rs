@@ -191,6 +191,7 @@ pub struct MockSplitQueue<'a, M> {
 
 // Move to a defs module in vm-virtio?
 const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
 
 impl<'a, M: GuestMemory> MockSplitQueue<'a, M> {
@@ -247,6 +248,22 @@ impl<'a, M: GuestMemory> MockSplitQueue<'a, M> {
         self.update_avail_idx(head_idx);
     }
 
+    // Adds a chain of `len` descriptors, the last `writable` of which are device writable.
+    pub fn add_chain_with_writable(&mut self, len: u16, writable: u16) {
+        let head_idx = self.desc_table.build_chain(len);
+
+        let mut index = head_idx;
+        for pos in 0..len {
+            let mut desc = self.desc_table.load(index);
+            if pos >= len - writable {
+                desc.flags |= VIRTQ_DESC_F_WRITE;
+                self.desc_table.store(index, desc);
+            }
+            index = desc.next;
+        }
+        self.update_avail_idx(head_idx);
+    }
+
     pub fn add_indirect_chain(&mut self, len: u16) {
         let head_idx = self.desc_table.build_chain(1);
 
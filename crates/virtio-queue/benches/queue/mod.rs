@@ -80,6 +80,96 @@ pub fn benchmark_queue(c: &mut Criterion) {
         );
     }
 
+    // Walking a 128-descriptor indirect table, with the descriptors being either read one by
+    // one, or in runs of contiguous descriptors.
+    for prefetch in [false, true].iter().copied() {
+        bench_queue(
+            c,
+            &format!("indirect table walk (descriptor_prefetch={})", prefetch),
+            || {
+                let mut q = queue_with_chains(1, 128, true);
+                q.set_descriptor_prefetch(prefetch);
+                q
+            },
+            |mut q| {
+                let (num_chains, num_descriptors) = walk_queue(&mut q);
+                assert_eq!(num_chains, 1);
+                assert_eq!(num_descriptors, 128);
+            },
+        );
+    }
+
+    // Consuming one chain per `iter` call only reads the available ring `idx` field once per
+    // batch of available chains thanks to caching, unlike refreshing it before each call.
+    for refresh in [false, true].iter().copied() {
+        bench_queue(
+            c,
+            &format!("chain per iter (refresh avail idx={})", refresh),
+            || queue_with_chains(128, 1, false),
+            |mut q| {
+                for _ in 0..128 {
+                    if refresh {
+                        q.refresh_avail_idx().unwrap();
+                    }
+                    assert!(q.iter().unwrap().next().is_some());
+                }
+            },
+        );
+    }
+
+    // Walking thousands of single descriptor chains, with each chain either holding a clone of
+    // the `GuestMemoryAtomic` guard, or borrowing a guard obtained once for the whole batch.
+    let many_small_chains = || {
+        let mut mq = MockSplitQueue::new(&mem, 4096);
+        for _ in 0..4096 {
+            mq.add_chain(1);
+        }
+        mq.create_queue(GuestMemoryAtomic::new(mem.clone()))
+    };
+
+    for borrowed in [false, true].iter().copied() {
+        bench_queue(
+            c,
+            &format!("many small chains (borrowed memory={})", borrowed),
+            many_small_chains,
+            |mut q| {
+                let num_chains = if borrowed {
+                    let mem = q.memory();
+                    let iter = q.iter_with_memory(&*mem).unwrap();
+                    iter.map(|chain| chain.count()).sum::<usize>()
+                } else {
+                    q.iter().unwrap().map(|chain| chain.count()).sum::<usize>()
+                };
+                assert_eq!(num_chains, 4096);
+            },
+        );
+    }
+
+    // A chain made of a readable header followed by a writable payload. Walking the readable
+    // descriptors reads the whole chain, unless it's known to be ordered.
+    let header_and_payload = || {
+        let mut mq = MockSplitQueue::new(&mem, 256);
+        mq.add_chain_with_writable(64, 63);
+        mq.create_queue(GuestMemoryAtomic::new(mem.clone()))
+    };
+
+    for skip in [false, true].iter().copied() {
+        bench_queue(
+            c,
+            &format!("readable descriptors (skip_to_writable={})", skip),
+            header_and_payload,
+            |mut q| {
+                let chain = q.iter().unwrap().next().unwrap();
+                let readable = if skip {
+                    chain.readable().skip_to_writable()
+                } else {
+                    chain.readable()
+                };
+                assert_eq!(readable.count(), 1);
+            },
+        );
+    }
+
     bench_queue(c, "add used", empty_queue, |mut q| {
         for _ in 0..128 {
             q.add_used(123, 0x1000).unwrap();
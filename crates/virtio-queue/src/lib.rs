@@ -11,20 +11,72 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 //! A crate that exposes the virtio queue API.
-
+//!
+//! The crate can be used in `no_std` environments by disabling the default `std` feature.
+//! The `alloc` feature (which is implied by `std`) enables the interfaces that require
+//! dynamic memory allocation.
+//!
+//! Errors are reported through the `log` crate. When the `tracing` feature is enabled, the
+//! queue operations are additionally instrumented with [`tracing`](https://docs.rs/tracing)
+//! spans and events, which don't change the behavior of the queue:
+//! - a `virtio_queue::iter` span (debug level) is entered by `Queue::iter`, with the
+//!   `next_avail` field;
+//! - a `virtio_queue::add_used` span (debug level) is entered by `Queue::add_used`, with the
+//!   `head_index`, `len` and `next_used` fields;
+//! - a `virtio_queue::descriptor` event (trace level) is emitted for each descriptor read
+//!   by a `DescriptorChain`, with the `head_index`, `index`, `addr`, `len` and `flags` fields.
+//!
+//! The `fuzzing` feature exposes `parse_descriptor_chain`, a stable entry point into the
+//! descriptor chain parser for fuzz targets.
+//!
+//! The `strict` feature makes `Queue::iter` reject available ring indices that are too far
+//! ahead, as described for `Queue::validate_avail_idx`.
+//!
+//! A `Queue` accesses guest memory through a single `GuestAddressSpace`, but the direction of
+//! the accesses depends on the queue structure:
+//! - the descriptor tables (including the indirect ones) and the available ring are only read;
+//! - the used ring is written, and read as well when the used ring overflow check is enabled;
+//! - the buffers of the descriptors are only accessed by the `DescriptorChain` helpers that
+//!   copy data to writable buffers, copy data from readable buffers, or return slices over
+//!   the buffers.
+//!
+//! A `GuestMemory` wrapper can thus enforce a policy such as the queue only being allowed to
+//! write to the used ring.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![deny(missing_docs)]
 
-use std::cmp::min;
-use std::fmt::{self, Debug, Display};
-use std::mem::size_of;
-use std::num::Wrapping;
-use std::sync::atomic::{fence, Ordering};
-
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(any(
+    feature = "eventfd",
+    feature = "metrics",
+    feature = "available-callback"
+))]
+use alloc::sync::Arc;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt::{self, Debug, Display};
+use core::mem::size_of;
+use core::num::Wrapping;
+#[cfg(feature = "available-callback")]
+use core::sync::atomic::AtomicBool;
+#[cfg(feature = "metrics")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{fence, Ordering};
+
+#[cfg(feature = "alloc")]
+use vm_memory::VolatileSlice;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryError,
+    GuestMemoryRegion,
 };
 
 use log::error;
+#[cfg(feature = "eventfd")]
+use vmm_sys_util::eventfd::EventFd;
 
 /// Marks a buffer as continuing via the next field.
 pub const VIRTQ_DESC_F_NEXT: u16 = 0x1;
@@ -33,6 +85,18 @@ pub const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 /// Shows that the buffer contains a list of buffer descriptors.
 pub const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
 
+/// The maximum size of a split virtqueue allowed by the virtio standard.
+pub const VIRTQ_MAX_SIZE: u16 = 32768;
+
+/// The feature bit of VIRTIO_F_INDIRECT_DESC.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 28;
+/// The feature bit of VIRTIO_F_RING_EVENT_IDX.
+pub const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
+/// The feature bit of VIRTIO_F_IN_ORDER.
+pub const VIRTIO_F_IN_ORDER: u64 = 35;
+/// The feature bit of VIRTIO_F_ORDER_PLATFORM.
+pub const VIRTIO_F_ORDER_PLATFORM: u64 = 36;
+
 const VIRTQ_USED_ELEMENT_SIZE: u64 = 8;
 // Used ring header: flags (u16) + idx (u16)
 const VIRTQ_USED_RING_HEADER_SIZE: u64 = 4;
@@ -43,6 +107,10 @@ const VIRTQ_USED_RING_META_SIZE: u64 = VIRTQ_USED_RING_HEADER_SIZE + 2;
 // Used flags
 const VIRTQ_USED_F_NO_NOTIFY: u16 = 0x1;
 
+/// Set by the driver in the `flags` field of the available ring when it doesn't want to be
+/// interrupted for used buffers.
+pub const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+
 const VIRTQ_AVAIL_ELEMENT_SIZE: u64 = 2;
 // Avail ring header: flags(u16) + idx(u16)
 const VIRTQ_AVAIL_RING_HEADER_SIZE: u64 = 4;
@@ -68,6 +136,19 @@ pub enum Error {
     InvalidChain,
     /// Invalid descriptor index.
     InvalidDescriptorIndex,
+    /// The maximum queue size is zero, not a power of 2, or greater than `VIRTQ_MAX_SIZE`.
+    InvalidSize,
+    /// Used buffers are not returned in the order they were made available.
+    UsedOutOfOrder,
+    /// Adding used buffers would overwrite used ring entries not yet consumed by the driver.
+    UsedRingOverflow,
+    /// No descriptor chain was consumed without being added to the used ring yet.
+    NoChainInFlight,
+    /// Access outside of the buffer of a descriptor.
+    DescriptorOutOfBounds,
+    /// Failed to write to the interrupt `EventFd`.
+    #[cfg(feature = "eventfd")]
+    InterruptEvent(std::io::Error),
 }
 
 impl Display for Error {
@@ -80,15 +161,144 @@ impl Display for Error {
             InvalidIndirectDescriptor => write!(f, "invalid indirect descriptor"),
             InvalidIndirectDescriptorTable => write!(f, "invalid indirect descriptor table"),
             InvalidDescriptorIndex => write!(f, "invalid descriptor index"),
+            InvalidSize => write!(f, "invalid maximum queue size"),
+            UsedOutOfOrder => write!(f, "used buffers out of order"),
+            UsedRingOverflow => write!(f, "used ring overflow"),
+            NoChainInFlight => write!(f, "no descriptor chain in flight"),
+            DescriptorOutOfBounds => write!(f, "access outside of the descriptor buffer"),
+            #[cfg(feature = "eventfd")]
+            InterruptEvent(_) => write!(f, "failed to signal the interrupt event"),
+        }
+    }
+}
+
+impl Error {
+    /// Returns the kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        use self::Error::*;
+
+        match self {
+            GuestMemory(_) => ErrorKind::GuestMemory,
+            InvalidIndirectDescriptor => ErrorKind::InvalidIndirectDescriptor,
+            InvalidIndirectDescriptorTable => ErrorKind::InvalidIndirectDescriptorTable,
+            InvalidChain => ErrorKind::InvalidChain,
+            InvalidDescriptorIndex => ErrorKind::InvalidDescriptorIndex,
+            InvalidSize => ErrorKind::InvalidSize,
+            UsedOutOfOrder => ErrorKind::UsedOutOfOrder,
+            UsedRingOverflow => ErrorKind::UsedRingOverflow,
+            NoChainInFlight => ErrorKind::NoChainInFlight,
+            DescriptorOutOfBounds => ErrorKind::DescriptorOutOfBounds,
+            #[cfg(feature = "eventfd")]
+            InterruptEvent(_) => ErrorKind::InterruptEvent,
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
 
+/// The kind of an `Error`, without the details it carries.
+///
+/// Unlike `Error`, this can be copied, and is what `Queue::take_last_error` reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// See `Error::GuestMemory`.
+    GuestMemory,
+    /// See `Error::InvalidIndirectDescriptor`.
+    InvalidIndirectDescriptor,
+    /// See `Error::InvalidIndirectDescriptorTable`.
+    InvalidIndirectDescriptorTable,
+    /// See `Error::InvalidChain`.
+    InvalidChain,
+    /// See `Error::InvalidDescriptorIndex`.
+    InvalidDescriptorIndex,
+    /// See `Error::InvalidSize`.
+    InvalidSize,
+    /// See `Error::UsedOutOfOrder`.
+    UsedOutOfOrder,
+    /// See `Error::UsedRingOverflow`.
+    UsedRingOverflow,
+    /// See `Error::NoChainInFlight`.
+    NoChainInFlight,
+    /// See `Error::DescriptorOutOfBounds`.
+    DescriptorOutOfBounds,
+    /// See `Error::InterruptEvent`.
+    #[cfg(feature = "eventfd")]
+    InterruptEvent,
+}
+
+/// Reasons for a virtio queue configuration to be invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueError {
+    /// The queue is not marked ready.
+    NotReady,
+    /// The queue size is zero, not a power of 2, or greater than the maximum size.
+    InvalidSize,
+    /// The maximum queue size is zero, not a power of 2, or greater than `VIRTQ_MAX_SIZE`.
+    InvalidMaxSize,
+    /// The descriptor table goes out of the bounds of guest memory.
+    DescTableOutOfBounds,
+    /// The available ring goes out of the bounds of guest memory.
+    AvailRingOutOfBounds,
+    /// The used ring goes out of the bounds of guest memory.
+    UsedRingOutOfBounds,
+    /// The descriptor table is not aligned to 16 bytes.
+    DescTableMisaligned,
+    /// The available ring is not aligned to 2 bytes.
+    AvailRingMisaligned,
+    /// The used ring is not aligned to 4 bytes.
+    UsedRingMisaligned,
+    /// The descriptor table overlaps the available ring.
+    DescTableOverlapsAvailRing,
+    /// The descriptor table overlaps the used ring.
+    DescTableOverlapsUsedRing,
+    /// The available ring overlaps the used ring.
+    AvailRingOverlapsUsedRing,
+    /// The descriptor table doesn't fit in the area reserved for it.
+    DescTableTooSmall,
+    /// The available ring doesn't fit in the area reserved for it.
+    AvailRingTooSmall,
+    /// The used ring doesn't fit in the area reserved for it.
+    UsedRingTooSmall,
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::QueueError::*;
+
+        match self {
+            NotReady => write!(f, "queue not ready"),
+            InvalidSize => write!(f, "invalid queue size"),
+            InvalidMaxSize => write!(f, "invalid maximum queue size"),
+            DescTableOutOfBounds => write!(f, "descriptor table out of bounds"),
+            AvailRingOutOfBounds => write!(f, "available ring out of bounds"),
+            UsedRingOutOfBounds => write!(f, "used ring out of bounds"),
+            DescTableMisaligned => write!(f, "misaligned descriptor table"),
+            AvailRingMisaligned => write!(f, "misaligned available ring"),
+            UsedRingMisaligned => write!(f, "misaligned used ring"),
+            DescTableOverlapsAvailRing => {
+                write!(f, "descriptor table overlaps the available ring")
+            }
+            DescTableOverlapsUsedRing => write!(f, "descriptor table overlaps the used ring"),
+            AvailRingOverlapsUsedRing => write!(f, "available ring overlaps the used ring"),
+            DescTableTooSmall => write!(f, "descriptor table area too small for the queue size"),
+            AvailRingTooSmall => write!(f, "available ring area too small for the queue size"),
+            UsedRingTooSmall => write!(f, "used ring area too small for the queue size"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for QueueError {}
+
 /// A virtio descriptor constraints with C representation
+///
+/// The fields are stored in little-endian byte order, like in guest memory, so descriptors can
+/// be read from and written to the descriptor table as they are. The constructor and the
+/// accessors convert from and to the byte order of the host, which is a no-op on little-endian
+/// targets.
 #[repr(C)]
-#[derive(Default, Clone, Copy, Debug)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
 pub struct Descriptor {
     /// Guest physical address of device specific data
     addr: u64,
@@ -106,31 +316,93 @@ pub struct Descriptor {
 
 #[allow(clippy::len_without_is_empty)]
 impl Descriptor {
+    /// Create a new `Descriptor` instance.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use virtio_queue::{Descriptor, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    /// // A chain made of a device-readable buffer, followed by a device-writable one.
+    /// let descs = [
+    ///     Descriptor::new(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1),
+    ///     Descriptor::new(0x2000, 0x100, VIRTQ_DESC_F_WRITE, 0),
+    /// ];
+    ///
+    /// assert!(descs[0].has_next());
+    /// assert_eq!(descs[0].next(), 1);
+    /// assert!(descs[1].is_write_only());
+    /// ```
+    pub fn new(addr: u64, len: u32, flags: u16, next: u16) -> Self {
+        Descriptor {
+            addr: addr.to_le(),
+            len: len.to_le(),
+            flags: flags.to_le(),
+            next: next.to_le(),
+        }
+    }
+
     /// Return the guest physical address of descriptor buffer
     pub fn addr(&self) -> GuestAddress {
-        GuestAddress(self.addr)
+        GuestAddress(u64::from_le(self.addr))
     }
 
     /// Return the length of descriptor buffer
     pub fn len(&self) -> u32 {
-        self.len
+        u32::from_le(self.len)
     }
 
     /// Return the flags for this descriptor, including next, write and indirect
     /// bits
     pub fn flags(&self) -> u16 {
-        self.flags
+        u16::from_le(self.flags)
     }
 
     /// Return the value stored in the `next` field of the descriptor.
     pub fn next(&self) -> u16 {
-        self.next
+        u16::from_le(self.next)
+    }
+
+    /// Return the guest physical address and the length of the descriptor buffer.
+    pub fn addr_range(&self) -> (GuestAddress, u32) {
+        (self.addr(), self.len())
+    }
+
+    /// Return the guest physical address and the length of the part of the descriptor buffer
+    /// starting `offset` bytes in, and spanning at most `len` bytes.
+    ///
+    /// The length is clamped to the end of the buffer, so for example the header at the start
+    /// of a buffer and the rest of it can be obtained with `subslice(0, header_len)` and
+    /// `subslice(header_len, u32::MAX)`. An `offset` equal to the length of the buffer results
+    /// in an empty range, while `Error::DescriptorOutOfBounds` is returned if it's past the end
+    /// of the buffer, or if the range doesn't fit in the guest physical address space.
+    pub fn subslice(&self, offset: u32, len: u32) -> Result<(GuestAddress, u32), Error> {
+        let (addr, buf_len) = self.addr_range();
+        if offset > buf_len {
+            error!(
+                "Offset {} is past the end of the descriptor buffer of length {}",
+                offset, buf_len
+            );
+            return Err(Error::DescriptorOutOfBounds);
+        }
+
+        let len = min(len, buf_len - offset);
+        addr.checked_add(u64::from(offset))
+            .filter(|start| len == 0 || start.checked_add(u64::from(len) - 1).is_some())
+            .map(|start| (start, len))
+            .ok_or_else(|| {
+                error!(
+                    "Descriptor buffer range at {:#x} overflows the guest physical address space",
+                    addr.raw_value()
+                );
+                Error::DescriptorOutOfBounds
+            })
     }
 
     /// Check whether this is an indirect descriptor.
+    ///
+    /// The restrictions on the flags of indirect descriptors are enforced when the indirect
+    /// table is processed by a `DescriptorChain`.
     pub fn is_indirect(&self) -> bool {
-        // TODO: The are a couple of restrictions in terms of which flags combinations are
-        // actually valid for indirect descriptors. Implement those checks as well somewhere.
         self.flags() & VIRTQ_DESC_F_INDIRECT != 0
     }
 
@@ -144,22 +416,76 @@ impl Descriptor {
     /// If this is false, this descriptor is read only.
     /// Write only means the the emulated device can write and the driver can read.
     pub fn is_write_only(&self) -> bool {
-        self.flags & VIRTQ_DESC_F_WRITE != 0
+        self.flags() & VIRTQ_DESC_F_WRITE != 0
     }
 }
 
 unsafe impl ByteValued for Descriptor {}
 
+/// The direction of the buffer pointed to by a descriptor, from the device point of view.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The buffer is read by the device.
+    Readable,
+    /// The buffer is written by the device (VIRTQ_DESC_F_WRITE is set).
+    Writable,
+}
+
+// The maximum number of descriptors read at once when prefetching is enabled.
+const DESCRIPTOR_PREFETCH_LEN: usize = 16;
+
+// A run of contiguous descriptors, which can be read from guest memory with a single access.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct DescriptorRun([Descriptor; DESCRIPTOR_PREFETCH_LEN]);
+
+unsafe impl ByteValued for DescriptorRun {}
+
+// The descriptors prefetched by a `DescriptorChain`, which are the `len` descriptors starting
+// at index `start` of the descriptor table at `table`.
+#[derive(Clone, Copy, Debug, Default)]
+struct DescriptorCache {
+    table: GuestAddress,
+    start: u16,
+    len: u16,
+    // Whether the descriptor to read next follows the previous one in the same table, which
+    // is when reading a run of descriptors is expected to pay off.
+    sequential: bool,
+    descs: DescriptorRun,
+}
+
 /// A virtio descriptor chain.
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct DescriptorChain<M: GuestAddressSpace> {
     mem: M::T,
     desc_table: GuestAddress,
     queue_size: u16,
+    // The queue descriptor table and size, which `desc_table` and `queue_size` no longer hold
+    // after switching to an indirect descriptor table.
+    head_desc_table: GuestAddress,
+    head_queue_size: u16,
     head_index: u16,
     next_index: u16,
     ttl: u16,
+    // The number of descriptors returned so far.
+    consumed: u16,
     is_indirect: bool,
+    // The `(address, size)` pairs of the available and used rings, which indirect descriptor
+    // tables must not overlap, if the check is enabled.
+    ring_ranges: Option<[(GuestAddress, u64); 2]>,
+    // The maximum number of descriptors accepted in an indirect descriptor table.
+    max_indirect_table_len: u16,
+    // The maximum total length of the buffers of the chain accepted by the length helpers.
+    max_chain_bytes: u64,
+    // Whether descriptors referring to indirect tables are accepted.
+    indirect_allowed: bool,
+    // The descriptors read ahead of the current one, if prefetching is enabled.
+    prefetch: Option<DescriptorCache>,
+    // A bitmap of the descriptors of the indirect table visited so far, used to detect cycles.
+    #[cfg(feature = "alloc")]
+    visited: Option<Vec<u64>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<QueueMetrics>>,
 }
 
 impl<M: GuestAddressSpace> DescriptorChain<M> {
@@ -174,10 +500,22 @@ impl<M: GuestAddressSpace> DescriptorChain<M> {
             mem,
             desc_table,
             queue_size,
+            head_desc_table: desc_table,
+            head_queue_size: queue_size,
             head_index,
             next_index: head_index,
             ttl,
+            consumed: 0,
             is_indirect: false,
+            ring_ranges: None,
+            max_indirect_table_len: u16::MAX,
+            max_chain_bytes: u64::MAX,
+            indirect_allowed: true,
+            prefetch: None,
+            #[cfg(feature = "alloc")]
+            visited: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
         }
     }
 
@@ -186,11 +524,77 @@ impl<M: GuestAddressSpace> DescriptorChain<M> {
         Self::with_ttl(mem, desc_table, queue_size, queue_size, head_index)
     }
 
+    /// Return a new `DescriptorChain` positioned at the head of this chain.
+    ///
+    /// Unlike `clone`, which preserves the current position, this allows walking the whole chain
+    /// again regardless of how much of it was consumed (e.g. a first pass to compute the length
+    /// of the chain, and a second pass to copy the data).
+    pub fn clone_from_head(&self) -> Self {
+        let mut chain = Self::new(
+            self.mem.clone(),
+            self.head_desc_table,
+            self.head_queue_size,
+            self.head_index,
+        );
+        chain.ring_ranges = self.ring_ranges;
+        chain.max_indirect_table_len = self.max_indirect_table_len;
+        chain.max_chain_bytes = self.max_chain_bytes;
+        chain.indirect_allowed = self.indirect_allowed;
+        chain.prefetch = self.prefetch.map(|_| DescriptorCache::default());
+        #[cfg(feature = "metrics")]
+        {
+            chain.metrics = self.metrics.clone();
+        }
+        chain
+    }
+
+    /// Check whether any two descriptors of the chain point to overlapping guest memory ranges.
+    ///
+    /// This is legal, but usually points to a driver bug. It is meant as a debugging aid, and
+    /// compares each pair of descriptors of the whole chain (starting from the head, regardless
+    /// of the position of `self`), which is quadratic in the length of the chain.
+    pub fn has_overlapping_buffers(&self) -> Result<bool, Error> {
+        let mut chain = self.clone_from_head();
+        while let Some(desc) = chain.next_descriptor() {
+            let desc = desc?;
+            let mut others = chain.clone();
+            while let Some(other) = others.next_descriptor() {
+                let other = other?;
+                if ranges_overlap(
+                    desc.addr(),
+                    u64::from(desc.len()),
+                    other.addr(),
+                    u64::from(other.len()),
+                ) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
     /// Get the descriptor index of the chain header
     pub fn head_index(&self) -> u16 {
         self.head_index
     }
 
+    /// Return the number of descriptors returned by the chain so far.
+    ///
+    /// Descriptors referring to indirect tables are not counted, since they are not returned
+    /// themselves, but the descriptors of the tables are.
+    pub fn descriptors_consumed(&self) -> u16 {
+        self.consumed
+    }
+
+    /// Return the maximum number of descriptors the chain can still return.
+    ///
+    /// This is the number of descriptors left before the chain is deemed to have a cycle, which
+    /// starts at the size of the queue, and at the length of the table after switching to an
+    /// indirect descriptor table. It drops to 0 once the chain ends or an error is returned.
+    pub fn remaining_ttl(&self) -> u16 {
+        self.ttl
+    }
+
     /// Return a `GuestMemory` object that can be used to access the buffers
     /// pointed to by the descriptor chain.
     pub fn memory(&self) -> &M::M {
@@ -202,6 +606,7 @@ impl<M: GuestAddressSpace> DescriptorChain<M> {
         DescriptorChainRwIter {
             chain: self,
             writable: false,
+            ordered: false,
         }
     }
 
@@ -210,48 +615,327 @@ impl<M: GuestAddressSpace> DescriptorChain<M> {
         DescriptorChainRwIter {
             chain: self,
             writable: true,
+            ordered: false,
         }
     }
 
-    // Alters the internal state of the `DescriptorChain` to switch iterating over an
-    // indirect descriptor table defined by `desc`.
-    fn process_indirect_descriptor(&mut self, desc: Descriptor) -> Result<(), Error> {
-        if self.is_indirect {
-            return Err(Error::InvalidIndirectDescriptor);
+    /// Returns an iterator that checks the access permissions of the guest memory regions
+    /// backing each descriptor buffer before yielding it.
+    ///
+    /// Writable descriptors must point to guest-writable memory, and readable descriptors must
+    /// point to guest-readable memory. A mismatch is reported as `Error::InvalidChain`, and ends
+    /// the iteration.
+    pub fn permission_checked(self) -> DescriptorChainPermIter<M>
+    where
+        <M::M as GuestMemory>::R: RegionPermissions,
+    {
+        DescriptorChainPermIter { chain: self }
+    }
+
+    /// Returns an iterator that yields all the descriptors in the chain, in order, together with
+    /// their direction.
+    pub fn directional(self) -> impl Iterator<Item = (Direction, Descriptor)> {
+        self.map(|desc| {
+            let direction = if desc.is_write_only() {
+                Direction::Writable
+            } else {
+                Direction::Readable
+            };
+            (direction, desc)
+        })
+    }
+
+    /// Return the total length of the readable and writable descriptors of the chain, as a
+    /// `(readable, writable)` pair.
+    ///
+    /// Only the descriptors that haven't been yielded yet are taken into account. The chain is
+    /// walked using a copy of the iteration state, so `self` is not affected. Each total
+    /// saturates at `u32::MAX` instead of overflowing. Errors are reported like for
+    /// `checked_total_len`, including `Error::InvalidChain` when the sum of both totals exceeds
+    /// the limit set with `Queue::set_max_chain_bytes`.
+    pub fn total_len(&self) -> Result<(u32, u32), Error> {
+        let saturate = |len: u64| len.min(u64::from(u32::MAX)) as u32;
+        self.checked_total_len()
+            .map(|(readable, writable)| (saturate(readable), saturate(writable)))
+    }
+
+    /// Return the total length of the readable and writable descriptors of the chain, as a
+    /// `(readable, writable)` pair, without saturating.
+    ///
+    /// Only the descriptors that haven't been yielded yet are taken into account, and `self`
+    /// is not affected. Errors encountered while walking the chain are returned, and
+    /// `Error::InvalidChain` is returned as soon as the running total of both lengths exceeds
+    /// the limit set with `Queue::set_max_chain_bytes`.
+    pub fn checked_total_len(&self) -> Result<(u64, u64), Error> {
+        let mut chain = self.clone();
+        let (mut readable, mut writable) = (0u64, 0u64);
+
+        while let Some(desc) = chain.next_descriptor() {
+            let desc = desc?;
+            if desc.is_write_only() {
+                writable += u64::from(desc.len());
+            } else {
+                readable += u64::from(desc.len());
+            }
+
+            // A chain has at most `u16::MAX` descriptors of at most `u32::MAX` bytes each, so
+            // the sum can't overflow.
+            if readable + writable > self.max_chain_bytes {
+                error!(
+                    "Descriptor chain length exceeds the maximum of {} bytes",
+                    self.max_chain_bytes
+                );
+                return Err(Error::InvalidChain);
+            }
         }
 
-        let table_len = (desc.len as usize) / VIRTQ_DESCRIPTOR_SIZE;
-        // Check the target indirect descriptor table is correctly aligned.
-        if desc.addr().raw_value() & (VIRTQ_DESCRIPTOR_SIZE as u64 - 1) != 0
-            || (desc.len as usize) & (VIRTQ_DESCRIPTOR_SIZE - 1) != 0
-            || table_len > usize::from(std::u16::MAX)
-        {
-            return Err(Error::InvalidIndirectDescriptorTable);
+        Ok((readable, writable))
+    }
+
+    /// Check that the writable descriptors of the chain can hold at least `min` bytes.
+    ///
+    /// The remaining descriptors are walked using a copy of the chain, so the iteration state
+    /// of `self` is not affected. Returns `Error::InvalidChain` if the total length of the
+    /// writable descriptors is smaller than `min`, or if the total length of the chain exceeds
+    /// the limit set with `Queue::set_max_chain_bytes`.
+    pub fn require_writable_bytes(&self, min: u64) -> Result<(), Error> {
+        let (_, writable_len) = self.checked_total_len()?;
+
+        if writable_len < min {
+            error!(
+                "Descriptor chain has {} writable bytes, {} are required",
+                writable_len, min
+            );
+            return Err(Error::InvalidChain);
         }
 
-        self.desc_table = desc.addr();
-        self.queue_size = table_len as u16;
-        self.next_index = 0;
-        self.ttl = self.queue_size;
-        self.is_indirect = true;
+        Ok(())
+    }
+
+    /// Run the `checks` on the descriptors of the chain.
+    ///
+    /// Only the descriptors that haven't been yielded yet are checked, and `self` is not
+    /// affected. The first error encountered while walking the chain is returned, and the
+    /// failed checks are reported with `Error::GuestMemory` for buffers that are not contained
+    /// in guest memory, or with `Error::InvalidChain` otherwise.
+    pub fn validate(&self, checks: &ChainValidation) -> Result<(), Error> {
+        let mut chain = self.clone();
+        let mut seen_writable = false;
+
+        while let Some(desc) = chain.next_descriptor() {
+            let desc = desc?;
+
+            if checks.addresses_in_range && !self.mem.check_range(desc.addr(), desc.len() as usize)
+            {
+                error!(
+                    "Descriptor buffer at {:x} with length {} is out of range",
+                    desc.addr().raw_value(),
+                    desc.len()
+                );
+                return Err(Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(
+                    desc.addr(),
+                )));
+            }
+
+            if desc.is_write_only() {
+                if checks.no_empty_writable && desc.len() == 0 {
+                    error!("Writable descriptor with a length of 0");
+                    return Err(Error::InvalidChain);
+                }
+                seen_writable = true;
+            } else if checks.readable_before_writable && seen_writable {
+                error!("Readable descriptor after a writable one");
+                return Err(Error::InvalidChain);
+            }
+        }
 
         Ok(())
     }
-}
 
-impl<M: GuestAddressSpace> Iterator for DescriptorChain<M> {
-    type Item = Descriptor;
+    /// Check that no readable descriptor of the chain follows a writable one, as required by
+    /// the specification.
+    ///
+    /// This runs only the `readable_before_writable` check of `validate`, and returns
+    /// `Error::InvalidChain` if it fails, or the first error encountered while walking the
+    /// chain. Only the descriptors that haven't been yielded yet are checked, and `self` is
+    /// not affected.
+    pub fn validate_order(&self) -> Result<(), Error> {
+        self.validate(&ChainValidation {
+            addresses_in_range: false,
+            no_empty_writable: false,
+            readable_before_writable: true,
+        })
+    }
 
-    /// Returns the next descriptor in this descriptor chain, if there is one.
+    /// Return copies of the descriptors of the chain, in order, with any indirect table
+    /// resolved.
     ///
-    /// Note that this is distinct from the next descriptor chain returned by
-    /// [`AvailIter`](struct.AvailIter.html), which is the head of the next
-    /// _available_ descriptor chain.
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.ttl == 0 || self.next_index >= self.queue_size {
+    /// Only the descriptors that haven't been yielded yet are returned. The chain is walked
+    /// using a copy of the iteration state, so `self` is not affected. Unlike `collect`ing the
+    /// chain as an iterator, errors are returned instead of truncating the result.
+    #[cfg(feature = "alloc")]
+    pub fn collect_descriptors(&self) -> Result<Vec<Descriptor>, Error> {
+        let mut chain = self.clone();
+        core::iter::from_fn(|| chain.next_descriptor()).collect()
+    }
+
+    /// Split the buffers of the writable (or readable) descriptors of the chain into
+    /// `(address, length)` segments that are at most `seg_size` bytes long.
+    ///
+    /// Buffers are split in order, and each one is covered by its own segments, so a buffer
+    /// that isn't larger than `seg_size` results in a single segment. Empty buffers don't
+    /// result in any segment, and a `seg_size` of 0 means the buffers are not split. Only the
+    /// descriptors that haven't been yielded yet are taken into account, and `self` is not
+    /// affected.
+    #[cfg(feature = "alloc")]
+    pub fn segments(
+        &self,
+        writable: bool,
+        seg_size: u64,
+    ) -> Result<Vec<(GuestAddress, u64)>, Error> {
+        let mut descriptors = DescriptorChainRwIter {
+            chain: self.clone(),
+            writable,
+            ordered: false,
+        };
+        let mut segments = Vec::new();
+        while let Some(desc) = descriptors.next_descriptor() {
+            let desc = desc?;
+            let len = u64::from(desc.len());
+            let seg_size = if seg_size == 0 { len } else { seg_size };
+            let mut offset = 0;
+            while offset < len {
+                let seg_len = min(seg_size, len - offset);
+                let addr = desc.addr().checked_add(offset).ok_or(Error::InvalidChain)?;
+                segments.push((addr, seg_len));
+                offset += seg_len;
+            }
+        }
+        Ok(segments)
+    }
+
+    /// Return a `VolatileSlice` over the buffer of each descriptor of the chain, in order, with
+    /// any indirect table resolved.
+    ///
+    /// This allows accessing the buffers in place, for example for scatter/gather I/O, instead
+    /// of copying through an intermediate buffer. Each buffer has to be contained in a single
+    /// region of guest memory, and `Error::GuestMemory` is returned if that's not the case,
+    /// or if a buffer is not mapped. Only the descriptors that haven't been yielded yet are
+    /// taken into account, and `self` is not affected.
+    #[cfg(feature = "alloc")]
+    pub fn buffer_slices(&self) -> Result<Vec<VolatileSlice<'_>>, Error> {
+        let mut chain = self.clone();
+        core::iter::from_fn(|| chain.next_descriptor())
+            .map(|desc| {
+                let desc = desc?;
+                self.mem
+                    .get_slice(desc.addr(), desc.len() as usize)
+                    .map_err(|e| {
+                        error!(
+                            "Failed to access the buffer of {} bytes at {:#x}: {}",
+                            desc.len(),
+                            desc.addr().raw_value(),
+                            e
+                        );
+                        Error::GuestMemory(e)
+                    })
+            })
+            .collect()
+    }
+
+    /// Copy `buf` to the buffers of the writable descriptors of the chain, in order, and return
+    /// the number of bytes written.
+    ///
+    /// The data is split across the descriptor buffers as needed, and it's truncated if the
+    /// writable descriptors can't hold all of it. Only the descriptors that haven't been
+    /// yielded yet are taken into account, and `self` is not affected.
+    pub fn write_all_to_writable(&self, buf: &[u8]) -> Result<usize, Error> {
+        self.copy_buffers(true, buf.len(), |addr, offset, len| {
+            self.mem.write_slice(&buf[offset..offset + len], addr)
+        })
+    }
+
+    /// Fill `buf` from the buffers of the readable descriptors of the chain, in order, and
+    /// return the number of bytes read.
+    ///
+    /// The data is gathered across the descriptor buffers as needed, and only part of `buf` is
+    /// filled if the readable descriptors hold less data. Only the descriptors that haven't
+    /// been yielded yet are taken into account, and `self` is not affected.
+    pub fn read_all_from_readable(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.copy_buffers(false, buf.len(), |addr, offset, len| {
+            self.mem.read_slice(&mut buf[offset..offset + len], addr)
+        })
+    }
+
+    // Walk the writable (or readable) descriptors of a copy of the chain, and call `copy` with
+    // the address, the offset in the caller buffer and the length of each access, until
+    // `total` bytes are covered. Returns the number of bytes copied.
+    fn copy_buffers<F>(&self, writable: bool, total: usize, mut copy: F) -> Result<usize, Error>
+    where
+        F: FnMut(GuestAddress, usize, usize) -> Result<(), GuestMemoryError>,
+    {
+        let mut iter = if writable {
+            self.clone().writable()
+        } else {
+            self.clone().readable()
+        };
+        let mut done = 0;
+
+        while done < total {
+            let desc = match iter.next_descriptor() {
+                Some(desc) => desc?,
+                None => break,
+            };
+            let len = min(desc.len() as usize, total - done);
+            copy(desc.addr(), done, len).map_err(|e| {
+                error!(
+                    "Failed to access the buffer of {} bytes at {:#x}: {}",
+                    desc.len(),
+                    desc.addr().raw_value(),
+                    e
+                );
+                Error::GuestMemory(e)
+            })?;
+            done += len;
+        }
+
+        Ok(done)
+    }
+
+    /// Returns the next descriptor in this descriptor chain, if there is one, or the error
+    /// that prevented it from being retrieved.
+    ///
+    /// This is the fallible counterpart of `Iterator::next`. Once an error is returned, the
+    /// chain is considered finished and subsequent calls return `None`. In particular, a chain
+    /// that does not end after `queue_size` descriptors (which happens for example when a
+    /// `next` field points back to an earlier descriptor) causes an `Error::InvalidChain` to be
+    /// returned, instead of being silently truncated.
+    pub fn next_descriptor(&mut self) -> Option<Result<Descriptor, Error>> {
+        if self.ttl == 0 {
             return None;
         }
 
+        if self.next_index >= self.queue_size {
+            self.ttl = 0;
+            return Some(Err(Error::InvalidDescriptorIndex));
+        }
+
+        // Revisiting a descriptor of an indirect table means the chain has a cycle, which is
+        // reported right away instead of walking the cycle until the TTL runs out.
+        #[cfg(feature = "alloc")]
+        if let Some(visited) = self.visited.as_mut() {
+            let (word, bit) = (usize::from(self.next_index / 64), self.next_index % 64);
+            if visited[word] & (1 << bit) != 0 {
+                error!(
+                    "Cycle in indirect descriptor table at descriptor {}",
+                    self.next_index
+                );
+                self.ttl = 0;
+                return Some(Err(Error::InvalidChain));
+            }
+            visited[word] |= 1 << bit;
+        }
+
         // It's ok to use `unchecked_add` here because we previously verify the index does not
         // exceed the queue size, and the descriptor table location is expected to have been
         // validate before (for example, before activating a device). Moreover, this cannot
@@ -260,14 +944,48 @@ impl<M: GuestAddressSpace> Iterator for DescriptorChain<M> {
             .desc_table
             .unchecked_add(self.next_index as u64 * size_of::<Descriptor>() as u64);
 
-        let desc = self.mem.read_obj::<Descriptor>(desc_addr).ok()?;
+        // Guest memory accesses are split at region boundaries, so descriptor tables (and even
+        // single descriptors) don't have to be contained in a single region of guest memory.
+        let desc = match self.read_descriptor(desc_addr) {
+            Ok(desc) => desc,
+            Err(e) => {
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    QueueMetrics::add(&metrics.desc_read_errors, 1);
+                }
+                self.ttl = 0;
+                return Some(Err(Error::GuestMemory(e)));
+            }
+        };
+
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            name: "virtio_queue::descriptor",
+            head_index = self.head_index,
+            index = self.next_index,
+            addr = desc.addr().raw_value(),
+            len = desc.len(),
+            flags = desc.flags(),
+        );
 
         if desc.is_indirect() {
-            self.process_indirect_descriptor(desc).ok()?;
-            return self.next();
+            if let Err(e) = self.process_indirect_descriptor(desc) {
+                self.ttl = 0;
+                return Some(Err(e));
+            }
+            return self.next_descriptor();
         }
 
         if desc.has_next() {
+            // A chain can have at most `queue_size` descriptors, so the last one we are allowed
+            // to process must not point to another descriptor.
+            if self.ttl == 1 {
+                self.ttl = 0;
+                return Some(Err(Error::InvalidChain));
+            }
+            if let Some(cache) = self.prefetch.as_mut() {
+                cache.sequential = desc.next() == self.next_index.wrapping_add(1);
+            }
             self.next_index = desc.next();
             // It's ok to decrement `self.ttl` here because we check at the start of the method
             // that it's greater than 0.
@@ -276,18 +994,157 @@ impl<M: GuestAddressSpace> Iterator for DescriptorChain<M> {
             self.ttl = 0;
         }
 
-        Some(desc)
+        self.consumed = self.consumed.saturating_add(1);
+        Some(Ok(desc))
+    }
+
+    // Read the descriptor at `self.next_index`, which is located at `desc_addr`. When
+    // prefetching is enabled, the descriptor is taken from the cache if it was read ahead
+    // already. Otherwise, if it follows the previous descriptor (or it's the first one of an
+    // indirect table), the run of descriptors starting with it is read at once and cached.
+    // Descriptors that aren't laid out contiguously are read one by one.
+    fn read_descriptor(&mut self, desc_addr: GuestAddress) -> Result<Descriptor, GuestMemoryError> {
+        let index = self.next_index;
+        let cache = match self.prefetch.as_mut() {
+            Some(cache) => cache,
+            None => return self.mem.read_obj(desc_addr),
+        };
+
+        if cache.table == self.desc_table && index >= cache.start && index - cache.start < cache.len
+        {
+            return Ok(cache.descs.0[usize::from(index - cache.start)]);
+        }
+
+        if !cache.sequential {
+            return self.mem.read_obj(desc_addr);
+        }
+
+        // The run doesn't go past the end of the table, which is where the chain ends anyway.
+        let len = min(
+            DESCRIPTOR_PREFETCH_LEN,
+            usize::from(self.queue_size - index),
+        );
+        let buf = &mut cache.descs.as_mut_slice()[..len * VIRTQ_DESCRIPTOR_SIZE];
+        if self.mem.read_slice(buf, desc_addr).is_err() {
+            // The run may extend past the end of guest memory even if the descriptor doesn't.
+            cache.len = 0;
+            return self.mem.read_obj(desc_addr);
+        }
+
+        cache.table = self.desc_table;
+        cache.start = index;
+        cache.len = len as u16;
+        Ok(cache.descs.0[0])
+    }
+
+    // Alters the internal state of the `DescriptorChain` to switch iterating over an
+    // indirect descriptor table defined by `desc`. The table can be anywhere in guest memory
+    // (including a different region than the queue descriptor table), as all the accesses go
+    // through the `GuestMemory` interface.
+    fn process_indirect_descriptor(&mut self, desc: Descriptor) -> Result<(), Error> {
+        // Indirect descriptors can only be used if VIRTIO_F_INDIRECT_DESC is negotiated.
+        if !self.indirect_allowed {
+            error!("Indirect descriptor without VIRTIO_F_INDIRECT_DESC");
+            return Err(Error::InvalidIndirectDescriptor);
+        }
+
+        // Indirect tables can't be nested, and a descriptor referring to an indirect table
+        // must not be chained with other descriptors as well.
+        if self.is_indirect || desc.has_next() {
+            return Err(Error::InvalidIndirectDescriptor);
+        }
+
+        // Check the target indirect descriptor table is correctly aligned.
+        if desc.addr().raw_value() & (VIRTQ_DESCRIPTOR_SIZE as u64 - 1) != 0 {
+            error!(
+                "Misaligned indirect descriptor table at {:x}",
+                desc.addr().raw_value()
+            );
+            return Err(Error::InvalidIndirectDescriptorTable);
+        }
+
+        // The length of the table must be a positive multiple of the descriptor size, as an
+        // empty table would otherwise pass for an empty chain.
+        let table_len = (desc.len() as usize) / VIRTQ_DESCRIPTOR_SIZE;
+        if (desc.len() as usize) & (VIRTQ_DESCRIPTOR_SIZE - 1) != 0
+            || table_len == 0
+            || table_len > usize::from(u16::MAX)
+        {
+            error!("Invalid indirect descriptor table length {}", desc.len());
+            return Err(Error::InvalidIndirectDescriptorTable);
+        }
+
+        if table_len > usize::from(self.max_indirect_table_len) {
+            error!(
+                "Indirect descriptor table with {} descriptors exceeds the limit of {}",
+                table_len, self.max_indirect_table_len
+            );
+            return Err(Error::InvalidIndirectDescriptorTable);
+        }
+
+        // Check the table doesn't alias the available or used rings, if requested.
+        if let Some(ranges) = self.ring_ranges {
+            if ranges
+                .iter()
+                .any(|&(start, len)| ranges_overlap(desc.addr(), u64::from(desc.len()), start, len))
+            {
+                error!(
+                    "Indirect descriptor table at {:x} overlaps the queue rings",
+                    desc.addr().raw_value()
+                );
+                return Err(Error::InvalidIndirectDescriptorTable);
+            }
+        }
+
+        self.desc_table = desc.addr();
+        self.queue_size = table_len as u16;
+        self.next_index = 0;
+        self.ttl = self.queue_size;
+        self.is_indirect = true;
+        // Without `alloc`, a cycle is only detected once the TTL runs out, which still bounds
+        // the walk by the length of the table.
+        #[cfg(feature = "alloc")]
+        {
+            self.visited = Some(alloc::vec![0; table_len.div_ceil(64)]);
+        }
+        // Indirect tables are laid out sequentially by definition.
+        if let Some(cache) = self.prefetch.as_mut() {
+            cache.sequential = true;
+        }
+
+        Ok(())
     }
 }
 
-/// An iterator for readable or writable descriptors.
-#[derive(Clone)]
-pub struct DescriptorChainRwIter<M: GuestAddressSpace> {
-    chain: DescriptorChain<M>,
-    writable: bool,
+// We can't derive Clone, because rustc would generate an unneeded M: Clone constraint
+// (only M::T has to be Clone, which is always the case).
+impl<M: GuestAddressSpace> Clone for DescriptorChain<M> {
+    fn clone(&self) -> Self {
+        DescriptorChain {
+            mem: self.mem.clone(),
+            desc_table: self.desc_table,
+            queue_size: self.queue_size,
+            head_desc_table: self.head_desc_table,
+            head_queue_size: self.head_queue_size,
+            head_index: self.head_index,
+            next_index: self.next_index,
+            ttl: self.ttl,
+            consumed: self.consumed,
+            is_indirect: self.is_indirect,
+            ring_ranges: self.ring_ranges,
+            max_indirect_table_len: self.max_indirect_table_len,
+            max_chain_bytes: self.max_chain_bytes,
+            indirect_allowed: self.indirect_allowed,
+            prefetch: self.prefetch,
+            #[cfg(feature = "alloc")]
+            visited: self.visited.clone(),
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics.clone(),
+        }
+    }
 }
 
-impl<M: GuestAddressSpace> Iterator for DescriptorChainRwIter<M> {
+impl<M: GuestAddressSpace> Iterator for DescriptorChain<M> {
     type Item = Descriptor;
 
     /// Returns the next descriptor in this descriptor chain, if there is one.
@@ -296,56 +1153,406 @@ impl<M: GuestAddressSpace> Iterator for DescriptorChainRwIter<M> {
     /// [`AvailIter`](struct.AvailIter.html), which is the head of the next
     /// _available_ descriptor chain.
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.chain.next() {
-                Some(v) => {
-                    if v.is_write_only() == self.writable {
-                        return Some(v);
-                    }
-                }
-                None => return None,
-            }
-        }
+        self.next_descriptor().and_then(Result::ok)
     }
 }
 
-// We can't derive Debug, because rustc doesn't generate the M::T: Debug
-// constraint
-impl<M: Debug + GuestAddressSpace> Debug for DescriptorChainRwIter<M>
-where
-    M::T: Debug,
-{
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+/// An iterator for readable or writable descriptors.
+#[derive(Clone)]
+pub struct DescriptorChainRwIter<M: GuestAddressSpace> {
+    chain: DescriptorChain<M>,
+    writable: bool,
+    // Whether the readable descriptors are known to precede the writable ones.
+    ordered: bool,
+}
+
+impl<M: GuestAddressSpace> DescriptorChainRwIter<M> {
+    /// Assume that all the readable descriptors of the chain precede the writable ones, and
+    /// stop reading the chain as soon as the first writable descriptor is found.
+    ///
+    /// By default, the readable descriptors are returned by walking the whole chain, and the
+    /// writable ones are read from guest memory just to be discarded. Most devices use chains
+    /// made of readable headers followed by a writable payload, as recommended by the virtio
+    /// standard, so the readable iterator can end at the start of the payload instead. The
+    /// assumption is not checked, so readable descriptors placed after a writable one are not
+    /// returned. This has no effect on writable iterators, since the readable descriptors must
+    /// be read anyway to find the next ones.
+    pub fn skip_to_writable(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
+
+    // Check whether `desc`, which is not yielded by the iterator, marks the end of the
+    // descriptors it yields, and stop the iteration over the chain if so.
+    fn past_end(&mut self, desc: &Descriptor) -> bool {
+        if self.ordered && desc.is_write_only() {
+            self.chain.ttl = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Returns the next readable or writable descriptor in this descriptor chain, if there is
+    /// one, or the error that prevented it from being retrieved.
+    ///
+    /// This is the fallible counterpart of `Iterator::next`, with the same semantics as
+    /// [`DescriptorChain::next_descriptor`](struct.DescriptorChain.html#method.next_descriptor).
+    pub fn next_descriptor(&mut self) -> Option<Result<Descriptor, Error>> {
+        loop {
+            match self.chain.next_descriptor()? {
+                Ok(desc) => {
+                    if desc.is_write_only() == self.writable {
+                        return Some(Ok(desc));
+                    }
+                    if self.past_end(&desc) {
+                        return None;
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<M: GuestAddressSpace> Iterator for DescriptorChainRwIter<M> {
+    type Item = Descriptor;
+
+    /// Returns the next descriptor in this descriptor chain, if there is one.
+    ///
+    /// Note that this is distinct from the next descriptor chain returned by
+    /// [`AvailIter`](struct.AvailIter.html), which is the head of the next
+    /// _available_ descriptor chain.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.chain.next() {
+                Some(v) => {
+                    if v.is_write_only() == self.writable {
+                        return Some(v);
+                    }
+                    if self.past_end(&v) {
+                        return None;
+                    }
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+// We can't derive Debug, because rustc doesn't generate the M::T: Debug
+// constraint
+impl<M: Debug + GuestAddressSpace> Debug for DescriptorChainRwIter<M>
+where
+    M::T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("DescriptorChainRwIter")
             .field("chain", &self.chain)
             .field("writable", &self.writable)
+            .field("ordered", &self.ordered)
+            .finish()
+    }
+}
+
+/// Access permissions of a guest memory region.
+pub trait RegionPermissions {
+    /// Returns whether the region can be read by the device.
+    fn is_readable(&self) -> bool;
+
+    /// Returns whether the region can be written by the device.
+    fn is_writable(&self) -> bool;
+}
+
+#[cfg(all(feature = "backend-mmap", unix))]
+impl RegionPermissions for vm_memory::GuestRegionMmap {
+    fn is_readable(&self) -> bool {
+        self.prot() & libc::PROT_READ != 0
+    }
+
+    fn is_writable(&self) -> bool {
+        self.prot() & libc::PROT_WRITE != 0
+    }
+}
+
+/// An iterator that checks the permissions of the memory backing each descriptor.
+#[derive(Clone)]
+pub struct DescriptorChainPermIter<M: GuestAddressSpace> {
+    chain: DescriptorChain<M>,
+}
+
+impl<M: GuestAddressSpace> DescriptorChainPermIter<M>
+where
+    <M::M as GuestMemory>::R: RegionPermissions,
+{
+    // Check that all the regions covered by the buffer of `desc` have the permission matching
+    // the direction of the descriptor.
+    fn check_permissions(&self, desc: &Descriptor) -> Result<(), Error> {
+        let mem = self.chain.memory();
+        let mut addr = desc.addr();
+        let mut remaining = u64::from(desc.len());
+
+        while remaining > 0 {
+            let region = mem.find_region(addr).ok_or_else(|| {
+                error!("descriptor buffer at {:#x} is not mapped", addr.raw_value());
+                Error::InvalidChain
+            })?;
+
+            let allowed = if desc.is_write_only() {
+                region.is_writable()
+            } else {
+                region.is_readable()
+            };
+            if !allowed {
+                error!(
+                    "descriptor buffer at {:#x} lacks the {} permission",
+                    addr.raw_value(),
+                    if desc.is_write_only() {
+                        "write"
+                    } else {
+                        "read"
+                    }
+                );
+                return Err(Error::InvalidChain);
+            }
+
+            // `find_region` guarantees `addr` is within the region, so this can't underflow.
+            let count = min(
+                remaining,
+                region.last_addr().unchecked_offset_from(addr) + 1,
+            );
+            remaining -= count;
+            if remaining > 0 {
+                addr = addr.checked_add(count).ok_or(Error::InvalidChain)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next descriptor in this descriptor chain, if there is one, or the error that
+    /// prevented it from being retrieved or from passing the permission checks.
+    pub fn next_descriptor(&mut self) -> Option<Result<Descriptor, Error>> {
+        let desc = match self.chain.next_descriptor()? {
+            Ok(desc) => desc,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if let Err(e) = self.check_permissions(&desc) {
+            self.chain.ttl = 0;
+            return Some(Err(e));
+        }
+
+        Some(Ok(desc))
+    }
+}
+
+impl<M: GuestAddressSpace> Iterator for DescriptorChainPermIter<M>
+where
+    <M::M as GuestMemory>::R: RegionPermissions,
+{
+    type Item = Result<Descriptor, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_descriptor()
+    }
+}
+
+// We can't derive Debug, because rustc doesn't generate the M::T: Debug
+// constraint
+impl<M: Debug + GuestAddressSpace> Debug for DescriptorChainPermIter<M>
+where
+    M::T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DescriptorChainPermIter")
+            .field("chain", &self.chain)
             .finish()
     }
 }
 
+/// A self-contained copy of a descriptor chain.
+///
+/// It holds the head index and the descriptors of the chain (with any indirect table already
+/// resolved), but no reference to guest memory, so it can be freely sent to other threads.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct OwnedChain {
+    head_index: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+#[cfg(feature = "alloc")]
+impl OwnedChain {
+    /// Get the descriptor index of the chain header.
+    pub fn head_index(&self) -> u16 {
+        self.head_index
+    }
+
+    /// Return the descriptors of the chain.
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+}
+
+/// A summary of the shape of a descriptor chain.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChainSummary {
+    head_index: u16,
+    readable_count: u16,
+    readable_len: u32,
+    writable_count: u16,
+    writable_len: u32,
+}
+
+#[cfg(feature = "alloc")]
+impl ChainSummary {
+    // Both the lengths and the counts saturate, to match `DescriptorChain::total_len`.
+    fn new(chain: &OwnedChain) -> Self {
+        let mut summary = ChainSummary {
+            head_index: chain.head_index(),
+            ..Default::default()
+        };
+
+        for desc in chain.descriptors() {
+            if desc.is_write_only() {
+                summary.writable_count = summary.writable_count.saturating_add(1);
+                summary.writable_len = summary.writable_len.saturating_add(desc.len());
+            } else {
+                summary.readable_count = summary.readable_count.saturating_add(1);
+                summary.readable_len = summary.readable_len.saturating_add(desc.len());
+            }
+        }
+
+        summary
+    }
+
+    /// Get the descriptor index of the chain header.
+    pub fn head_index(&self) -> u16 {
+        self.head_index
+    }
+
+    /// Return the number of readable descriptors in the chain.
+    pub fn readable_count(&self) -> u16 {
+        self.readable_count
+    }
+
+    /// Return the total length of the readable descriptors in the chain.
+    pub fn readable_len(&self) -> u32 {
+        self.readable_len
+    }
+
+    /// Return the number of writable descriptors in the chain.
+    pub fn writable_count(&self) -> u16 {
+        self.writable_count
+    }
+
+    /// Return the total length of the writable descriptors in the chain.
+    pub fn writable_len(&self) -> u32 {
+        self.writable_len
+    }
+}
+
+/// The checks run on descriptor chains by `DescriptorChain::validate`.
+///
+/// All the checks are enabled by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainValidation {
+    /// Check that the buffer of each descriptor is contained in guest memory.
+    pub addresses_in_range: bool,
+    /// Reject writable descriptors with a length of 0.
+    pub no_empty_writable: bool,
+    /// Reject readable descriptors that follow writable ones, which the specification forbids.
+    pub readable_before_writable: bool,
+}
+
+impl Default for ChainValidation {
+    fn default() -> Self {
+        ChainValidation {
+            addresses_in_range: true,
+            no_empty_writable: true,
+            readable_before_writable: true,
+        }
+    }
+}
+
+// The queue settings used to read the available ring, and the descriptor chains it points to.
+#[derive(Clone, Copy, Debug)]
+struct ChainConfig {
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    queue_size: u16,
+    ring_ranges: Option<[(GuestAddress, u64); 2]>,
+    max_indirect_table_len: u16,
+    max_chain_bytes: u64,
+    indirect_allowed: bool,
+    descriptor_prefetch: bool,
+}
+
+impl ChainConfig {
+    // Return the descriptor chain with the head at `head_index`, subject to these settings.
+    fn chain<M: GuestAddressSpace>(&self, mem: M::T, head_index: u16) -> DescriptorChain<M> {
+        let mut chain = DescriptorChain::new(mem, self.desc_table, self.queue_size, head_index);
+        chain.ring_ranges = self.ring_ranges;
+        chain.max_indirect_table_len = self.max_indirect_table_len;
+        chain.max_chain_bytes = self.max_chain_bytes;
+        chain.indirect_allowed = self.indirect_allowed;
+        chain.prefetch = self.descriptor_prefetch.then(DescriptorCache::default);
+        chain
+    }
+}
+
 /// Consuming iterator over all available descriptor chain heads in the queue.
 #[derive(Debug)]
 pub struct AvailIter<'b, M: GuestAddressSpace> {
     mem: M::T,
-    desc_table: GuestAddress,
-    avail_ring: GuestAddress,
+    config: ChainConfig,
     last_index: Wrapping<u16>,
-    queue_size: u16,
     next_avail: &'b mut Wrapping<u16>,
+    // The number of chains consumed and not added to the used ring yet.
+    in_flight: &'b mut u16,
+    last_error: &'b mut Option<ErrorKind>,
+    // The statistics to update, if the chains are actually consumed.
+    #[cfg(feature = "metrics")]
+    metrics: Option<&'b Arc<QueueMetrics>>,
 }
 
-impl<'b, M: GuestAddressSpace> Iterator for AvailIter<'b, M> {
-    type Item = DescriptorChain<M>;
+impl<'b, M: GuestAddressSpace> AvailIter<'b, M> {
+    // Create an iterator over the chains from `next_avail` up to `last_index`, which stops at
+    // the first error and stores its kind in `last_error`. Consumed chains are counted in
+    // `in_flight`, and accounted for in `metrics`, if present.
+    fn new(
+        mem: M::T,
+        config: ChainConfig,
+        last_index: Wrapping<u16>,
+        next_avail: &'b mut Wrapping<u16>,
+        in_flight: &'b mut u16,
+        last_error: &'b mut Option<ErrorKind>,
+        #[cfg(feature = "metrics")] metrics: Option<&'b Arc<QueueMetrics>>,
+    ) -> Self {
+        AvailIter {
+            mem,
+            config,
+            last_index,
+            next_avail,
+            in_flight,
+            last_error,
+            #[cfg(feature = "metrics")]
+            metrics,
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if *self.next_avail == self.last_index {
+    // Return the next available chain, or the error that prevented reading its head index from
+    // the available ring, after which the iteration stops.
+    fn try_next(&mut self) -> Option<Result<DescriptorChain<M>, Error>> {
+        if ring_index::wrapping_distance(*self.next_avail, self.last_index) == 0 {
             return None;
         }
 
         // This computation cannot overflow because all the values involved are actually
         // `u16`s cast to `u64`.
         let offset = VIRTQ_AVAIL_RING_HEADER_SIZE
-            + (self.next_avail.0 % self.queue_size) as u64 * VIRTQ_AVAIL_ELEMENT_SIZE;
+            + (self.next_avail.0 % self.config.queue_size) as u64 * VIRTQ_AVAIL_ELEMENT_SIZE;
 
         // The logic in `Queue::is_valid` ensures it's ok to use `unchecked_add` as long
         // as the index is within bounds. We do not currently enforce that a queue is only used
@@ -353,308 +1560,2124 @@ impl<'b, M: GuestAddressSpace> Iterator for AvailIter<'b, M> {
         // before activation. The standard also forbids drivers to change queue parameters
         // while the device is "running". A warp-around cannot lead to unsafe memory accesses
         // because the memory model performs its own validations.
-        let addr = self.avail_ring.unchecked_add(offset);
-        let head_index: u16 = self
-            .mem
-            .read_obj(addr)
-            .map_err(|_| error!("Failed to read from memory {:x}", addr.raw_value()))
-            .ok()?;
+        let addr = self.config.avail_ring.unchecked_add(offset);
+        let head_index: u16 = match self.mem.read_obj(addr) {
+            Ok(head_index) => head_index,
+            Err(e) => {
+                error!("Failed to read from memory {:x}", addr.raw_value());
+                // Stop the iteration, so the iterator doesn't keep reporting chains it can't
+                // yield.
+                self.last_index = *self.next_avail;
+                return Some(Err(Error::GuestMemory(e)));
+            }
+        };
 
         *self.next_avail += Wrapping(1);
+        *self.in_flight = self.in_flight.saturating_add(1);
+
+        #[allow(unused_mut)]
+        let mut chain = self.config.chain(self.mem.clone(), head_index);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics {
+            QueueMetrics::add(&metrics.chains_processed, 1);
+            chain.metrics = Some(metrics.clone());
+        }
 
-        Some(DescriptorChain::new(
-            self.mem.clone(),
-            self.desc_table,
-            self.queue_size,
-            head_index,
-        ))
+        Some(Ok(chain))
     }
 }
 
-/// Represents the contents of an element from the used virtqueue ring.
-#[repr(C)]
-#[derive(Clone, Copy, Default, Debug)]
-pub struct VirtqUsedElem {
-    id: u32,
-    len: u32,
-}
+impl<'b, M: GuestAddressSpace> Iterator for AvailIter<'b, M> {
+    type Item = DescriptorChain<M>;
 
-impl VirtqUsedElem {
-    /// Create a new `VirtqUsedElem` instance.
-    pub fn new(id: u16, len: u32) -> Self {
-        VirtqUsedElem {
-            id: u32::from(id),
-            len,
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.try_next()? {
+            Ok(chain) => Some(chain),
+            Err(e) => {
+                *self.last_error = Some(e.kind());
+                None
+            }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // The free running indices wrap around, so the difference has to be computed using
+        // wrapping arithmetic as well.
+        let len = usize::from(ring_index::wrapping_distance(
+            *self.next_avail,
+            self.last_index,
+        ));
+        (len, Some(len))
+    }
 }
 
-unsafe impl ByteValued for VirtqUsedElem {}
+impl<'b, M: GuestAddressSpace> ExactSizeIterator for AvailIter<'b, M> {}
 
-#[derive(Clone, Debug)]
-/// A virtio queue's parameters.
-pub struct Queue<M: GuestAddressSpace> {
-    mem: M,
+impl<'b, M: GuestAddressSpace> AvailIter<'b, M> {
+    // Move back to the chain yielded last, which is not counted as processed anymore.
+    fn rewind(&mut self) {
+        *self.next_avail -= Wrapping(1);
+        *self.in_flight = self.in_flight.saturating_sub(1);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics {
+            QueueMetrics::sub(&metrics.chains_processed, 1);
+        }
+    }
 
-    /// The maximal size in elements offered by the device
-    max_size: u16,
+    /// Returns an iterator that yields available descriptor chains as long as `pred` returns
+    /// `true` for them.
+    ///
+    /// The first chain rejected by `pred` is not consumed (the position in the available ring
+    /// is moved back by one), so it will be returned again by the next iteration over the
+    /// queue. No other chains are yielded after a rejection.
+    pub fn take_while_chain<F>(self, pred: F) -> AvailIterWhile<'b, M, F>
+    where
+        F: FnMut(&DescriptorChain<M>) -> bool,
+    {
+        AvailIterWhile {
+            iter: self,
+            pred,
+            done: false,
+        }
+    }
 
-    next_avail: Wrapping<u16>,
-    next_used: Wrapping<u16>,
+    /// Returns an iterator that yields the head index of each available descriptor chain,
+    /// together with the chain.
+    ///
+    /// The head index is the one to pass to `Queue::add_used` once the chain is processed.
+    pub fn with_indices(self) -> AvailIterWithIndices<'b, M> {
+        AvailIterWithIndices { iter: self }
+    }
 
-    /// VIRTIO_F_RING_EVENT_IDX negotiated
-    pub event_idx_enabled: bool,
+    /// Returns an iterator that runs the default `ChainValidation` checks on each available
+    /// descriptor chain, and yields either the chain or the error that rejected it.
+    ///
+    /// Rejected chains are consumed as well. Devices that have to return them to the driver
+    /// can call `DescriptorChain::validate` on the chains instead, which keeps their head index
+    /// at hand.
+    pub fn validated(self) -> AvailIterValidated<'b, M> {
+        self.validated_with(ChainValidation::default())
+    }
 
-    /// The last used value when using EVENT_IDX
-    signalled_used: Option<Wrapping<u16>>,
+    /// Returns an iterator like `validated`, that runs the provided `checks` instead.
+    pub fn validated_with(self, checks: ChainValidation) -> AvailIterValidated<'b, M> {
+        AvailIterValidated { iter: self, checks }
+    }
+}
 
-    /// The queue size in elements the driver selected
-    pub size: u16,
+/// Consuming iterator over the available descriptor chain heads in the queue, that validates
+/// each chain before yielding it.
+pub struct AvailIterValidated<'b, M: GuestAddressSpace> {
+    iter: AvailIter<'b, M>,
+    checks: ChainValidation,
+}
 
-    /// Indicates if the queue is finished with configuration
-    pub ready: bool,
+impl<'b, M: GuestAddressSpace> Iterator for AvailIterValidated<'b, M> {
+    type Item = Result<DescriptorChain<M>, Error>;
 
-    /// Guest physical address of the descriptor table
-    pub desc_table: GuestAddress,
+    fn next(&mut self) -> Option<Self::Item> {
+        let chain = self.iter.next()?;
+        Some(chain.validate(&self.checks).map(|_| chain))
+    }
 
-    /// Guest physical address of the available ring
-    pub avail_ring: GuestAddress,
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
 
-    /// Guest physical address of the used ring
-    pub used_ring: GuestAddress,
+impl<'b, M: GuestAddressSpace> ExactSizeIterator for AvailIterValidated<'b, M> {}
+
+/// Consuming iterator over the available descriptor chain heads in the queue, that yields the
+/// errors encountered while reading the available ring instead of stopping silently.
+///
+/// The iteration stops after the first error.
+pub struct AvailIterFallible<'b, M: GuestAddressSpace> {
+    iter: AvailIter<'b, M>,
+    // The error that prevented reading the `idx` field of the available ring, if any.
+    error: Option<Error>,
 }
 
-impl<M: GuestAddressSpace> Queue<M> {
-    /// Constructs an empty virtio queue with the given `max_size`.
-    pub fn new(mem: M, max_size: u16) -> Queue<M> {
-        Queue {
-            mem,
-            max_size,
-            size: max_size,
-            ready: false,
-            desc_table: GuestAddress(0),
-            avail_ring: GuestAddress(0),
-            used_ring: GuestAddress(0),
-            next_avail: Wrapping(0),
-            next_used: Wrapping(0),
-            event_idx_enabled: false,
-            signalled_used: None,
+impl<'b, M: GuestAddressSpace> Iterator for AvailIterFallible<'b, M> {
+    type Item = Result<DescriptorChain<M>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.error.take() {
+            Some(e) => Some(Err(e)),
+            None => self.iter.try_next(),
         }
     }
 
-    /// Gets the virtio queue maximum size.
-    pub fn max_size(&self) -> u16 {
-        self.max_size
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // An error cuts the iteration short, so only the upper bound is known.
+        let len = self.iter.len() + usize::from(self.error.is_some());
+        (0, Some(len))
     }
+}
 
-    /// Return the actual size of the queue, as the driver may not set up a
-    /// queue as big as the device allows.
-    pub fn actual_size(&self) -> u16 {
-        min(self.size, self.max_size)
-    }
+/// Consuming iterator over the available descriptor chain heads in the queue, that yields
+/// `(head_index, chain)` pairs.
+pub struct AvailIterWithIndices<'b, M: GuestAddressSpace> {
+    iter: AvailIter<'b, M>,
+}
 
-    /// Reset the queue to a state that is acceptable for a device reset
-    pub fn reset(&mut self) {
-        self.ready = false;
-        self.size = self.max_size;
-        self.desc_table = GuestAddress(0);
-        self.avail_ring = GuestAddress(0);
-        self.used_ring = GuestAddress(0);
-        self.next_avail = Wrapping(0);
-        self.next_used = Wrapping(0);
-        self.signalled_used = None;
-        self.event_idx_enabled = false;
+impl<'b, M: GuestAddressSpace> Iterator for AvailIterWithIndices<'b, M> {
+    type Item = (u16, DescriptorChain<M>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|chain| (chain.head_index(), chain))
     }
 
-    /// Enable/disable the VIRTIO_F_RING_EVENT_IDX feature.
-    pub fn set_event_idx(&mut self, enabled: bool) {
-        self.signalled_used = None;
-        self.event_idx_enabled = enabled;
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
     }
+}
 
-    /// Check if the virtio queue configuration is valid.
-    pub fn is_valid(&self) -> bool {
-        let mem = self.mem.memory();
-        let queue_size = self.actual_size() as u64;
-        let desc_table = self.desc_table;
-        let desc_table_size = size_of::<Descriptor>() as u64 * queue_size;
-        let avail_ring = self.avail_ring;
-        let avail_ring_size = VIRTQ_AVAIL_RING_META_SIZE + VIRTQ_AVAIL_ELEMENT_SIZE * queue_size;
-        let used_ring = self.used_ring;
-        let used_ring_size = VIRTQ_USED_RING_META_SIZE + VIRTQ_USED_ELEMENT_SIZE * queue_size;
-        if !self.ready {
-            error!("attempt to use virtio queue that is not marked ready");
-            false
-        } else if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0
-        {
-            error!("virtio queue with invalid size: {}", self.size);
-            false
-        } else if desc_table
-            .checked_add(desc_table_size)
-            .map_or(true, |v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue descriptor table goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                desc_table.raw_value(),
-                desc_table_size
-            );
-            false
-        } else if avail_ring
-            .checked_add(avail_ring_size)
-            .map_or(true, |v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue available ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                avail_ring.raw_value(),
-                avail_ring_size
-            );
-            false
-        } else if used_ring
-            .checked_add(used_ring_size)
-            .map_or(true, |v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue used ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                used_ring.raw_value(),
-                used_ring_size
-            );
-            false
-        } else if desc_table.mask(0xf) != 0 {
-            error!("virtio queue descriptor table breaks alignment contraints");
-            false
-        } else if avail_ring.mask(0x1) != 0 {
-            error!("virtio queue available ring breaks alignment contraints");
-            false
-        } else if used_ring.mask(0x3) != 0 {
-            error!("virtio queue used ring breaks alignment contraints");
-            false
-        } else {
-            true
+impl<'b, M: GuestAddressSpace> ExactSizeIterator for AvailIterWithIndices<'b, M> {}
+
+/// Consuming iterator over available descriptor chain heads that stops at the first chain
+/// rejected by a predicate, without consuming it.
+pub struct AvailIterWhile<'b, M: GuestAddressSpace, F> {
+    iter: AvailIter<'b, M>,
+    pred: F,
+    done: bool,
+}
+
+impl<'b, M, F> Iterator for AvailIterWhile<'b, M, F>
+where
+    M: GuestAddressSpace,
+    F: FnMut(&DescriptorChain<M>) -> bool,
+{
+    type Item = DescriptorChain<M>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let chain = self.iter.next()?;
+        if (self.pred)(&chain) {
+            return Some(chain);
         }
+
+        // Leave the rejected chain in the available ring.
+        self.iter.rewind();
+        self.done = true;
+        None
     }
+}
 
-    /// Reads the `idx` field from the available ring.
-    pub fn avail_idx(&self, order: Ordering) -> Result<Wrapping<u16>, Error> {
-        let addr = self.used_ring.unchecked_add(2);
-        self.mem
-            .memory()
-            .load(addr, order)
-            .map(Wrapping)
-            .map_err(Error::GuestMemory)
+/// Represents the contents of an element from the used virtqueue ring.
+///
+/// Like for `Descriptor`, the fields are stored in little-endian byte order.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl VirtqUsedElem {
+    /// Create a new `VirtqUsedElem` instance.
+    pub fn new(id: u16, len: u32) -> Self {
+        VirtqUsedElem {
+            id: u32::from(id).to_le(),
+            len: len.to_le(),
+        }
     }
 
-    /// A consuming iterator over all available descriptor chain heads offered by the driver.
-    pub fn iter(&mut self) -> Result<AvailIter<'_, M>, Error> {
-        self.avail_idx(Ordering::Acquire).map(move |idx| AvailIter {
-            mem: self.mem.memory(),
-            desc_table: self.desc_table,
-            avail_ring: self.avail_ring,
-            last_index: idx,
-            queue_size: self.actual_size(),
-            next_avail: &mut self.next_avail,
-        })
+    /// Return the index of the head descriptor of the used descriptor chain.
+    pub fn id(&self) -> u32 {
+        u32::from_le(self.id)
     }
 
-    /// Puts an available descriptor head into the used ring for use by the guest.
-    pub fn add_used(&mut self, head_index: u16, len: u32) -> Result<(), Error> {
-        if head_index >= self.actual_size() {
-            error!(
-                "attempted to add out of bounds descriptor to used ring: {}",
-                head_index
-            );
-            return Err(Error::InvalidDescriptorIndex);
-        }
+    /// Return the number of bytes written by the device to the buffers of the chain.
+    pub fn len(&self) -> u32 {
+        u32::from_le(self.len)
+    }
+}
 
-        let mem = self.mem.memory();
-        let next_used_index = u64::from(self.next_used.0 % self.actual_size());
-        let addr = self.used_ring.unchecked_add(4 + next_used_index * 8);
-        mem.write_obj(VirtqUsedElem::new(head_index, len), addr)
-            .map_err(Error::GuestMemory)?;
+unsafe impl ByteValued for VirtqUsedElem {}
 
-        self.next_used += Wrapping(1);
+// Returns the sizes in bytes of the descriptor table, the available ring, and the used ring
+// (in this order) for a queue with `queue_size` elements, or `None` if any of the computations
+// overflows.
+fn ring_sizes(queue_size: u64) -> Option<(u64, u64, u64)> {
+    let desc_table_size = (size_of::<Descriptor>() as u64).checked_mul(queue_size)?;
+    let avail_ring_size = VIRTQ_AVAIL_ELEMENT_SIZE
+        .checked_mul(queue_size)?
+        .checked_add(VIRTQ_AVAIL_RING_META_SIZE)?;
+    let used_ring_size = VIRTQ_USED_ELEMENT_SIZE
+        .checked_mul(queue_size)?
+        .checked_add(VIRTQ_USED_RING_META_SIZE)?;
+    Some((desc_table_size, avail_ring_size, used_ring_size))
+}
 
-        mem.store(
-            self.next_used.0,
-            self.used_ring.unchecked_add(2),
-            Ordering::Release,
-        )
+// Check that `max_size` is a non-zero power of 2 not greater than `VIRTQ_MAX_SIZE`.
+fn is_valid_max_size(max_size: u16) -> bool {
+    max_size.is_power_of_two() && max_size <= VIRTQ_MAX_SIZE
+}
+
+// Returns `addr` with its least and/or most significant 4 bytes replaced by `low` and `high`.
+fn replace_address_halves(addr: GuestAddress, low: Option<u32>, high: Option<u32>) -> GuestAddress {
+    let low = low.map_or(addr.raw_value() & 0xffff_ffff, u64::from);
+    let high = high.map_or(addr.raw_value() >> 32, u64::from);
+    GuestAddress((high << 32) | low)
+}
+
+// Returns whether the `[a_start, a_start + a_len)` and `[b_start, b_start + b_len)` ranges
+// overlap. Empty ranges don't overlap anything.
+fn ranges_overlap(a_start: GuestAddress, a_len: u64, b_start: GuestAddress, b_len: u64) -> bool {
+    // The ends are computed as `u128`s, so they can't overflow.
+    let (a_start, b_start) = (
+        u128::from(a_start.raw_value()),
+        u128::from(b_start.raw_value()),
+    );
+    a_len != 0
+        && b_len != 0
+        && a_start < b_start + u128::from(b_len)
+        && b_start < a_start + u128::from(a_len)
+}
+
+// Helpers for comparing the free running ring indices, which wrap around at `u16::MAX`.
+mod ring_index {
+    use core::num::Wrapping;
+
+    // Return the number of increments it takes to go from `from` to `to`, which is the number
+    // of ring entries between the two indices when `to` is ahead of `from`.
+    pub(crate) fn wrapping_distance(from: Wrapping<u16>, to: Wrapping<u16>) -> u16 {
+        (to - from).0
+    }
+
+    // Check whether `event` was crossed when an index moved from `old` to `idx`, which is the
+    // case when `event` is in the `[old, idx)` range. The indices are looked at as if they are
+    // on an axis that wraps around. If the distance from `event + 1` to `idx` is greater than
+    // or equal to the distance from `old` to `idx`, then `old` is closer to `idx` than `event`
+    // (and thus more recent), so `event` was already crossed before.
+    pub(crate) fn is_behind(idx: Wrapping<u16>, event: Wrapping<u16>, old: Wrapping<u16>) -> bool {
+        wrapping_distance(event + Wrapping(1), idx) < wrapping_distance(old, idx)
+    }
+}
+
+// Returns the ordering that has to be used for an access to the rings which would otherwise
+// use `order`, depending on whether VIRTIO_F_ORDER_PLATFORM is enabled.
+fn ring_order(order_platform: bool, order: Ordering) -> Ordering {
+    if order_platform {
+        Ordering::SeqCst
+    } else {
+        order
+    }
+}
+
+// Writes a `VirtqUsedElem` built from `head_index` and `len` to the entry that corresponds
+// to `used_idx` of the used ring at `used_ring`, for a queue with `queue_size` elements.
+fn store_used_elem<G: GuestMemory>(
+    mem: &G,
+    used_ring: GuestAddress,
+    queue_size: u16,
+    used_idx: Wrapping<u16>,
+    head_index: u16,
+    len: u32,
+) -> Result<(), Error> {
+    let next_used_index = u64::from(used_idx.0 % queue_size);
+    let addr = used_ring
+        .unchecked_add(VIRTQ_USED_RING_HEADER_SIZE + next_used_index * VIRTQ_USED_ELEMENT_SIZE);
+    mem.write_obj(VirtqUsedElem::new(head_index, len), addr)
+        .map_err(Error::GuestMemory)
+}
+
+// Writes `val` to the `idx` field of the used ring at `used_ring`.
+fn store_used_idx<G: GuestMemory>(
+    mem: &G,
+    used_ring: GuestAddress,
+    val: u16,
+    order: Ordering,
+) -> Result<(), Error> {
+    mem.store(val, used_ring.unchecked_add(2), order)
+        .map_err(Error::GuestMemory)
+}
+
+// Reads the `used_event` field of the available ring at `avail_ring`, for a queue with
+// `queue_size` elements.
+fn load_used_event<G: GuestMemory>(
+    mem: &G,
+    avail_ring: GuestAddress,
+    queue_size: u16,
+    order: Ordering,
+) -> Result<Wrapping<u16>, Error> {
+    let used_event_addr = avail_ring.unchecked_add(
+        VIRTQ_AVAIL_RING_HEADER_SIZE + u64::from(queue_size) * VIRTQ_AVAIL_ELEMENT_SIZE,
+    );
+    mem.load(used_event_addr, order)
+        .map(Wrapping)
         .map_err(Error::GuestMemory)
+}
+
+// Reads the `flags` field of the available ring at `avail_ring`.
+fn load_avail_flags<G: GuestMemory>(
+    mem: &G,
+    avail_ring: GuestAddress,
+    order: Ordering,
+) -> Result<u16, Error> {
+    mem.load(avail_ring, order).map_err(Error::GuestMemory)
+}
+
+/// Statistics collected while processing a virtio queue.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    chains_processed: AtomicU64,
+    used_elements: AtomicU64,
+    notifications_requested: AtomicU64,
+    suppressed_notifications: AtomicU64,
+    desc_read_errors: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl QueueMetrics {
+    // Increment `counter` by `value`.
+    fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
     }
 
-    // Helper method that writes `val` to the `avail_event` field of the used ring, using
-    // the provided ordering.
-    fn set_avail_event(&self, val: u16, order: Ordering) -> Result<(), Error> {
-        let offset = (4 + self.actual_size() * 8) as u64;
-        let addr = self.used_ring.unchecked_add(offset);
-        self.mem
-            .memory()
-            .store(val, addr, order)
-            .map_err(Error::GuestMemory)
+    // Decrement `counter` by `value`, to take back a previous `add`.
+    fn sub(counter: &AtomicU64, value: u64) {
+        counter.fetch_sub(value, Ordering::Relaxed);
     }
 
-    // Set the value of the `flags` field of the used ring, applying the specified ordering.
-    fn set_used_flags(&mut self, val: u16, order: Ordering) -> Result<(), Error> {
-        self.mem
-            .memory()
-            .store(val, self.used_ring, order)
-            .map_err(Error::GuestMemory)
+    // Count the outcome of a `needs_notification` call.
+    fn record_notification(&self, notification: Notification) {
+        match notification {
+            Notification::Needed => Self::add(&self.notifications_requested, 1),
+            Notification::Suppressed | Notification::Withheld => {
+                Self::add(&self.suppressed_notifications, 1)
+            }
+            Notification::Masked => (),
+        }
     }
 
-    // Write the appropriate values to enable or disable notifications from the driver. Every
-    // access in this method uses `Relaxed` ordering because a fence is added by the caller
-    // when appropriate.
-    fn set_notification(&mut self, enable: bool) -> Result<(), Error> {
-        if enable {
+    /// Returns the number of descriptor chains consumed from the available ring.
+    pub fn chains_processed(&self) -> u64 {
+        self.chains_processed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of elements published to the used ring.
+    pub fn used_elements(&self) -> u64 {
+        self.used_elements.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times `needs_notification` requested a notification.
+    pub fn notifications_requested(&self) -> u64 {
+        self.notifications_requested.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times `needs_notification` suppressed a notification while
+    /// VIRTIO_F_RING_EVENT_IDX was enabled.
+    pub fn suppressed_notifications(&self) -> u64 {
+        self.suppressed_notifications.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of failed guest memory reads of descriptors, for the chains consumed
+    /// from the available ring.
+    pub fn desc_read_errors(&self) -> u64 {
+        self.desc_read_errors.load(Ordering::Relaxed)
+    }
+}
+
+// The callback set with `Queue::set_on_became_available`, together with whether the available
+// ring was empty when last observed.
+#[cfg(feature = "available-callback")]
+struct BecameAvailable {
+    callback: std::sync::Mutex<Box<dyn FnMut() + Send>>,
+    empty: AtomicBool,
+}
+
+#[cfg(feature = "available-callback")]
+impl Debug for BecameAvailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BecameAvailable")
+            .field("empty", &self.empty)
+            .finish()
+    }
+}
+
+/// The raw values of the queue configuration registers, as written by the driver through a
+/// transport (e.g. MMIO or PCI).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueRegisters {
+    /// The queue size selected by the driver.
+    pub size: u16,
+    /// The queue ready/enable register, where `1` marks the queue as ready.
+    pub ready: u16,
+    /// The least significant 4 bytes of the descriptor table address.
+    pub desc_table_lo: u32,
+    /// The most significant 4 bytes of the descriptor table address.
+    pub desc_table_hi: u32,
+    /// The least significant 4 bytes of the available ring address.
+    pub avail_ring_lo: u32,
+    /// The most significant 4 bytes of the available ring address.
+    pub avail_ring_hi: u32,
+    /// The least significant 4 bytes of the used ring address.
+    pub used_ring_lo: u32,
+    /// The most significant 4 bytes of the used ring address.
+    pub used_ring_hi: u32,
+}
+
+/// The size and ring addresses of a queue, as configured by the driver.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RingAddresses {
+    /// The queue size selected by the driver.
+    pub size: u16,
+    /// Guest physical address of the descriptor table.
+    pub desc_table: GuestAddress,
+    /// Guest physical address of the available ring.
+    pub avail_ring: GuestAddress,
+    /// Guest physical address of the used ring.
+    pub used_ring: GuestAddress,
+}
+
+/// The state of a queue at a given time, meant for debugging.
+///
+/// The `Display` implementation formats all the values on a single line, for logging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueStateSnapshot {
+    /// The size and the ring addresses of the queue.
+    pub addresses: RingAddresses,
+    /// The `idx` field of the available ring, as read from guest memory.
+    pub avail_idx: u16,
+    /// The `idx` field of the used ring, as read from guest memory.
+    pub used_idx: u16,
+    /// The index of the next available ring entry to process.
+    pub next_avail: u16,
+    /// The index of the next used ring entry to fill.
+    pub next_used: u16,
+    /// Whether the VIRTIO_F_RING_EVENT_IDX feature is enabled.
+    pub event_idx_enabled: bool,
+}
+
+impl Display for QueueStateSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "size {}, desc_table {:#x}, avail_ring {:#x}, used_ring {:#x}, \
+             avail idx {} (next_avail {}), used idx {} (next_used {}), event_idx {}",
+            self.addresses.size,
+            self.addresses.desc_table.raw_value(),
+            self.addresses.avail_ring.raw_value(),
+            self.addresses.used_ring.raw_value(),
+            self.avail_idx,
+            self.next_avail,
+            self.used_idx,
+            self.next_used,
             if self.event_idx_enabled {
-                // We call `set_avail_event` using the `next_avail` value, instead of reading
-                // and using the current `avail_idx` to avoid missing notifications. More
-                // details in `enable_notification`.
-                self.set_avail_event(self.next_avail.0, Ordering::Relaxed)?;
+                "enabled"
             } else {
-                self.set_used_flags(0, Ordering::Relaxed)?;
+                "disabled"
             }
+        )
+    }
+}
+
+/// The sizes, in bytes, of the guest memory areas reserved for the queue structures.
+///
+/// Some transports know how large these areas are supposed to be (for example because the
+/// layout of the queue is derived from a fixed size), which can be checked against the queue
+/// size with `Queue::validate_extents`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RingExtents {
+    /// The size of the area reserved for the descriptor table.
+    pub desc_table: u64,
+    /// The size of the area reserved for the available ring.
+    pub avail_ring: u64,
+    /// The size of the area reserved for the used ring.
+    pub used_ring: u64,
+}
+
+/// How a queue handles an available ring `idx` that is more than the queue size ahead of the
+/// next available entry to process.
+///
+/// At most `queue_size` descriptor chains can be made available at a time, so such a value
+/// means the driver wrapped the available ring past the entries that weren't consumed yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AvailOverrun {
+    /// Only process the next `queue_size` entries of the available ring.
+    Clamp,
+    /// Report the condition with `Error::InvalidChain`.
+    Error,
+}
+
+/// How a queue synchronizes with the driver when reading the `idx` field of the available
+/// ring and when publishing the `idx` field of the used ring.
+///
+/// A device must observe the available ring entries written by the driver before the `idx`
+/// field that makes them available, and the driver must observe the used ring entries written
+/// by the device before the `idx` field that publishes them. Both policies provide these
+/// guarantees, they differ in the way they do it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryOrdering {
+    /// The available ring `idx` field is loaded with `Acquire` ordering, and the used ring
+    /// `idx` field is stored with `Release` ordering.
+    ///
+    /// This is correct for any guest, and it's the default.
+    StrictAcqRel,
+    /// The `idx` fields are accessed with `Relaxed` ordering, and the queue issues an
+    /// `Acquire` fence after loading the available ring `idx` field, and a `Release` fence
+    /// before storing the used ring `idx` field.
+    ///
+    /// The ring entries must only be accessed through the queue (or after it synchronized
+    /// with the driver), as the accesses to the `idx` fields don't order anything on their
+    /// own. This avoids the stronger atomic instructions on targets where stand-alone fences
+    /// are cheaper, such as single-vCPU or polling-only backends.
+    FencedRelaxed,
+}
+
+impl MemoryOrdering {
+    // Return the ordering used to load the `idx` field of the available ring.
+    fn load_order(self) -> Ordering {
+        match self {
+            MemoryOrdering::StrictAcqRel => Ordering::Acquire,
+            MemoryOrdering::FencedRelaxed => Ordering::Relaxed,
         }
-        // Notifications are effectively disabled by default after triggering once when
-        // `VIRTIO_F_EVENT_IDX` is negotiated, so we don't do anything in that case.
-        else if !self.event_idx_enabled {
-            self.set_used_flags(VIRTQ_USED_F_NO_NOTIFY, Ordering::Relaxed)?;
+    }
+
+    // Return the ordering used to store the `idx` field of the used ring.
+    fn store_order(self) -> Ordering {
+        match self {
+            MemoryOrdering::StrictAcqRel => Ordering::Release,
+            MemoryOrdering::FencedRelaxed => Ordering::Relaxed,
         }
-        Ok(())
     }
 
-    /// Enable notification events from the guest driver. Returns true if one or more descriptors
-    /// can be consumed from the available ring after notifications were enabled (and thus it's
-    /// possible there will be no corresponding notification).
+    // Issue the fence that has to follow loading the `idx` field of the available ring.
+    fn fence_after_load(self) {
+        if self == MemoryOrdering::FencedRelaxed {
+            fence(Ordering::Acquire);
+        }
+    }
 
-    // TODO: Turn this into a doc comment/example.
-    // With the current implementation, a common way of consuming entries from the available ring
-    // while also leveraging notification suppression is to use a loop, for example:
-    //
-    // loop {
-    //     // We have to explicitly disable notifications if `VIRTIO_F_EVENT_IDX` has not been
-    //     // negotiated.
-    //     self.disable_notification()?;
-    //
-    //     for chain in self.iter()? {
-    //         // Do something with each chain ...
-    //         // Let's assume we process all available chains here.
-    //     }
-    //
-    //     // If `enable_notification` returns `true`, the driver has added more entries to the
-    //     // available ring.
-    //     if !self.enable_notification()? {
-    //         break;
-    //     }
-    // }
-    #[inline]
-    pub fn enable_notification(&mut self) -> Result<bool, Error> {
-        self.set_notification(true)?;
-        // Ensures the following read is not reordered before any previous write operation.
-        fence(Ordering::SeqCst);
+    // Issue the fence that has to precede storing the `idx` field of the used ring.
+    fn fence_before_store(self) {
+        if self == MemoryOrdering::FencedRelaxed {
+            fence(Ordering::Release);
+        }
+    }
+}
 
-        // We double check here to avoid the situation where the available ring has been updated
-        // just before we re-enabled notifications, and it's possible to miss one. We compare the
-        // current `avail_idx` value to `self.next_avail` because it's where we stopped processing
+/// The negotiated features that affect the behavior of a queue.
+///
+/// The value holds the bits of the device feature set (such as `1 << VIRTIO_F_IN_ORDER`), and
+/// only keeps the ones that are relevant to the queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueFeatures(u64);
+
+impl QueueFeatures {
+    /// VIRTIO_F_INDIRECT_DESC: descriptors can refer to indirect descriptor tables.
+    pub const INDIRECT_DESC: u64 = 1 << VIRTIO_F_INDIRECT_DESC;
+    /// VIRTIO_F_RING_EVENT_IDX: notifications are suppressed with the event index fields.
+    pub const RING_EVENT_IDX: u64 = 1 << VIRTIO_F_RING_EVENT_IDX;
+    /// VIRTIO_F_IN_ORDER: buffers are used in the order they are made available.
+    pub const IN_ORDER: u64 = 1 << VIRTIO_F_IN_ORDER;
+    /// VIRTIO_F_ORDER_PLATFORM: the ring accesses are ordered as for a hardware device.
+    pub const ORDER_PLATFORM: u64 = 1 << VIRTIO_F_ORDER_PLATFORM;
+
+    const ALL: u64 =
+        Self::INDIRECT_DESC | Self::RING_EVENT_IDX | Self::IN_ORDER | Self::ORDER_PLATFORM;
+
+    /// Create a `QueueFeatures` from the negotiated device feature bits, ignoring the bits
+    /// that don't affect the queue.
+    pub fn from_bits(bits: u64) -> Self {
+        QueueFeatures(bits & Self::ALL)
+    }
+
+    /// Return the feature bits.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Check whether VIRTIO_F_RING_EVENT_IDX is enabled.
+    pub fn has_event_idx(&self) -> bool {
+        self.0 & Self::RING_EVENT_IDX != 0
+    }
+
+    /// Check whether VIRTIO_F_INDIRECT_DESC is enabled.
+    pub fn has_indirect(&self) -> bool {
+        self.0 & Self::INDIRECT_DESC != 0
+    }
+
+    /// Check whether VIRTIO_F_IN_ORDER is enabled.
+    pub fn has_in_order(&self) -> bool {
+        self.0 & Self::IN_ORDER != 0
+    }
+
+    /// Check whether VIRTIO_F_ORDER_PLATFORM is enabled.
+    pub fn has_order_platform(&self) -> bool {
+        self.0 & Self::ORDER_PLATFORM != 0
+    }
+
+    // Enable or disable the features of `mask`.
+    fn set(&mut self, mask: u64, enabled: bool) {
+        if enabled {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// A virtio queue's parameters.
+pub struct Queue<M: GuestAddressSpace> {
+    mem: M,
+
+    /// The maximal size in elements offered by the device
+    max_size: u16,
+
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+
+    /// The number of descriptor chains consumed and not added to the used ring yet
+    in_flight: u16,
+
+    /// The value of the available ring `idx` field read by the last `refresh_avail_idx`
+    cached_avail_idx: Wrapping<u16>,
+
+    /// The negotiated features that affect the queue
+    features: QueueFeatures,
+
+    /// Whether `add_used` checks for used ring overflows
+    used_overflow_check: bool,
+
+    /// Whether indirect descriptor tables are checked not to overlap the rings
+    indirect_alias_check: bool,
+
+    /// The maximum number of descriptors accepted in an indirect descriptor table
+    max_indirect_table_len: u16,
+
+    /// The maximum total length of the buffers of a descriptor chain
+    max_chain_bytes: u64,
+
+    /// Whether descriptor chains read runs of contiguous descriptors at once
+    descriptor_prefetch: bool,
+
+    /// How an available ring `idx` too far ahead of `next_avail` is handled
+    avail_overrun: AvailOverrun,
+
+    /// How the accesses to the `idx` fields of the rings synchronize with the driver
+    memory_ordering: MemoryOrdering,
+
+    /// Whether VIRTQ_AVAIL_F_NO_INTERRUPT suppresses notifications with EVENT_IDX as well
+    honor_avail_flags_with_event_idx: bool,
+
+    /// The last used value when using EVENT_IDX
+    signalled_used: Option<Wrapping<u16>>,
+
+    /// The minimum number of used elements between two notifications
+    notification_coalescing: u16,
+
+    /// The used ring index when the driver was last notified, for notification coalescing
+    coalescing_base: Wrapping<u16>,
+
+    /// The queue size in elements the driver selected
+    pub size: u16,
+
+    /// Indicates if the queue is finished with configuration
+    pub ready: bool,
+
+    /// Guest physical address of the descriptor table
+    pub desc_table: GuestAddress,
+
+    /// Guest physical address of the available ring
+    pub avail_ring: GuestAddress,
+
+    /// Guest physical address of the used ring
+    pub used_ring: GuestAddress,
+
+    /// The statistics of the queue, which are shared with its descriptor chains and clones
+    #[cfg(feature = "metrics")]
+    metrics: Arc<QueueMetrics>,
+
+    /// The kind of the last error encountered since the last call to `take_last_error`
+    last_error: Option<ErrorKind>,
+
+    /// The event used to notify the driver about used buffers
+    #[cfg(feature = "eventfd")]
+    interrupt_evt: Option<Arc<EventFd>>,
+
+    /// The callback invoked when the available ring stops being empty
+    #[cfg(feature = "available-callback")]
+    became_available: Option<Arc<BecameAvailable>>,
+}
+
+impl<M: GuestAddressSpace> Queue<M> {
+    /// Constructs an empty virtio queue with the given `max_size`.
+    ///
+    /// `max_size` is assumed to be valid, and queues with an invalid one are only rejected
+    /// later by `is_valid`. Use `try_new` to check it upfront.
+    pub fn new(mem: M, max_size: u16) -> Queue<M> {
+        Queue {
+            mem,
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: GuestAddress(0),
+            avail_ring: GuestAddress(0),
+            used_ring: GuestAddress(0),
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            in_flight: 0,
+            cached_avail_idx: Wrapping(0),
+            features: QueueFeatures(QueueFeatures::INDIRECT_DESC),
+            used_overflow_check: false,
+            indirect_alias_check: false,
+            max_indirect_table_len: u16::MAX,
+            max_chain_bytes: u64::MAX,
+            descriptor_prefetch: false,
+            avail_overrun: AvailOverrun::Clamp,
+            memory_ordering: MemoryOrdering::StrictAcqRel,
+            honor_avail_flags_with_event_idx: false,
+            signalled_used: None,
+            notification_coalescing: 0,
+            coalescing_base: Wrapping(0),
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(QueueMetrics::default()),
+            last_error: None,
+            #[cfg(feature = "eventfd")]
+            interrupt_evt: None,
+            #[cfg(feature = "available-callback")]
+            became_available: None,
+        }
+    }
+
+    /// Constructs a virtio queue with the given `max_size`, configured from the raw values of
+    /// the transport registers.
+    ///
+    /// The values go through the same validation as the `set_size` and `set_*_address`
+    /// setters, so invalid ones are ignored and leave the defaults set by `new` in place.
+    pub fn from_registers(mem: M, max_size: u16, regs: QueueRegisters) -> Queue<M> {
+        let mut queue = Queue::new(mem, max_size);
+        // The setters log the values they reject.
+        let _ = queue.set_size(regs.size);
+        let _ = queue.set_desc_table_address(Some(regs.desc_table_lo), Some(regs.desc_table_hi));
+        let _ = queue.set_avail_ring_address(Some(regs.avail_ring_lo), Some(regs.avail_ring_hi));
+        let _ = queue.set_used_ring_address(Some(regs.used_ring_lo), Some(regs.used_ring_hi));
+        queue.ready = regs.ready == 1;
+        queue
+    }
+
+    /// Constructs an empty virtio queue with the given `max_size`, which must be a non-zero
+    /// power of 2 not greater than `VIRTQ_MAX_SIZE`, otherwise `Error::InvalidSize` is returned.
+    pub fn try_new(mem: M, max_size: u16) -> Result<Queue<M>, Error> {
+        if !is_valid_max_size(max_size) {
+            error!("virtio queue with invalid maximum size: {}", max_size);
+            return Err(Error::InvalidSize);
+        }
+        Ok(Queue::new(mem, max_size))
+    }
+
+    /// Constructs a virtio queue from the values of all its driver visible state, for example
+    /// when restoring a queue from a snapshot.
+    ///
+    /// `max_size` is checked as for `try_new`, and `size` must be a non-zero power of 2 not
+    /// greater than `max_size`. The other values are used as they are, and any state not
+    /// passed here (such as the queue policy settings) gets the defaults set by `new`. Like
+    /// `set_next_used`, this doesn't access guest memory.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_parts(
+        mem: M,
+        max_size: u16,
+        size: u16,
+        ready: bool,
+        desc_table: GuestAddress,
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+        next_avail: u16,
+        next_used: u16,
+        event_idx_enabled: bool,
+    ) -> Result<Queue<M>, QueueError> {
+        if !is_valid_max_size(max_size) {
+            error!("virtio queue with invalid maximum size: {}", max_size);
+            return Err(QueueError::InvalidMaxSize);
+        }
+        let mut queue = Queue::new(mem, max_size);
+        if !queue.is_valid_size(size) {
+            error!("virtio queue with invalid size: {}", size);
+            return Err(QueueError::InvalidSize);
+        }
+
+        queue.size = size;
+        queue.ready = ready;
+        queue.desc_table = desc_table;
+        queue.avail_ring = avail_ring;
+        queue.used_ring = used_ring;
+        queue.set_next_avail(next_avail);
+        queue.set_next_used(next_used);
+        queue.set_event_idx(event_idx_enabled);
+        Ok(queue)
+    }
+
+    /// Return a view of the guest memory the queue accesses, as provided by its
+    /// `GuestAddressSpace`.
+    pub fn memory(&self) -> M::T {
+        self.mem.memory()
+    }
+
+    /// Gets the virtio queue maximum size.
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    /// Return the actual size of the queue, as the driver may not set up a
+    /// queue as big as the device allows.
+    pub fn actual_size(&self) -> u16 {
+        min(self.size, self.max_size)
+    }
+
+    /// Return the size in bytes of the descriptor table of a queue with `size` elements.
+    pub fn desc_table_size(size: u16) -> u64 {
+        Self::component_sizes(size).0
+    }
+
+    /// Return the size in bytes of the available ring of a queue with `size` elements,
+    /// including the `used_event` field.
+    pub fn avail_ring_size(size: u16) -> u64 {
+        Self::component_sizes(size).1
+    }
+
+    /// Return the size in bytes of the used ring of a queue with `size` elements, including
+    /// the `avail_event` field.
+    pub fn used_ring_size(size: u16) -> u64 {
+        Self::component_sizes(size).2
+    }
+
+    // Return the sizes computed by `ring_sizes` for a queue with `size` elements. They are far
+    // from overflowing for a `u16` size, so the fallback value is never used.
+    fn component_sizes(size: u16) -> (u64, u64, u64) {
+        ring_sizes(u64::from(size)).unwrap_or((u64::MAX, u64::MAX, u64::MAX))
+    }
+
+    // Check that `size` is a non-zero power of 2 not greater than either `max_size` or the
+    // limit of the virtio standard.
+    fn is_valid_size(&self, size: u16) -> bool {
+        size <= self.max_size && size <= VIRTQ_MAX_SIZE && size != 0 && (size & (size - 1)) == 0
+    }
+
+    /// Sets the queue size selected by the driver.
+    ///
+    /// The size must be a non-zero power of 2 not greater than `max_size` or
+    /// `VIRTQ_MAX_SIZE`, otherwise `QueueError::InvalidSize` is returned and the current size is
+    /// kept.
+    ///
+    /// The offsets of the ring entries are derived from the queue size, so the ring addresses
+    /// have to point to structures laid out for the size set here. Devices are expected to set
+    /// the size before the ring addresses, and not to change it afterwards. When the extents of
+    /// the rings are known, `validate_extents` checks they are consistent with the size.
+    pub fn set_size(&mut self, size: u16) -> Result<(), QueueError> {
+        if !self.is_valid_size(size) {
+            error!("virtio queue with invalid size: {}", size);
+            return Err(QueueError::InvalidSize);
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    /// Sets the guest physical address of the descriptor table.
+    ///
+    /// `low` and `high` are the least and most significant 4 bytes of the address, as written
+    /// by the driver to the transport registers. A `None` half keeps its current value. If the
+    /// resulting address is not aligned to 16 bytes, `QueueError::DescTableMisaligned` is
+    /// returned and the current address is kept.
+    pub fn set_desc_table_address(
+        &mut self,
+        low: Option<u32>,
+        high: Option<u32>,
+    ) -> Result<(), QueueError> {
+        self.set_desc_table(replace_address_halves(self.desc_table, low, high))
+    }
+
+    /// Sets the guest physical address of the available ring.
+    ///
+    /// The halves are handled as for `set_desc_table_address`. If the resulting address is not
+    /// aligned to 2 bytes, `QueueError::AvailRingMisaligned` is returned and the current address
+    /// is kept.
+    pub fn set_avail_ring_address(
+        &mut self,
+        low: Option<u32>,
+        high: Option<u32>,
+    ) -> Result<(), QueueError> {
+        self.set_avail_ring(replace_address_halves(self.avail_ring, low, high))
+    }
+
+    /// Sets the guest physical address of the used ring.
+    ///
+    /// The halves are handled as for `set_desc_table_address`. If the resulting address is not
+    /// aligned to 4 bytes, `QueueError::UsedRingMisaligned` is returned and the current address
+    /// is kept.
+    pub fn set_used_ring_address(
+        &mut self,
+        low: Option<u32>,
+        high: Option<u32>,
+    ) -> Result<(), QueueError> {
+        self.set_used_ring(replace_address_halves(self.used_ring, low, high))
+    }
+
+    /// Sets the guest physical address of the descriptor table from a 64-bit value.
+    ///
+    /// This is meant for transports that program the address at once instead of in halves. A
+    /// misaligned address is reported with `QueueError::DescTableMisaligned`, and the current
+    /// address is kept.
+    pub fn set_desc_table(&mut self, addr: GuestAddress) -> Result<(), QueueError> {
+        if addr.mask(0xf) != 0 {
+            error!("virtio queue descriptor table breaks alignment constraints");
+            return Err(QueueError::DescTableMisaligned);
+        }
+        self.desc_table = addr;
+        Ok(())
+    }
+
+    /// Sets the guest physical address of the available ring from a 64-bit value.
+    ///
+    /// A misaligned address is reported with `QueueError::AvailRingMisaligned`, and the current
+    /// address is kept.
+    pub fn set_avail_ring(&mut self, addr: GuestAddress) -> Result<(), QueueError> {
+        if addr.mask(0x1) != 0 {
+            error!("virtio queue available ring breaks alignment constraints");
+            return Err(QueueError::AvailRingMisaligned);
+        }
+        self.avail_ring = addr;
+        Ok(())
+    }
+
+    /// Sets the guest physical address of the used ring from a 64-bit value.
+    ///
+    /// A misaligned address is reported with `QueueError::UsedRingMisaligned`, and the current
+    /// address is kept.
+    pub fn set_used_ring(&mut self, addr: GuestAddress) -> Result<(), QueueError> {
+        if addr.mask(0x3) != 0 {
+            error!("virtio queue used ring breaks alignment constraints");
+            return Err(QueueError::UsedRingMisaligned);
+        }
+        self.used_ring = addr;
+        Ok(())
+    }
+
+    /// Return the size and the ring addresses of the queue.
+    pub fn ring_addresses(&self) -> RingAddresses {
+        RingAddresses {
+            size: self.size,
+            desc_table: self.desc_table,
+            avail_ring: self.avail_ring,
+            used_ring: self.used_ring,
+        }
+    }
+
+    /// Return the state of the queue, including the `idx` fields of the available and used
+    /// rings, for debugging purposes.
+    ///
+    /// The `idx` fields are read from guest memory, which fails if the rings are not mapped.
+    /// Nothing is checked or modified, so this can be used to inspect a queue that's stuck.
+    pub fn dump(&self) -> Result<QueueStateSnapshot, Error> {
+        let used_idx = self
+            .mem
+            .memory()
+            .load(
+                self.used_ring.unchecked_add(2),
+                self.ring_order(Ordering::Acquire),
+            )
+            .map_err(Error::GuestMemory)?;
+        Ok(QueueStateSnapshot {
+            addresses: self.ring_addresses(),
+            avail_idx: self.avail_idx(Ordering::Acquire)?.0,
+            used_idx,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+            event_idx_enabled: self.features.has_event_idx(),
+        })
+    }
+
+    /// Set the size and the ring addresses of the queue, for example to restore the values
+    /// returned by `ring_addresses`.
+    ///
+    /// Like the corresponding public fields, the values are set as they are. Use `validate` to
+    /// check the resulting configuration.
+    pub fn set_ring_addresses(&mut self, addrs: RingAddresses) {
+        self.size = addrs.size;
+        self.desc_table = addrs.desc_table;
+        self.avail_ring = addrs.avail_ring;
+        self.used_ring = addrs.used_ring;
+    }
+
+    /// Return a hash of the configuration of the queue.
+    ///
+    /// The hash covers the maximum size, the size, the ready state, the ring addresses and the
+    /// negotiated features (as returned by `features`), but neither the positions in the rings
+    /// nor the contents of guest memory. It is computed with the 64-bit FNV-1a function over a
+    /// fixed encoding of these values, so identically configured queues have the same hash,
+    /// even across different builds or platforms.
+    pub fn config_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let values = [
+            u64::from(self.max_size),
+            u64::from(self.size),
+            u64::from(self.ready),
+            self.features.bits(),
+            self.desc_table.raw_value(),
+            self.avail_ring.raw_value(),
+            self.used_ring.raw_value(),
+        ];
+
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .fold(FNV_OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// Reset the queue to a state that is acceptable for a device reset
+    pub fn reset(&mut self) {
+        self.reset_addresses();
+        self.signalled_used = None;
+        self.features = QueueFeatures(QueueFeatures::INDIRECT_DESC);
+        self.last_error = None;
+    }
+
+    /// Reset the configuration of the rings, keeping the negotiated features.
+    ///
+    /// Unlike `reset`, which is meant for a device reset, this only clears the ring addresses,
+    /// `ready`, `size` and the positions in the rings. The features enabled by `set_event_idx`,
+    /// `set_order_platform` and `set_in_order` are left unchanged, and so is the last used ring
+    /// index for which the driver was notified. This allows tearing down and reconfiguring a
+    /// queue while the feature set negotiated with the driver stays the same.
+    pub fn reset_addresses(&mut self) {
+        self.ready = false;
+        self.size = self.max_size;
+        self.desc_table = GuestAddress(0);
+        self.avail_ring = GuestAddress(0);
+        self.used_ring = GuestAddress(0);
+        self.next_avail = Wrapping(0);
+        self.next_used = Wrapping(0);
+        self.in_flight = 0;
+        self.coalescing_base = Wrapping(0);
+        self.cached_avail_idx = Wrapping(0);
+        #[cfg(feature = "available-callback")]
+        if let Some(became_available) = &self.became_available {
+            became_available.empty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the statistics collected for this queue.
+    ///
+    /// The statistics are shared by the clones of the queue, and are not affected by `reset`.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &QueueMetrics {
+        &self.metrics
+    }
+
+    /// Apply the negotiated device feature bits to the queue.
+    ///
+    /// The bits of VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_IN_ORDER and
+    /// VIRTIO_F_ORDER_PLATFORM enable the corresponding queue behaviors (which can also be
+    /// toggled one at a time with `set_event_idx`, `set_in_order` and `set_order_platform`),
+    /// and the other bits are ignored. Without VIRTIO_F_INDIRECT_DESC, the descriptor chains
+    /// returned by the queue reject indirect descriptors with `Error::InvalidIndirectDescriptor`.
+    ///
+    /// New queues, and queues after a `reset`, accept indirect descriptors and have the other
+    /// features disabled, until the negotiated features are applied.
+    pub fn set_features(&mut self, features: u64) {
+        let features = QueueFeatures::from_bits(features);
+        if features.has_event_idx() != self.features.has_event_idx() {
+            self.signalled_used = None;
+        }
+        self.features = features;
+    }
+
+    /// Return the negotiated features that affect the queue.
+    pub fn features(&self) -> QueueFeatures {
+        self.features
+    }
+
+    /// Enable/disable the VIRTIO_F_RING_EVENT_IDX feature.
+    pub fn set_event_idx(&mut self, enabled: bool) {
+        self.signalled_used = None;
+        self.features.set(QueueFeatures::RING_EVENT_IDX, enabled);
+    }
+
+    /// Enable/disable the VIRTIO_F_ORDER_PLATFORM feature.
+    ///
+    /// When enabled, every access to the `idx`, `flags` and event fields of the rings uses
+    /// `SeqCst` ordering, instead of the weaker orderings that are otherwise sufficient to
+    /// synchronize with a driver running on a CPU. This is required when the other side of the
+    /// queue is an actual hardware device, but the stronger barriers come with a performance
+    /// cost on weakly ordered architectures, so the feature should only be enabled if it has
+    /// been negotiated.
+    pub fn set_order_platform(&mut self, enabled: bool) {
+        self.features.set(QueueFeatures::ORDER_PLATFORM, enabled);
+    }
+
+    /// Check whether the VIRTIO_F_ORDER_PLATFORM feature is enabled.
+    pub fn order_platform(&self) -> bool {
+        self.features.has_order_platform()
+    }
+
+    /// Enable/disable the VIRTIO_F_IN_ORDER feature.
+    ///
+    /// When enabled, `add_used` and `add_used_batch` check that buffers are returned in the
+    /// same order in which they were made available, and `add_used_batch` only writes the
+    /// last element of a batch to the used ring (as allowed by the standard), which means the
+    /// driver only gets to see the length of the last buffer.
+    pub fn set_in_order(&mut self, enabled: bool) {
+        self.features.set(QueueFeatures::IN_ORDER, enabled);
+    }
+
+    /// Check whether the VIRTIO_F_IN_ORDER feature is enabled.
+    pub fn in_order(&self) -> bool {
+        self.features.has_in_order()
+    }
+
+    /// Enable/disable the used ring overflow check.
+    ///
+    /// When enabled and VIRTIO_F_RING_EVENT_IDX is negotiated, `add_used` and `add_used_batch`
+    /// use `used_event` as an estimate of the position up to which the driver consumed the
+    /// used ring, and return `Error::UsedRingOverflow` instead of overwriting used elements
+    /// that are more than `actual_size()` positions ahead of it. Drivers that poll the used
+    /// ring without updating `used_event` will trigger false positives, so the check is
+    /// disabled by default. It is not affected by `reset`.
+    pub fn set_used_overflow_check(&mut self, enabled: bool) {
+        self.used_overflow_check = enabled;
+    }
+
+    /// Check whether the used ring overflow check is enabled.
+    pub fn used_overflow_check(&self) -> bool {
+        self.used_overflow_check
+    }
+
+    /// Enable/disable honoring the VIRTQ_AVAIL_F_NO_INTERRUPT flag of the available ring in
+    /// `needs_notification` when VIRTIO_F_RING_EVENT_IDX is negotiated.
+    ///
+    /// The specification says the flag has to be ignored in that case, and only `used_event`
+    /// governs notifications, which is the default behavior. Some drivers set both during
+    /// transitions, so a conservative device can use the flag as an additional suppression
+    /// signal instead. The setting is not affected by `reset`.
+    pub fn set_honor_avail_flags_with_event_idx(&mut self, enabled: bool) {
+        self.honor_avail_flags_with_event_idx = enabled;
+    }
+
+    /// Check whether the VIRTQ_AVAIL_F_NO_INTERRUPT flag suppresses notifications when
+    /// VIRTIO_F_RING_EVENT_IDX is negotiated.
+    pub fn honor_avail_flags_with_event_idx(&self) -> bool {
+        self.honor_avail_flags_with_event_idx
+    }
+
+    /// Set the minimum number of used elements to publish between two notifications.
+    ///
+    /// `needs_notification` returns `false` until at least `min_elems` elements were added to
+    /// the used ring since it last returned `true`, and only then evaluates the usual
+    /// suppression conditions. With VIRTIO_F_RING_EVENT_IDX, a withheld notification is not
+    /// lost: the `used_event` crossings of the withheld elements are still taken into account
+    /// once the threshold is reached. Devices that also want to notify the driver after a
+    /// timeout can disable the coalescing (with a value of 0 or 1, which is the default) before
+    /// calling `needs_notification`. The setting is not affected by `reset`.
+    pub fn set_notification_coalescing(&mut self, min_elems: u16) {
+        self.notification_coalescing = min_elems;
+    }
+
+    /// Return the minimum number of used elements to publish between two notifications.
+    pub fn notification_coalescing(&self) -> u16 {
+        self.notification_coalescing
+    }
+
+    /// Enable/disable the indirect descriptor table aliasing check.
+    ///
+    /// When enabled, the descriptor chains returned by the queue reject indirect descriptor
+    /// tables that overlap the available or used rings with
+    /// `Error::InvalidIndirectDescriptorTable`. Such tables are never legitimate, but can be used
+    /// by a malicious driver to make the device interpret ring updates as descriptors. The check
+    /// is disabled by default, and is not affected by `reset`.
+    pub fn set_indirect_alias_check(&mut self, enabled: bool) {
+        self.indirect_alias_check = enabled;
+    }
+
+    /// Check whether the indirect descriptor table aliasing check is enabled.
+    pub fn indirect_alias_check(&self) -> bool {
+        self.indirect_alias_check
+    }
+
+    /// Set the maximum number of descriptors accepted in an indirect descriptor table.
+    ///
+    /// The descriptor chains returned by the queue reject larger indirect tables with
+    /// `Error::InvalidIndirectDescriptorTable`, which bounds the number of descriptors a driver
+    /// can make the device walk through a single indirect descriptor. The default is
+    /// `u16::MAX`, which is the largest table size allowed by the specification. The limit is
+    /// not affected by `reset`.
+    pub fn set_max_indirect_table_len(&mut self, max_len: u16) {
+        self.max_indirect_table_len = max_len;
+    }
+
+    /// Return the maximum number of descriptors accepted in an indirect descriptor table.
+    pub fn max_indirect_table_len(&self) -> u16 {
+        self.max_indirect_table_len
+    }
+
+    /// Set the maximum total length, in bytes, of the buffers of a descriptor chain.
+    ///
+    /// The length helpers of the descriptor chains returned by the queue (`total_len`,
+    /// `checked_total_len` and `require_writable_bytes` of `DescriptorChain`) return
+    /// `Error::InvalidChain` when the running total of the descriptor lengths exceeds
+    /// `max_bytes`. The default is `u64::MAX`, which means there's no limit. The limit is not
+    /// affected by `reset`.
+    pub fn set_max_chain_bytes(&mut self, max_bytes: u64) {
+        self.max_chain_bytes = max_bytes;
+    }
+
+    /// Return the maximum total length, in bytes, of the buffers of a descriptor chain.
+    pub fn max_chain_bytes(&self) -> u64 {
+        self.max_chain_bytes
+    }
+
+    /// Enable or disable descriptor prefetching for the descriptor chains returned by the queue.
+    ///
+    /// By default, a chain reads its descriptors from guest memory one at a time. With
+    /// prefetching enabled, a chain whose descriptors are laid out contiguously (each `next`
+    /// index following the previous one, as is always the case in indirect tables) reads up to
+    /// 16 of them with a single guest access, and takes the following ones from an internal
+    /// cache. This only helps contiguous layouts: when a descriptor doesn't follow the previous
+    /// one, the chain falls back to reading it on its own. The setting is not affected by
+    /// `reset`.
+    pub fn set_descriptor_prefetch(&mut self, enabled: bool) {
+        self.descriptor_prefetch = enabled;
+    }
+
+    /// Check whether descriptor prefetching is enabled.
+    pub fn descriptor_prefetch(&self) -> bool {
+        self.descriptor_prefetch
+    }
+
+    /// Return the queue with all the opt-in performance options that don't change its
+    /// behavior enabled, as in `Queue::new(mem, 256).optimized()`.
+    ///
+    /// This currently enables descriptor prefetching (see `set_descriptor_prefetch`). The
+    /// queue yields the same chains and writes the same used elements as with the default
+    /// configuration. Clone-free iteration doesn't depend on the configuration, and is used by
+    /// calling `iter_with_memory` instead of `iter`.
+    pub fn optimized(mut self) -> Self {
+        self.set_descriptor_prefetch(true);
+        self
+    }
+
+    /// Set how an available ring `idx` that is more than `actual_size()` entries ahead of the
+    /// next available entry is handled by `iter`, `peek`, `pop`, `snapshot_available` and
+    /// `available_descriptor_chains`.
+    ///
+    /// The default is `AvailOverrun::Clamp`. The setting is not affected by `reset`.
+    pub fn set_avail_overrun(&mut self, avail_overrun: AvailOverrun) {
+        self.avail_overrun = avail_overrun;
+    }
+
+    /// Return how an available ring `idx` too far ahead of the next available entry is handled.
+    pub fn avail_overrun(&self) -> AvailOverrun {
+        self.avail_overrun
+    }
+
+    /// Set how the queue synchronizes with the driver when reading the `idx` field of the
+    /// available ring (in `iter`, `pop` and the other methods that consume chains) and when
+    /// publishing the `idx` field of the used ring (in `add_used` and `add_used_batch`).
+    ///
+    /// See `MemoryOrdering` for the requirements of each policy. The default is
+    /// `MemoryOrdering::StrictAcqRel`. When VIRTIO_F_ORDER_PLATFORM is enabled, the accesses
+    /// use `SeqCst` ordering regardless of the policy. The setting is not affected by `reset`,
+    /// and it applies to the `UsedRing` returned by `split` as well.
+    pub fn set_memory_ordering(&mut self, policy: MemoryOrdering) {
+        self.memory_ordering = policy;
+    }
+
+    /// Return how the queue synchronizes with the driver when accessing the ring indices.
+    pub fn memory_ordering(&self) -> MemoryOrdering {
+        self.memory_ordering
+    }
+
+    // Returns the `(address, size)` pairs of the available and used rings that indirect
+    // descriptor tables must not overlap, or `None` if the check is disabled.
+    fn indirect_alias_ranges(&self) -> Option<[(GuestAddress, u64); 2]> {
+        if !self.indirect_alias_check {
+            return None;
+        }
+        let (_, avail_ring_size, used_ring_size) = ring_sizes(u64::from(self.actual_size()))?;
+        Some([
+            (self.avail_ring, avail_ring_size),
+            (self.used_ring, used_ring_size),
+        ])
+    }
+
+    // Returns the ordering that has to be used for an access to the rings which would
+    // otherwise use `order`.
+    fn ring_order(&self, order: Ordering) -> Ordering {
+        ring_order(self.features.has_order_platform(), order)
+    }
+
+    /// Check if the virtio queue configuration is valid.
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// Check if the virtio queue configuration is valid, and that each queue structure fits in
+    /// the area described by `extents`.
+    ///
+    /// This catches a queue size that was changed after the ring addresses were computed for a
+    /// different one, in which case the entries accessed based on `actual_size()` would fall
+    /// outside the rings the driver set up.
+    pub fn validate_extents(&self, extents: RingExtents) -> Result<(), QueueError> {
+        self.validate()?;
+
+        // The sizes can't overflow, as they are checked by `validate`.
+        let (desc_table_size, avail_ring_size, used_ring_size) =
+            ring_sizes(u64::from(self.actual_size())).ok_or(QueueError::InvalidSize)?;
+        if desc_table_size > extents.desc_table {
+            error!(
+                "virtio queue descriptor table doesn't fit: size:0x{:08x} extent:0x{:08x}",
+                desc_table_size, extents.desc_table
+            );
+            Err(QueueError::DescTableTooSmall)
+        } else if avail_ring_size > extents.avail_ring {
+            error!(
+                "virtio queue available ring doesn't fit: size:0x{:08x} extent:0x{:08x}",
+                avail_ring_size, extents.avail_ring
+            );
+            Err(QueueError::AvailRingTooSmall)
+        } else if used_ring_size > extents.used_ring {
+            error!(
+                "virtio queue used ring doesn't fit: size:0x{:08x} extent:0x{:08x}",
+                used_ring_size, extents.used_ring
+            );
+            Err(QueueError::UsedRingTooSmall)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check if the virtio queue configuration is valid, and return the reason if it isn't.
+    pub fn validate(&self) -> Result<(), QueueError> {
+        let mem = self.mem.memory();
+        let queue_size = self.actual_size() as u64;
+        let (desc_table_size, avail_ring_size, used_ring_size) = match ring_sizes(queue_size) {
+            Some(sizes) => sizes,
+            None => {
+                error!("virtio queue ring sizes overflow: {}", queue_size);
+                return Err(QueueError::InvalidSize);
+            }
+        };
+        let desc_table = self.desc_table;
+        let avail_ring = self.avail_ring;
+        let used_ring = self.used_ring;
+        if !self.ready {
+            error!("attempt to use virtio queue that is not marked ready");
+            Err(QueueError::NotReady)
+        } else if !self.is_valid_size(self.size) {
+            error!("virtio queue with invalid size: {}", self.size);
+            Err(QueueError::InvalidSize)
+        } else if desc_table
+            .checked_add(desc_table_size)
+            .map_or(true, |v| !mem.address_in_range(v))
+        {
+            error!(
+                "virtio queue descriptor table goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                desc_table.raw_value(),
+                desc_table_size
+            );
+            Err(QueueError::DescTableOutOfBounds)
+        } else if avail_ring
+            .checked_add(avail_ring_size)
+            .map_or(true, |v| !mem.address_in_range(v))
+        {
+            error!(
+                "virtio queue available ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                avail_ring.raw_value(),
+                avail_ring_size
+            );
+            Err(QueueError::AvailRingOutOfBounds)
+        } else if used_ring
+            .checked_add(used_ring_size)
+            .map_or(true, |v| !mem.address_in_range(v))
+        {
+            error!(
+                "virtio queue used ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                used_ring.raw_value(),
+                used_ring_size
+            );
+            Err(QueueError::UsedRingOutOfBounds)
+        } else if desc_table.mask(0xf) != 0 {
+            error!("virtio queue descriptor table breaks alignment constraints");
+            Err(QueueError::DescTableMisaligned)
+        } else if avail_ring.mask(0x1) != 0 {
+            error!("virtio queue available ring breaks alignment constraints");
+            Err(QueueError::AvailRingMisaligned)
+        } else if used_ring.mask(0x3) != 0 {
+            error!("virtio queue used ring breaks alignment constraints");
+            Err(QueueError::UsedRingMisaligned)
+        } else if ranges_overlap(desc_table, desc_table_size, avail_ring, avail_ring_size) {
+            error!("virtio queue descriptor table overlaps the available ring");
+            Err(QueueError::DescTableOverlapsAvailRing)
+        } else if ranges_overlap(desc_table, desc_table_size, used_ring, used_ring_size) {
+            error!("virtio queue descriptor table overlaps the used ring");
+            Err(QueueError::DescTableOverlapsUsedRing)
+        } else if ranges_overlap(avail_ring, avail_ring_size, used_ring, used_ring_size) {
+            error!("virtio queue available ring overlaps the used ring");
+            Err(QueueError::AvailRingOverlapsUsedRing)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads the `idx` field from the available ring.
+    ///
+    /// The requested `order` is upgraded to `SeqCst` if VIRTIO_F_ORDER_PLATFORM is enabled.
+    pub fn avail_idx(&self, order: Ordering) -> Result<Wrapping<u16>, Error> {
+        let addr = self.avail_ring.unchecked_add(2);
+        self.mem
+            .memory()
+            .load(addr, self.ring_order(order))
+            .map(Wrapping)
+            .map_err(Error::GuestMemory)
+    }
+
+    /// Returns the number of descriptor chain heads that the driver made available and that
+    /// haven't been consumed yet, without consuming them.
+    ///
+    /// The `idx` field of the available ring is read from guest memory, so the result may
+    /// include chains that `iter` doesn't yield until its cached index is refreshed.
+    pub fn available_descriptor_chains(&self) -> Result<u16, Error> {
+        let count = ring_index::wrapping_distance(self.next_avail, self.checked_avail_idx()?);
+        #[cfg(feature = "available-callback")]
+        self.observe_available(count != 0);
+        Ok(count)
+    }
+
+    /// Set a callback to invoke when the available ring is observed to hold descriptor chains
+    /// after being observed empty.
+    ///
+    /// The observations are made by `iter` and `available_descriptor_chains`, which invoke the
+    /// callback on each transition from empty to non-empty (the queue is considered empty
+    /// initially, and after `reset`). This can be used for example to switch a device from
+    /// notification driven processing to polling. The callback is shared by the clones of the
+    /// queue.
+    #[cfg(feature = "available-callback")]
+    pub fn set_on_became_available<F>(&mut self, callback: F)
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.became_available = Some(Arc::new(BecameAvailable {
+            callback: std::sync::Mutex::new(Box::new(callback)),
+            empty: AtomicBool::new(true),
+        }));
+    }
+
+    // Record whether the available ring holds descriptor chains, and invoke the callback set
+    // with `set_on_became_available` if it was empty when last observed.
+    #[cfg(feature = "available-callback")]
+    fn observe_available(&self, available: bool) {
+        if let Some(became_available) = &self.became_available {
+            let was_empty = became_available.empty.swap(!available, Ordering::Relaxed);
+            if was_empty && available {
+                if let Ok(mut callback) = became_available.callback.lock() {
+                    (*callback)();
+                }
+            }
+        }
+    }
+
+    // Read the `idx` field of the available ring, synchronizing with the driver according to
+    // the `memory_ordering` setting.
+    fn synced_avail_idx(&self) -> Result<Wrapping<u16>, Error> {
+        let idx = self.avail_idx(self.memory_ordering.load_order())?;
+        self.memory_ordering.fence_after_load();
+        Ok(idx)
+    }
+
+    // Read the `idx` field of the available ring with `synced_avail_idx`, and handle values
+    // that are more than `actual_size()` entries ahead of `next_avail` according to the
+    // `avail_overrun` setting.
+    fn checked_avail_idx(&self) -> Result<Wrapping<u16>, Error> {
+        let idx = self.synced_avail_idx()?;
+        let size = self.actual_size();
+        if ring_index::wrapping_distance(self.next_avail, idx) <= size {
+            return Ok(idx);
+        }
+
+        error!(
+            "Available ring idx {} is more than {} entries ahead of {}",
+            idx.0, size, self.next_avail.0
+        );
+        match self.avail_overrun {
+            AvailOverrun::Clamp => Ok(self.next_avail + Wrapping(size)),
+            AvailOverrun::Error => Err(Error::InvalidChain),
+        }
+    }
+
+    /// Reads the `idx` field from the available ring and caches it for use by `iter`.
+    ///
+    /// The value is loaded according to the `memory_ordering` setting (with `Acquire` ordering
+    /// by default), which synchronizes with the `Release` store of the driver, so the available
+    /// ring entries up to the returned index are visible to the device afterwards. Values too
+    /// far ahead of the next available entry are handled according to the `avail_overrun`
+    /// setting.
+    pub fn refresh_avail_idx(&mut self) -> Result<Wrapping<u16>, Error> {
+        self.cached_avail_idx = self.checked_avail_idx()?;
+        Ok(self.cached_avail_idx)
+    }
+
+    /// Check that the `idx` field of the available ring is at most `actual_size()` entries
+    /// ahead of the next available entry.
+    ///
+    /// A driver can't make more chains available than the size of the queue, so a larger gap
+    /// is caused by a guest bug or by memory corruption, and `Error::InvalidChain` is returned.
+    /// This catches such issues early, before stale entries of the available ring are
+    /// processed, regardless of the `avail_overrun` setting. When the `strict` feature is
+    /// enabled, `iter` performs this check each time it's called, which means the `idx` field
+    /// is read from guest memory even when the cached value is not exhausted.
+    pub fn validate_avail_idx(&self) -> Result<(), Error> {
+        let idx = self.synced_avail_idx()?;
+        if ring_index::wrapping_distance(self.next_avail, idx) > self.actual_size() {
+            error!(
+                "Available ring idx {} is more than {} entries ahead of {}",
+                idx.0,
+                self.actual_size(),
+                self.next_avail.0
+            );
+            return Err(Error::InvalidChain);
+        }
+        Ok(())
+    }
+
+    /// Use the available ring index carried by a driver notification, when the
+    /// VIRTIO_F_NOTIFICATION_DATA feature is negotiated.
+    ///
+    /// `next_avail` is the index of the available ring entry the driver will fill next, as
+    /// found in the notification payload. It's cached like the value read by
+    /// `refresh_avail_idx`, so the next `iter` call yields the chains up to that index without
+    /// reading the `idx` field of the available ring from guest memory first. Values that
+    /// don't advance the cache, such as stale ones from notifications racing with a previous
+    /// read of the ring, and values more than the queue size ahead of the next available entry
+    /// are ignored.
+    ///
+    /// `enable_notification` still reads the `idx` field from guest memory, because after
+    /// notifications are re-enabled there's no payload to cover the chains made available in
+    /// the meantime.
+    pub fn set_notification_data(&mut self, next_avail: u16) {
+        let ahead = ring_index::wrapping_distance(self.next_avail, Wrapping(next_avail));
+        if ahead > self.actual_size() || ahead <= self.cached_avail_len() {
+            return;
+        }
+        // The driver makes the ring entries visible before notifying the device, so this pairs
+        // with its barrier like the `Acquire` load of the `idx` field would.
+        fence(Ordering::Acquire);
+        self.cached_avail_idx = Wrapping(next_avail);
+    }
+
+    /// A consuming iterator over all available descriptor chain heads offered by the driver.
+    ///
+    /// The iterator stops at the available ring index cached by the last `refresh_avail_idx`
+    /// call. The cache is only refreshed from guest memory when all the chains up to that index
+    /// have been consumed, so repeated calls don't have to read the `idx` field each time. The
+    /// memory ordering established by the `Acquire` load of the cached value still covers
+    /// the chains yielded later on, but chains the driver made available after that load are
+    /// only seen once the cache is exhausted, or after an explicit `refresh_avail_idx` call.
+    pub fn iter(&mut self) -> Result<AvailIter<'_, M>, Error> {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("virtio_queue::iter", next_avail = self.next_avail.0).entered();
+        let last_index = self.iter_end()?;
+        Ok(self.avail_iter(last_index))
+    }
+
+    /// A consuming iterator over the available descriptor chain heads offered by the driver,
+    /// whose chains borrow `mem` instead of holding their own handle to guest memory.
+    ///
+    /// `iter` hands each chain the value returned by `GuestAddressSpace::memory`, which is a
+    /// clone of a guard (such as an `Arc`) for most address spaces. The chains returned by this
+    /// method are `DescriptorChain<&M::M>`s instead, which only copy a reference. `mem` must be
+    /// a view of the guest memory the queue is set up for, typically obtained once for a batch
+    /// of chains, as in `let mem = q.memory(); q.iter_with_memory(&*mem)`.
+    ///
+    /// Devices that are generic over the `GuestAddressSpace` of their chains can thus switch
+    /// from `iter` to this method without other changes, while code that names
+    /// `DescriptorChain<M>` has to name `DescriptorChain<&M::M>` instead. Apart from the chain
+    /// memory, the iterator behaves exactly like the one returned by `iter`.
+    pub fn iter_with_memory<'a>(
+        &'a mut self,
+        mem: &'a M::M,
+    ) -> Result<AvailIter<'a, &'a M::M>, Error> {
+        let last_index = self.iter_end()?;
+        Ok(self.avail_iter_in::<&M::M>(mem, last_index))
+    }
+
+    /// A consuming iterator over the available descriptor chain heads offered by the driver,
+    /// that reports the guest memory errors encountered while reading the available ring.
+    ///
+    /// Unlike `iter`, which stops at the first head index it can't read and records the error
+    /// for `take_last_error`, the error is yielded by the iterator, after which the iteration
+    /// stops. This is also the case for an error reading the `idx` field of the available ring,
+    /// which is yielded as the first item. The errors are not recorded for `take_last_error`.
+    pub fn try_iter(&mut self) -> AvailIterFallible<'_, M> {
+        let (last_index, error) = match self.iter_end() {
+            Ok(last_index) => (last_index, None),
+            Err(e) => {
+                error!("Failed to read the available ring index: {}", e);
+                (self.next_avail, Some(e))
+            }
+        };
+        AvailIterFallible {
+            iter: self.avail_iter(last_index),
+            error,
+        }
+    }
+
+    // Return the number of chains left before the cached available ring index. `pop` and the
+    // methods built on it read the `idx` field directly, and may move `next_avail` past the
+    // cached value, in which case nothing is left.
+    fn cached_avail_len(&self) -> u16 {
+        let len = ring_index::wrapping_distance(self.next_avail, self.cached_avail_idx);
+        if len > self.actual_size() {
+            0
+        } else {
+            len
+        }
+    }
+
+    // Return the available ring index where `iter` stops, refreshing the cached value if all
+    // the chains up to it have been consumed.
+    fn iter_end(&mut self) -> Result<Wrapping<u16>, Error> {
+        #[cfg(feature = "strict")]
+        self.validate_avail_idx()?;
+        if self.cached_avail_len() == 0 {
+            self.refresh_avail_idx()?;
+        }
+        #[cfg(feature = "available-callback")]
+        self.observe_available(self.next_avail != self.cached_avail_idx);
+        Ok(self.cached_avail_idx)
+    }
+
+    // Return an iterator over the available chains from `next_avail` up to `last_index`.
+    fn avail_iter(&mut self, last_index: Wrapping<u16>) -> AvailIter<'_, M> {
+        let mem = self.mem.memory();
+        self.avail_iter_in::<M>(mem, last_index)
+    }
+
+    // Return an iterator like `avail_iter`, whose chains access guest memory through `mem`.
+    fn avail_iter_in<A: GuestAddressSpace>(
+        &mut self,
+        mem: A::T,
+        last_index: Wrapping<u16>,
+    ) -> AvailIter<'_, A> {
+        AvailIter::new(
+            mem,
+            self.chain_config(),
+            last_index,
+            &mut self.next_avail,
+            &mut self.in_flight,
+            &mut self.last_error,
+            #[cfg(feature = "metrics")]
+            Some(&self.metrics),
+        )
+    }
+
+    // Return the settings the chains yielded by this queue are subject to.
+    fn chain_config(&self) -> ChainConfig {
+        ChainConfig {
+            desc_table: self.desc_table,
+            avail_ring: self.avail_ring,
+            queue_size: self.actual_size(),
+            ring_ranges: self.indirect_alias_ranges(),
+            max_indirect_table_len: self.max_indirect_table_len,
+            max_chain_bytes: self.max_chain_bytes,
+            indirect_allowed: self.features.has_indirect(),
+            descriptor_prefetch: self.descriptor_prefetch,
+        }
+    }
+
+    /// A consuming iterator over at most `max` of the available descriptor chain heads offered
+    /// by the driver.
+    ///
+    /// This works like `iter`, except that the remaining chains are left in the available ring
+    /// for the next iteration, so a device can bound the amount of work done for a queue before
+    /// servicing other ones. Only the chains that are actually yielded are consumed.
+    pub fn iter_budget(&mut self, max: u16) -> Result<AvailIter<'_, M>, Error> {
+        let mut iter = self.iter()?;
+        if iter.len() > usize::from(max) {
+            iter.last_index = *iter.next_avail + Wrapping(max);
+        }
+        Ok(iter)
+    }
+
+    /// Return the descriptor chain headed by the descriptor at `head_index` in the descriptor
+    /// table.
+    ///
+    /// This gives access to a chain independently of the available ring, for example to replay
+    /// requests, and leaves the position in the available ring unchanged. The chain goes
+    /// through the same checks as the ones returned by `iter`. `Error::InvalidDescriptorIndex`
+    /// is returned if `head_index` is out of bounds.
+    pub fn descriptor_chain(&self, head_index: u16) -> Result<DescriptorChain<M>, Error> {
+        if head_index >= self.actual_size() {
+            error!(
+                "attempted to access out of bounds descriptor chain head: {}",
+                head_index
+            );
+            return Err(Error::InvalidDescriptorIndex);
+        }
+
+        #[allow(unused_mut)]
+        let mut chain = self.chain_config().chain(self.mem.memory(), head_index);
+        #[cfg(feature = "metrics")]
+        {
+            chain.metrics = Some(self.metrics.clone());
+        }
+        Ok(chain)
+    }
+
+    /// Return the next available descriptor chain, if any, without consuming it.
+    ///
+    /// The position in the available ring is not changed, so the same chain is returned again
+    /// by the next call to `peek`, `pop` or `iter`. Call `pop` to consume it.
+    pub fn peek(&self) -> Result<Option<DescriptorChain<M>>, Error> {
+        // The chain is not consumed, so the position and the count of chains in flight are
+        // updated in copies. Errors are returned by `try_next` instead of being recorded.
+        let mut next_avail = self.next_avail;
+        let mut in_flight = self.in_flight;
+        AvailIter::new(
+            self.mem.memory(),
+            self.chain_config(),
+            self.checked_avail_idx()?,
+            &mut next_avail,
+            &mut in_flight,
+            &mut None,
+            #[cfg(feature = "metrics")]
+            None,
+        )
+        .try_next()
+        .transpose()
+    }
+
+    /// Consume and return the next available descriptor chain, if any.
+    pub fn pop(&mut self) -> Result<Option<DescriptorChain<M>>, Error> {
+        let last_index = self.checked_avail_idx()?;
+        self.avail_iter(last_index).try_next().transpose()
+    }
+
+    /// Consume and return the next available descriptor chain, or `None` if there's none.
+    ///
+    /// This is meant for devices that process one chain at a time, and handles errors like
+    /// `iter` does: they are recorded for `take_last_error`, and `None` is returned. Unlike
+    /// `iter`, the `idx` field of the available ring is read from guest memory on each call.
+    pub fn pop_descriptor_chain(&mut self) -> Option<DescriptorChain<M>> {
+        let result = self.pop();
+        self.record_error(result).ok().flatten()
+    }
+
+    /// Consume the available descriptor chains, passing each of them to `f`, and return the
+    /// number of chains processed.
+    ///
+    /// Unlike `iter`, which stops at the first error and records it for `take_last_error`,
+    /// errors are returned as soon as they occur, both when accessing the chains and when
+    /// returned by `f`. In both cases, the failing chain is left in the available ring, so it's
+    /// the first one returned by the next call to `process`, `iter`, `peek` or `pop`. Like for
+    /// `pop`, the `idx` field of the available ring is read from guest memory for each chain.
+    pub fn process<F>(&mut self, mut f: F) -> Result<usize, Error>
+    where
+        F: FnMut(DescriptorChain<M>) -> Result<(), Error>,
+    {
+        let mut count = 0;
+        while let Some(chain) = self.pop()? {
+            if let Err(e) = f(chain) {
+                self.rewind(1);
+                return Err(e);
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Return copies of all the descriptor chains that are currently available, without
+    /// consuming them.
+    ///
+    /// The position in the available ring is not changed, so the same chains are going to be
+    /// yielded by the next call to `iter`.
+    #[cfg(feature = "alloc")]
+    pub fn snapshot_available(&self) -> Result<Vec<OwnedChain>, Error> {
+        // Like for `peek`, the chains are not consumed.
+        let mut next_avail = self.next_avail;
+        let mut in_flight = self.in_flight;
+        let mut last_error = None;
+        let mut iter: AvailIter<'_, M> = AvailIter::new(
+            self.mem.memory(),
+            self.chain_config(),
+            self.checked_avail_idx()?,
+            &mut next_avail,
+            &mut in_flight,
+            &mut last_error,
+            #[cfg(feature = "metrics")]
+            None,
+        );
+
+        let mut chains = Vec::new();
+        while let Some(chain) = iter.try_next() {
+            let chain = chain?;
+            chains.push(OwnedChain {
+                head_index: chain.head_index(),
+                descriptors: chain.collect_descriptors()?,
+            });
+        }
+        Ok(chains)
+    }
+
+    /// Return a summary of each descriptor chain that is currently available, without consuming
+    /// them.
+    ///
+    /// This is meant for diagnostics, and has the same semantics as `snapshot_available`.
+    #[cfg(feature = "alloc")]
+    pub fn analyze_available(&self) -> Result<Vec<ChainSummary>, Error> {
+        Ok(self
+            .snapshot_available()?
+            .iter()
+            .map(ChainSummary::new)
+            .collect())
+    }
+
+    /// Puts an available descriptor head into the used ring for use by the guest.
+    ///
+    /// On success, returns the new value of the `idx` field of the used ring. This is a free
+    /// running index, so the returned value wraps around modulo 2^16.
+    pub fn add_used(&mut self, head_index: u16, len: u32) -> Result<u16, Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "virtio_queue::add_used",
+            head_index,
+            len,
+            next_used = self.next_used.0
+        )
+        .entered();
+        let result = self.add_used_elem(head_index, len);
+        #[cfg(feature = "metrics")]
+        if result.is_ok() {
+            QueueMetrics::add(&self.metrics.used_elements, 1);
+        }
+        self.record_error(result)
+    }
+
+    /// Puts an available descriptor head into the used ring for use by the guest, after
+    /// checking that `len` doesn't exceed the total length of the writable descriptors of the
+    /// chain headed by `head_index`.
+    ///
+    /// The chain is read from guest memory, which makes this slower than `add_used`. If the
+    /// chain can't hold `len` bytes, `Error::InvalidChain` is returned, and the used ring is
+    /// not updated.
+    pub fn add_used_checked(&mut self, head_index: u16, len: u32) -> Result<u16, Error> {
+        let result = self.check_used_len(head_index, len);
+        self.record_error(result)?;
+        self.add_used(head_index, len)
+    }
+
+    // Check that the writable descriptors of the chain headed by `head_index` can hold at
+    // least `len` bytes.
+    fn check_used_len(&self, head_index: u16, len: u32) -> Result<(), Error> {
+        let chain = self.descriptor_chain(head_index)?;
+        chain
+            .require_writable_bytes(u64::from(len))
+            .inspect_err(|_| {
+                error!(
+                    "used length {} exceeds the writable capacity of chain {}",
+                    len, head_index
+                )
+            })
+    }
+
+    // Implements `add_used`, without recording the error.
+    fn add_used_elem(&mut self, head_index: u16, len: u32) -> Result<u16, Error> {
+        self.add_used_elems(&[(head_index, len)])
+            .map(|_| self.next_used.0)
+    }
+
+    /// Puts multiple available descriptor heads into the used ring for use by the guest.
+    ///
+    /// Each element of `elems` is a `(head_index, len)` pair, as would be passed to `add_used`.
+    /// All the used elements are written first, and the `idx` field of the used ring is then
+    /// updated with a single `Release` store, so the driver observes the whole batch at once.
+    /// Every head index is validated before anything is written to the used ring, and
+    /// `Error::UsedRingOverflow` is returned if there are more elements than `actual_size()`.
+    ///
+    /// If VIRTIO_F_IN_ORDER is enabled, only the last element of the batch is written to the
+    /// used ring.
+    pub fn add_used_batch(&mut self, elems: &[(u16, u32)]) -> Result<(), Error> {
+        let result = self.add_used_elems(elems);
+        #[cfg(feature = "metrics")]
+        if result.is_ok() {
+            QueueMetrics::add(&self.metrics.used_elements, elems.len() as u64);
+        }
+        self.record_error(result)
+    }
+
+    // Implements `add_used_batch`, without recording the error.
+    fn add_used_elems(&mut self, elems: &[(u16, u32)]) -> Result<(), Error> {
+        self.update_used_ring(|state, mem| state.add_used_elems(mem, elems))?;
+        // The batch fits in the used ring, so its length fits in a `u16`.
+        self.in_flight = self.in_flight.saturating_sub(elems.len() as u16);
+        Ok(())
+    }
+
+    // Return the state of the used ring, which implements the operations on it.
+    fn used_ring_state(&self) -> UsedRingState {
+        UsedRingState {
+            avail_ring: self.avail_ring,
+            used_ring: self.used_ring,
+            size: self.actual_size(),
+            next_used: self.next_used,
+            signalled_used: self.signalled_used,
+            coalescing_base: self.coalescing_base,
+            features: self.features,
+            used_overflow_check: self.used_overflow_check,
+            honor_avail_flags_with_event_idx: self.honor_avail_flags_with_event_idx,
+            notification_coalescing: self.notification_coalescing,
+            memory_ordering: self.memory_ordering,
+        }
+    }
+
+    // Run `f` on the state of the used ring, and keep the positions it updated, even when an
+    // error is returned.
+    fn update_used_ring<T, F>(&mut self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut UsedRingState, &M::M) -> Result<T, Error>,
+    {
+        let mut state = self.used_ring_state();
+        let result = f(&mut state, &*self.mem.memory());
+        self.set_used_ring_positions(&state);
+        result
+    }
+
+    // Take over the positions in the used ring from `state`.
+    fn set_used_ring_positions(&mut self, state: &UsedRingState) {
+        self.next_used = state.next_used;
+        self.signalled_used = state.signalled_used;
+        self.coalescing_base = state.coalescing_base;
+    }
+
+    // Remember the error returned by an operation, so it can be retrieved later on with
+    // `take_last_error`.
+    fn record_error<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(e) = &result {
+            self.last_error = Some(e.kind());
+        }
+        result
+    }
+
+    /// Returns the kind of the last error encountered while iterating over the available
+    /// descriptor chains or adding used elements, and clears it.
+    ///
+    /// The errors are also reported through the `Result` returned by `add_used` and
+    /// `add_used_batch`. This is meant for devices that would rather poll for errors
+    /// periodically, and makes visible the errors that stop an `AvailIter` early.
+    pub fn take_last_error(&mut self) -> Option<ErrorKind> {
+        self.last_error.take()
+    }
+
+    // Helper method that writes `val` to the `avail_event` field of the used ring, using
+    // the provided ordering (or a stronger one, when VIRTIO_F_ORDER_PLATFORM is enabled).
+    fn set_avail_event(&self, val: u16, order: Ordering) -> Result<(), Error> {
+        let offset =
+            VIRTQ_USED_RING_HEADER_SIZE + u64::from(self.actual_size()) * VIRTQ_USED_ELEMENT_SIZE;
+        let addr = self.used_ring.unchecked_add(offset);
+        self.mem
+            .memory()
+            .store(val, addr, self.ring_order(order))
+            .map_err(Error::GuestMemory)
+    }
+
+    /// Return the value of the `avail_event` field of the used ring, which is written by the
+    /// device when VIRTIO_F_EVENT_IDX is negotiated.
+    ///
+    /// This is meant for debugging, and for implementing the driver side of a queue in tests.
+    /// The requested `order` is upgraded to `SeqCst` if VIRTIO_F_ORDER_PLATFORM is enabled.
+    pub fn avail_event(&self, order: Ordering) -> Result<u16, Error> {
+        let offset =
+            VIRTQ_USED_RING_HEADER_SIZE + u64::from(self.actual_size()) * VIRTQ_USED_ELEMENT_SIZE;
+        let addr = self.used_ring.unchecked_add(offset);
+        self.mem
+            .memory()
+            .load(addr, self.ring_order(order))
+            .map_err(Error::GuestMemory)
+    }
+
+    // Set the value of the `flags` field of the used ring, applying the specified ordering
+    // (or a stronger one, when VIRTIO_F_ORDER_PLATFORM is enabled).
+    fn set_used_flags(&mut self, val: u16, order: Ordering) -> Result<(), Error> {
+        self.mem
+            .memory()
+            .store(val, self.used_ring, self.ring_order(order))
+            .map_err(Error::GuestMemory)
+    }
+
+    // Return the value of the `flags` field of the used ring, applying the specified ordering
+    // (or a stronger one, when VIRTIO_F_ORDER_PLATFORM is enabled).
+    fn used_flags(&self, order: Ordering) -> Result<u16, Error> {
+        self.mem
+            .memory()
+            .load(self.used_ring, self.ring_order(order))
+            .map_err(Error::GuestMemory)
+    }
+
+    // Write the appropriate values to enable or disable notifications from the driver. Every
+    // access in this method uses `Relaxed` ordering because a fence is added by the caller
+    // when appropriate.
+    fn set_notification(&mut self, enable: bool) -> Result<(), Error> {
+        if enable {
+            if self.features.has_event_idx() {
+                // We call `set_avail_event` using the `next_avail` value, instead of reading
+                // and using the current `avail_idx` to avoid missing notifications. More
+                // details in `enable_notification`.
+                self.set_avail_event(self.next_avail.0, Ordering::Relaxed)?;
+            } else {
+                self.set_used_flags(0, Ordering::Relaxed)?;
+            }
+        }
+        // Notifications are effectively disabled by default after triggering once when
+        // `VIRTIO_F_EVENT_IDX` is negotiated, so we don't do anything in that case.
+        else if !self.features.has_event_idx() {
+            self.set_used_flags(VIRTQ_USED_F_NO_NOTIFY, Ordering::Relaxed)?;
+        }
+        Ok(())
+    }
+
+    /// Enable notification events from the guest driver. Returns true if one or more descriptors
+    /// can be consumed from the available ring after notifications were enabled (and thus it's
+    /// possible there will be no corresponding notification).
+
+    // TODO: Turn this into a doc comment/example.
+    // With the current implementation, a common way of consuming entries from the available ring
+    // while also leveraging notification suppression is to use a loop, for example:
+    //
+    // loop {
+    //     // We have to explicitly disable notifications if `VIRTIO_F_EVENT_IDX` has not been
+    //     // negotiated.
+    //     self.disable_notification()?;
+    //
+    //     for chain in self.iter()? {
+    //         // Do something with each chain ...
+    //         // Let's assume we process all available chains here.
+    //     }
+    //
+    //     // If `enable_notification` returns `true`, the driver has added more entries to the
+    //     // available ring.
+    //     if !self.enable_notification()? {
+    //         break;
+    //     }
+    // }
+    #[inline]
+    pub fn enable_notification(&mut self) -> Result<bool, Error> {
+        self.set_notification(true)?;
+        // Ensures the following read is not reordered before any previous write operation.
+        fence(Ordering::SeqCst);
+
+        // We double check here to avoid the situation where the available ring has been updated
+        // just before we re-enabled notifications, and it's possible to miss one. We compare the
+        // current `avail_idx` value to `self.next_avail` because it's where we stopped processing
         // entries. There are situations where we intentionally avoid processing everything in the
         // available ring (which will cause this method to return `true`), but in that case we'll
         // probably not re-enable notifications as we already know there are pending entries.
@@ -662,740 +3685,5451 @@ impl<M: GuestAddressSpace> Queue<M> {
             .map(|idx| idx != self.next_avail)
     }
 
-    /// Disable notification events from the guest driver.
-    #[inline]
-    pub fn disable_notification(&mut self) -> Result<(), Error> {
-        self.set_notification(false)
-    }
+    /// Disable notification events from the guest driver.
+    #[inline]
+    pub fn disable_notification(&mut self) -> Result<(), Error> {
+        self.set_notification(false)
+    }
+
+    /// Check whether the device currently tells the driver not to notify it, as seen by the
+    /// guest.
+    ///
+    /// This reads the `flags` field of the used ring from guest memory, and reports whether
+    /// `VIRTQ_USED_F_NO_NOTIFY` is set (for example by `disable_notification`). The flag is
+    /// ignored by the driver when VIRTIO_F_RING_EVENT_IDX is negotiated, so `false` is always
+    /// returned in that case.
+    pub fn interrupts_masked(&self) -> Result<bool, Error> {
+        if self.features.has_event_idx() {
+            return Ok(false);
+        }
+        self.used_flags(Ordering::Relaxed)
+            .map(|flags| flags & VIRTQ_USED_F_NO_NOTIFY != 0)
+    }
+
+    /// Return the value present in the `flags` field of the available ring (for example, to check
+    /// whether VIRTQ_AVAIL_F_NO_INTERRUPT is set).
+    ///
+    /// The field is controlled by the driver, which can change it at any time, so the value may
+    /// already be stale when it's returned. The ordering of the access is chosen by the caller,
+    /// and is upgraded to `SeqCst` if VIRTIO_F_ORDER_PLATFORM is enabled.
+    pub fn avail_flags(&self, order: Ordering) -> Result<u16, Error> {
+        load_avail_flags(&*self.mem.memory(), self.avail_ring, self.ring_order(order))
+    }
+
+    /// Check whether a notification to the guest is needed.
+    ///
+    /// Please note this method has side effects: once it returns `true`, it considers the
+    /// driver will actually be notified, remember the associated index in the used ring, and
+    /// won't return `true` again until the driver updates `used_event` and/or the notification
+    /// conditions hold once more.
+    pub fn needs_notification(&mut self) -> Result<bool, Error> {
+        let notification = self.update_used_ring(|state, mem| state.needs_notification(mem))?;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_notification(notification);
+        Ok(notification == Notification::Needed)
+    }
+
+    /// Put a used descriptor head into the used ring, and check whether a notification to the
+    /// guest is needed.
+    ///
+    /// This is the same as calling `add_used` followed by `needs_notification`, which issues a
+    /// `SeqCst` fence between publishing the used element and reading the notification
+    /// suppression fields of the driver. The queue doesn't notify the driver on its own: when
+    /// `true` is returned, the caller must actually raise the interrupt, as
+    /// `needs_notification` considers the driver notified from then on. The element is not
+    /// published if `add_used` fails, and its error is returned.
+    pub fn add_used_and_check_notify(&mut self, head: u16, len: u32) -> Result<bool, Error> {
+        self.add_used(head, len)?;
+        self.needs_notification()
+    }
+
+    /// Check whether `needs_notification` would return `true`, without any side effect.
+    ///
+    /// The same conditions are evaluated against the current state of the queue, but the
+    /// index in the used ring is not remembered, and the statistics are not updated. The
+    /// result is advisory: the driver can update `used_event` or the `flags` of the available
+    /// ring at any time, so a later call to `needs_notification` may return a different value.
+    pub fn would_notify(&self) -> Result<bool, Error> {
+        self.used_ring_state()
+            .notification(&*self.mem.memory())
+            .map(|notification| notification == Notification::Needed)
+    }
+
+    /// Set the `EventFd` used by `signal_used` to notify the driver.
+    ///
+    /// The event is shared by the clones of the queue, and is not affected by `reset`.
+    #[cfg(feature = "eventfd")]
+    pub fn set_interrupt_evt(&mut self, evt: EventFd) {
+        self.interrupt_evt = Some(Arc::new(evt));
+    }
+
+    /// Notify the driver about used buffers by writing to the interrupt `EventFd`, if
+    /// `needs_notification` says a notification is needed.
+    ///
+    /// Nothing happens if no `EventFd` was set with `set_interrupt_evt`, in which case
+    /// `needs_notification` is not called either.
+    #[cfg(feature = "eventfd")]
+    pub fn signal_used(&mut self) -> Result<(), Error> {
+        let evt = match self.interrupt_evt.clone() {
+            Some(evt) => evt,
+            None => return Ok(()),
+        };
+
+        if self.needs_notification()? {
+            evt.write(1).map_err(|e| {
+                error!("failed to signal the interrupt event: {}", e);
+                Error::InterruptEvent(e)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Move back `count` positions in the available ring, so the chains there are neither in
+    // flight nor counted as processed anymore.
+    fn rewind(&mut self, count: u16) {
+        self.next_avail -= Wrapping(count);
+        self.in_flight = self.in_flight.saturating_sub(count);
+        #[cfg(feature = "metrics")]
+        QueueMetrics::sub(&self.metrics.chains_processed, u64::from(count));
+    }
+
+    /// Goes back one position in the available descriptor chain offered by the driver.
+    /// Rust does not support bidirectional iterators. This is the only way to revert the effect
+    /// of an iterator increment on the queue.
+    pub fn go_to_previous_position(&mut self) {
+        self.rewind(1);
+    }
+
+    /// Goes back `count` positions in the available ring, so the last `count` consumed
+    /// descriptor chains are returned again by the next `iter`, `peek` or `pop` calls.
+    ///
+    /// This is meant for devices that have to give back chains they can't process yet. The
+    /// chains are given back in the reverse order they were consumed in, so `count` must not
+    /// include a chain consumed before one that is kept, and none of the chains may have been
+    /// added to the used ring. Debug builds check that `count` is not greater than the number
+    /// of chains consumed and not yet added to the used ring.
+    pub fn undo(&mut self, count: u16) {
+        debug_assert!(
+            count <= self.in_flight,
+            "undoing more chains than the ones in flight"
+        );
+        self.rewind(count);
+    }
+
+    /// Give back the descriptor chain consumed last, so it's the first one returned by the next
+    /// `iter`, `peek` or `pop` call.
+    ///
+    /// This rewinds the position in the available ring by one, like `go_to_previous_position`,
+    /// for example to defer a chain in favor of higher priority ones. The chain must not have
+    /// been added to the used ring. `Error::NoChainInFlight` is returned, and nothing changes,
+    /// if all the consumed chains were added to the used ring.
+    pub fn requeue_front(&mut self) -> Result<(), Error> {
+        if self.in_flight == 0 {
+            error!("attempted to requeue a descriptor chain, but none is in flight");
+            return Err(Error::NoChainInFlight);
+        }
+        self.undo(1);
+        Ok(())
+    }
+
+    /// Returns the index for the next descriptor in the available ring.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    /// Sets the index for the next descriptor in the available ring.
+    ///
+    /// This also invalidates the available ring index cached for `iter`, and forgets about the
+    /// chains consumed so far, which can't be given back with `undo` anymore.
+    pub fn set_next_avail(&mut self, next_avail: u16) {
+        self.next_avail = Wrapping(next_avail);
+        self.in_flight = 0;
+        self.cached_avail_idx = self.next_avail;
+    }
+
+    /// Returns the index for the next element to be added to the used ring.
+    pub fn next_used(&self) -> u16 {
+        self.next_used.0
+    }
+
+    /// Sets the index for the next element to be added to the used ring.
+    ///
+    /// Guest memory is not accessed, so the `idx` field of the used ring is not updated. The
+    /// device is responsible for keeping it consistent with `next_used`, for example when
+    /// restoring a queue from a snapshot. The count of used elements for notification
+    /// coalescing starts over from `next_used`.
+    pub fn set_next_used(&mut self, next_used: u16) {
+        self.next_used = Wrapping(next_used);
+        self.coalescing_base = self.next_used;
+    }
+
+    /// Split the queue into its available ring and its used ring, for devices that publish
+    /// used buffers separately from the processing of the available ring, for example from
+    /// another thread.
+    ///
+    /// The position in the used ring, the index for which the driver was last notified, and
+    /// the settings that affect the used ring are moved to the `UsedRing`, while the rest of the
+    /// queue goes with the `AvailRing`. Use `AvailRing::join` to get the queue back, with the
+    /// updates made through both halves.
+    pub fn split(self) -> (AvailRing<M>, UsedRing<M>)
+    where
+        M: Clone,
+    {
+        let used = UsedRing {
+            mem: self.mem.clone(),
+            state: self.used_ring_state(),
+            completed: 0,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics.clone(),
+        };
+        (AvailRing { queue: self }, used)
+    }
+}
+
+/// Iterates over the available descriptor chains, like `Queue::iter`.
+///
+/// If the `idx` field of the available ring can't be read, the error is logged and recorded
+/// for `take_last_error`, and the iterator is empty, the same way `AvailIter` stops at the first
+/// error.
+impl<'a, M: GuestAddressSpace> IntoIterator for &'a mut Queue<M> {
+    type Item = DescriptorChain<M>;
+    type IntoIter = AvailIter<'a, M>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let result = self.iter_end();
+        let last_index = match self.record_error(result) {
+            Ok(last_index) => last_index,
+            Err(e) => {
+                error!("Failed to read the available ring index: {}", e);
+                self.next_avail
+            }
+        };
+        self.avail_iter(last_index)
+    }
+}
+
+// The outcome of checking whether the driver has to be notified about used buffers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Notification {
+    // The driver has to be notified.
+    Needed,
+    // The driver doesn't want to be notified yet, according to `used_event`.
+    Suppressed,
+    // The notification is held back by the device, because of notification coalescing or of
+    // VIRTQ_AVAIL_F_NO_INTERRUPT with VIRTIO_F_RING_EVENT_IDX.
+    Withheld,
+    // The driver disabled notifications with VIRTQ_AVAIL_F_NO_INTERRUPT.
+    Masked,
+}
+
+// The state of a used ring, which implements adding used elements and checking whether the
+// driver has to be notified for both `Queue` and `UsedRing`.
+#[derive(Clone, Copy, Debug)]
+struct UsedRingState {
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    size: u16,
+    next_used: Wrapping<u16>,
+    signalled_used: Option<Wrapping<u16>>,
+    coalescing_base: Wrapping<u16>,
+    features: QueueFeatures,
+    used_overflow_check: bool,
+    honor_avail_flags_with_event_idx: bool,
+    notification_coalescing: u16,
+    memory_ordering: MemoryOrdering,
+}
+
+impl UsedRingState {
+    // Return the ordering to use for an access to the rings which would otherwise use `order`.
+    fn ring_order(&self, order: Ordering) -> Ordering {
+        ring_order(self.features.has_order_platform(), order)
+    }
+
+    // Implements `Queue::add_used_batch`, without recording the error.
+    fn add_used_elems<G: GuestMemory>(
+        &mut self,
+        mem: &G,
+        elems: &[(u16, u32)],
+    ) -> Result<(), Error> {
+        if let Some((head_index, _)) = elems
+            .iter()
+            .find(|(head_index, _)| *head_index >= self.size)
+        {
+            error!(
+                "attempted to add out of bounds descriptor to used ring: {}",
+                head_index
+            );
+            return Err(Error::InvalidDescriptorIndex);
+        }
+
+        // A batch that doesn't fit in the used ring would overwrite its own elements.
+        if elems.len() > usize::from(self.size) {
+            error!(
+                "attempted to add {} used elements to a used ring of size {}",
+                elems.len(),
+                self.size
+            );
+            return Err(Error::UsedRingOverflow);
+        }
+
+        self.check_used_overflow(mem, elems.len())?;
+
+        if self.features.has_in_order() {
+            self.check_in_order(mem, elems)?;
+
+            // The driver skips forward in the used ring based on the used `idx` value, so
+            // only the last element of the batch has to be written.
+            if let Some(&(head_index, len)) = elems.last() {
+                let last = self.next_used + Wrapping(elems.len() as u16 - 1);
+                store_used_elem(mem, self.used_ring, self.size, last, head_index, len)?;
+                self.next_used += Wrapping(elems.len() as u16);
+            }
+        } else {
+            for &(head_index, len) in elems {
+                store_used_elem(
+                    mem,
+                    self.used_ring,
+                    self.size,
+                    self.next_used,
+                    head_index,
+                    len,
+                )?;
+                self.next_used += Wrapping(1);
+            }
+        }
+
+        // Write `next_used` to the `idx` field of the used ring, synchronizing with the driver
+        // according to the `memory_ordering` setting.
+        self.memory_ordering.fence_before_store();
+        store_used_idx(
+            mem,
+            self.used_ring,
+            self.next_used.0,
+            self.ring_order(self.memory_ordering.store_order()),
+        )
+    }
+
+    // Check that adding `count` used elements doesn't overwrite used elements the driver didn't
+    // consume yet, if the overflow check is enabled.
+    fn check_used_overflow<G: GuestMemory>(&self, mem: &G, count: usize) -> Result<(), Error> {
+        if !self.used_overflow_check || !self.features.has_event_idx() {
+            return Ok(());
+        }
+
+        let pending = (self.next_used - self.used_event(mem)?).0;
+        if usize::from(pending) + count > usize::from(self.size) {
+            error!(
+                "used ring overflow: {} pending used elements, adding {}",
+                pending, count
+            );
+            return Err(Error::UsedRingOverflow);
+        }
+
+        Ok(())
+    }
+
+    // Check that the head indices in `elems` are the ones that were made available by the
+    // driver (in the same order), starting with the available ring entry that corresponds to
+    // the current `next_used` position.
+    fn check_in_order<G: GuestMemory>(&self, mem: &G, elems: &[(u16, u32)]) -> Result<(), Error> {
+        let mut used_idx = self.next_used;
+        for &(head_index, _) in elems {
+            let offset = VIRTQ_AVAIL_RING_HEADER_SIZE
+                + u64::from(used_idx.0 % self.size) * VIRTQ_AVAIL_ELEMENT_SIZE;
+            let avail_head: u16 = mem
+                .read_obj(self.avail_ring.unchecked_add(offset))
+                .map_err(Error::GuestMemory)?;
+            if avail_head != head_index {
+                error!(
+                    "attempted to add used descriptor out of order: {} (expected {})",
+                    head_index, avail_head
+                );
+                return Err(Error::UsedOutOfOrder);
+            }
+            used_idx += Wrapping(1);
+        }
+        Ok(())
+    }
+
+    // Read the `used_event` field of the available ring, with `Relaxed` ordering (or a stronger
+    // one, when VIRTIO_F_ORDER_PLATFORM is enabled).
+    fn used_event<G: GuestMemory>(&self, mem: &G) -> Result<Wrapping<u16>, Error> {
+        load_used_event(
+            mem,
+            self.avail_ring,
+            self.size,
+            self.ring_order(Ordering::Relaxed),
+        )
+    }
+
+    // Check whether fewer used elements than the coalescing threshold were published since
+    // the last notification.
+    fn coalescing_notification(&self) -> bool {
+        ring_index::wrapping_distance(self.coalescing_base, self.next_used)
+            < self.notification_coalescing
+    }
+
+    // Check whether the driver has to be notified, without any side effect.
+    fn notification<G: GuestMemory>(&self, mem: &G) -> Result<Notification, Error> {
+        // Complete all the writes in add_used() before reading the event.
+        fence(Ordering::SeqCst);
+
+        // Like the flag check below, this comes first so `signalled_used` keeps the index
+        // the driver was last notified for.
+        if self.coalescing_notification() {
+            return Ok(Notification::Withheld);
+        }
+
+        let flags = || load_avail_flags(mem, self.avail_ring, self.ring_order(Ordering::Relaxed));
+        if self.features.has_event_idx() {
+            // The flag is ignored according to the specification, unless requested otherwise.
+            // This check comes first, so `signalled_used` isn't updated for notifications that
+            // don't actually take place.
+            if self.honor_avail_flags_with_event_idx && flags()? & VIRTQ_AVAIL_F_NO_INTERRUPT != 0 {
+                return Ok(Notification::Withheld);
+            }
+            if let Some(old_idx) = self.signalled_used {
+                if !ring_index::is_behind(self.next_used, self.used_event(mem)?, old_idx) {
+                    return Ok(Notification::Suppressed);
+                }
+            }
+        } else if flags()? & VIRTQ_AVAIL_F_NO_INTERRUPT != 0 {
+            // Without VIRTIO_F_EVENT_IDX, the driver can use this flag to let the device know
+            // it doesn't want to be interrupted.
+            return Ok(Notification::Masked);
+        }
+
+        Ok(Notification::Needed)
+    }
+
+    // Implements `Queue::needs_notification`, without updating the statistics.
+    fn needs_notification<G: GuestMemory>(&mut self, mem: &G) -> Result<Notification, Error> {
+        let notification = self.notification(mem)?;
+        match notification {
+            Notification::Needed | Notification::Suppressed if self.features.has_event_idx() => {
+                self.signalled_used = Some(self.next_used);
+            }
+            _ => (),
+        }
+        if notification == Notification::Needed {
+            self.coalescing_base = self.next_used;
+        }
+        Ok(notification)
+    }
+}
+
+/// The available ring of a queue split with `Queue::split`.
+///
+/// The available descriptor chains are consumed like with the `Queue` methods of the same
+/// name. Adding used buffers is left to the matching `UsedRing`.
+#[derive(Debug)]
+pub struct AvailRing<M: GuestAddressSpace> {
+    queue: Queue<M>,
+}
+
+impl<M: GuestAddressSpace> AvailRing<M> {
+    /// A consuming iterator over all available descriptor chain heads offered by the driver.
+    ///
+    /// See `Queue::iter`.
+    pub fn iter(&mut self) -> Result<AvailIter<'_, M>, Error> {
+        self.queue.iter()
+    }
+
+    /// Consume and return the next available descriptor chain, if any.
+    pub fn pop(&mut self) -> Result<Option<DescriptorChain<M>>, Error> {
+        self.queue.pop()
+    }
+
+    /// Returns the index for the next descriptor in the available ring.
+    pub fn next_avail(&self) -> u16 {
+        self.queue.next_avail()
+    }
+
+    /// Enable notification events from the guest driver.
+    ///
+    /// See `Queue::enable_notification`.
+    pub fn enable_notification(&mut self) -> Result<bool, Error> {
+        self.queue.enable_notification()
+    }
+
+    /// Disable notification events from the guest driver.
+    pub fn disable_notification(&mut self) -> Result<(), Error> {
+        self.queue.disable_notification()
+    }
+
+    /// Put the queue back together from this available ring and `used`, which must come from
+    /// the same call to `Queue::split`.
+    ///
+    /// The chains added to the used ring through `used` are not in flight anymore.
+    pub fn join(self, used: UsedRing<M>) -> Queue<M> {
+        let mut queue = self.queue;
+        queue.set_used_ring_positions(&used.state);
+        queue.in_flight = queue.in_flight.saturating_sub(used.completed);
+        queue
+    }
+}
+
+/// The used ring of a queue split with `Queue::split`.
+///
+/// Used buffers are added and notifications are checked by the same code as for
+/// `Queue::add_used`, `Queue::add_used_batch` and `Queue::needs_notification`, and the
+/// statistics are shared with the queue.
+#[derive(Debug)]
+pub struct UsedRing<M: GuestAddressSpace> {
+    mem: M,
+    state: UsedRingState,
+    // The number of used elements added since the split.
+    completed: u16,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<QueueMetrics>,
+}
+
+impl<M: GuestAddressSpace> UsedRing<M> {
+    /// Returns the index for the next element to be added to the used ring.
+    pub fn next_used(&self) -> u16 {
+        self.state.next_used.0
+    }
+
+    /// Puts an available descriptor head into the used ring for use by the guest.
+    ///
+    /// On success, returns the new value of the `idx` field of the used ring, like
+    /// `Queue::add_used`.
+    pub fn add_used(&mut self, head_index: u16, len: u32) -> Result<u16, Error> {
+        self.add_used_batch(&[(head_index, len)])
+            .map(|_| self.state.next_used.0)
+    }
+
+    /// Puts multiple available descriptor heads into the used ring for use by the guest.
+    ///
+    /// Like `Queue::add_used_batch`, the `idx` field of the used ring is only updated once,
+    /// and nothing is written if one of the head indices is out of bounds.
+    pub fn add_used_batch(&mut self, elems: &[(u16, u32)]) -> Result<(), Error> {
+        self.state.add_used_elems(&*self.mem.memory(), elems)?;
+        self.completed = self.completed.saturating_add(elems.len() as u16);
+        #[cfg(feature = "metrics")]
+        QueueMetrics::add(&self.metrics.used_elements, elems.len() as u64);
+        Ok(())
+    }
+
+    /// Check whether a notification to the guest is needed.
+    ///
+    /// This has the same side effects as `Queue::needs_notification`.
+    pub fn needs_notification(&mut self) -> Result<bool, Error> {
+        let notification = self.state.needs_notification(&*self.mem.memory())?;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_notification(notification);
+        Ok(notification == Notification::Needed)
+    }
+}
+
+/// Parse the descriptor chain headed by descriptor `head` of the descriptor table at
+/// `desc_table`, and return copies of its descriptors, with any indirect table resolved.
+///
+/// This is the entry point for fuzz targets, which fill guest memory with arbitrary bytes
+/// and drive the same checks `DescriptorChain` applies to the chains returned by
+/// `Queue::iter`. `Error::InvalidDescriptorIndex` is returned if `head` is not smaller than
+/// `queue_size`, and the first error encountered while walking the chain is returned
+/// otherwise.
+#[cfg(feature = "fuzzing")]
+pub fn parse_descriptor_chain(
+    mem: &vm_memory::GuestMemoryMmap,
+    desc_table: GuestAddress,
+    queue_size: u16,
+    head: u16,
+) -> Result<Vec<Descriptor>, Error> {
+    if head >= queue_size {
+        error!("Descriptor chain head {} is out of bounds", head);
+        return Err(Error::InvalidDescriptorIndex);
+    }
+    DescriptorChain::<&vm_memory::GuestMemoryMmap>::new(mem, desc_table, queue_size, head)
+        .collect_descriptors()
+}
+
+#[allow(missing_docs)]
+#[cfg(feature = "test-utils")]
+pub mod test_utils {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::marker::PhantomData;
+    use std::mem;
+
+    use vm_memory::{
+        GuestAddress, GuestMemoryMmap, GuestMemoryRegion, GuestUsize, VolatileMemory, VolatileRef,
+        VolatileSlice,
+    };
+
+    // Represents a virtio descriptor in guest memory.
+    pub struct VirtqDesc<'a> {
+        desc: VolatileSlice<'a>,
+    }
+
+    /// Extracts the displacement of a field in a struct
+    #[macro_export]
+    macro_rules! offset_of {
+        ($ty:ty, $field:ident) => {
+            unsafe { &(*std::ptr::null::<$ty>()).$field as *const _ as usize }
+        };
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    impl<'a> VirtqDesc<'a> {
+        pub fn new(dtable: &'a VolatileSlice<'a>, i: u16) -> Self {
+            let desc = dtable
+                .get_slice((i as usize) * Self::dtable_len(1), Self::dtable_len(1))
+                .unwrap();
+            VirtqDesc { desc }
+        }
+
+        pub fn addr(&self) -> VolatileRef<u64> {
+            self.desc.get_ref(offset_of!(Descriptor, addr)).unwrap()
+        }
+
+        pub fn len(&self) -> VolatileRef<u32> {
+            self.desc.get_ref(offset_of!(Descriptor, len)).unwrap()
+        }
+
+        pub fn flags(&self) -> VolatileRef<u16> {
+            self.desc.get_ref(offset_of!(Descriptor, flags)).unwrap()
+        }
+
+        pub fn next(&self) -> VolatileRef<u16> {
+            self.desc.get_ref(offset_of!(Descriptor, next)).unwrap()
+        }
+
+        pub fn set(&self, addr: u64, len: u32, flags: u16, next: u16) {
+            self.addr().store(addr);
+            self.len().store(len);
+            self.flags().store(flags);
+            self.next().store(next);
+        }
+
+        pub fn dtable_len(nelem: u16) -> usize {
+            16 * nelem as usize
+        }
+    }
+
+    // Represents a virtio queue ring. The only difference between the used and available rings,
+    // is the ring element type.
+    pub struct VirtqRing<'a, T> {
+        ring: VolatileSlice<'a>,
+        start: GuestAddress,
+        qsize: u16,
+        _marker: PhantomData<*const T>,
+    }
+
+    impl<'a, T> VirtqRing<'a, T>
+    where
+        T: vm_memory::ByteValued,
+    {
+        fn new(
+            start: GuestAddress,
+            mem: &'a GuestMemoryMmap,
+            qsize: u16,
+            alignment: GuestUsize,
+        ) -> Self {
+            assert_eq!(start.0 & (alignment - 1), 0);
+
+            let (region, addr) = mem.to_region_addr(start).unwrap();
+            let size = Self::ring_len(qsize);
+            let ring = region.get_slice(addr, size).unwrap();
+
+            let result = VirtqRing {
+                ring,
+                start,
+                qsize,
+                _marker: PhantomData,
+            };
+
+            result.flags().store(0);
+            result.idx().store(0);
+            result.event().store(0);
+            result
+        }
+
+        pub fn start(&self) -> GuestAddress {
+            self.start
+        }
+
+        pub fn end(&self) -> GuestAddress {
+            self.start.unchecked_add(self.ring.len() as GuestUsize)
+        }
+
+        pub fn flags(&self) -> VolatileRef<u16> {
+            self.ring.get_ref(0).unwrap()
+        }
+
+        pub fn idx(&self) -> VolatileRef<u16> {
+            self.ring.get_ref(2).unwrap()
+        }
+
+        fn ring_offset(i: u16) -> usize {
+            4 + mem::size_of::<T>() * (i as usize)
+        }
+
+        pub fn ring(&self, i: u16) -> VolatileRef<T> {
+            assert!(i < self.qsize);
+            self.ring.get_ref(Self::ring_offset(i)).unwrap()
+        }
+
+        pub fn event(&self) -> VolatileRef<u16> {
+            self.ring.get_ref(Self::ring_offset(self.qsize)).unwrap()
+        }
+
+        fn ring_len(qsize: u16) -> usize {
+            Self::ring_offset(qsize) + 2
+        }
+    }
+
+    pub type VirtqAvail<'a> = VirtqRing<'a, u16>;
+    pub type VirtqUsed<'a> = VirtqRing<'a, VirtqUsedElem>;
+
+    trait GuestAddressExt {
+        fn align_up(&self, x: GuestUsize) -> GuestAddress;
+    }
+    impl GuestAddressExt for GuestAddress {
+        fn align_up(&self, x: GuestUsize) -> GuestAddress {
+            Self((self.0 + (x - 1)) & !(x - 1))
+        }
+    }
+
+    pub struct VirtQueue<'a> {
+        start: GuestAddress,
+        end: GuestAddress,
+        dtable: VolatileSlice<'a>,
+        pub avail: VirtqAvail<'a>,
+        pub used: VirtqUsed<'a>,
+        mem: &'a GuestMemoryMmap,
+        // State used by `build_chain` to hand out descriptors and buffers.
+        next_free_desc: Cell<u16>,
+        next_free_addr: Cell<GuestAddress>,
+    }
+
+    // Builds a `VirtQueue` with a custom memory layout.
+    //
+    // By default, the descriptor table starts at `GuestAddress(0)`, and each ring is placed at
+    // the first properly aligned address following the previous structure and the requested
+    // padding (0 bytes by default). Ring addresses that are set explicitly are used as they
+    // are, so tests can build misaligned or overlapping layouts, e.g.:
+    //
+    //     let vq = VirtQueueBuilder::new(16)
+    //         .used_ring(GuestAddress(0x80))
+    //         .build(m);
+    //
+    // places the used ring inside the descriptor table.
+    pub struct VirtQueueBuilder {
+        qsize: u16,
+        desc_table: GuestAddress,
+        avail_ring: Option<GuestAddress>,
+        used_ring: Option<GuestAddress>,
+        avail_padding: GuestUsize,
+        used_padding: GuestUsize,
+    }
+
+    impl VirtQueueBuilder {
+        pub fn new(qsize: u16) -> Self {
+            VirtQueueBuilder {
+                qsize,
+                desc_table: GuestAddress(0),
+                avail_ring: None,
+                used_ring: None,
+                avail_padding: 0,
+                used_padding: 0,
+            }
+        }
+
+        pub fn desc_table(mut self, addr: GuestAddress) -> Self {
+            self.desc_table = addr;
+            self
+        }
+
+        pub fn avail_ring(mut self, addr: GuestAddress) -> Self {
+            self.avail_ring = Some(addr);
+            self
+        }
+
+        pub fn used_ring(mut self, addr: GuestAddress) -> Self {
+            self.used_ring = Some(addr);
+            self
+        }
+
+        // Number of bytes left between the end of the descriptor table and the available ring,
+        // when the address of the latter is not set explicitly.
+        pub fn avail_padding(mut self, padding: GuestUsize) -> Self {
+            self.avail_padding = padding;
+            self
+        }
+
+        // Number of bytes left between the end of the available ring and the used ring, when
+        // the address of the latter is not set explicitly.
+        pub fn used_padding(mut self, padding: GuestUsize) -> Self {
+            self.used_padding = padding;
+            self
+        }
+
+        pub fn build(self, mem: &GuestMemoryMmap) -> VirtQueue<'_> {
+            // power of 2?
+            let qsize = self.qsize;
+            assert!(qsize > 0 && qsize & (qsize - 1) == 0);
+
+            let start = self.desc_table;
+            let (region, addr) = mem.to_region_addr(start).unwrap();
+            let dtable = region
+                .get_slice(addr, VirtqDesc::dtable_len(qsize))
+                .unwrap();
+
+            const AVAIL_ALIGN: GuestUsize = 2;
+
+            let avail = match self.avail_ring {
+                Some(avail_addr) => VirtqAvail::new(avail_addr, mem, qsize, 1),
+                None => {
+                    let avail_addr = start
+                        .unchecked_add(VirtqDesc::dtable_len(qsize) as GuestUsize)
+                        .unchecked_add(self.avail_padding)
+                        .align_up(AVAIL_ALIGN);
+                    VirtqAvail::new(avail_addr, mem, qsize, AVAIL_ALIGN)
+                }
+            };
+
+            const USED_ALIGN: GuestUsize = 4;
+
+            let used = match self.used_ring {
+                Some(used_addr) => VirtqUsed::new(used_addr, mem, qsize, 1),
+                None => {
+                    let used_addr = avail
+                        .end()
+                        .unchecked_add(self.used_padding)
+                        .align_up(USED_ALIGN);
+                    VirtqUsed::new(used_addr, mem, qsize, USED_ALIGN)
+                }
+            };
+
+            let end = start
+                .unchecked_add(dtable.len() as GuestUsize)
+                .max(avail.end())
+                .max(used.end());
+            // Buffers allocated by `build_chain` start at the first page after the queue.
+            let next_free_addr = end.align_up(0x1000);
+
+            VirtQueue {
+                start,
+                end,
+                dtable,
+                avail,
+                used,
+                mem,
+                next_free_desc: Cell::new(0),
+                next_free_addr: Cell::new(next_free_addr),
+            }
+        }
+    }
+
+    impl<'a> VirtQueue<'a> {
+        // We try to make sure things are aligned properly :-s
+        pub fn new(start: GuestAddress, mem: &'a GuestMemoryMmap, qsize: u16) -> Self {
+            VirtQueueBuilder::new(qsize).desc_table(start).build(mem)
+        }
+
+        pub fn size(&self) -> u16 {
+            (self.dtable.len() / VirtqDesc::dtable_len(1)) as u16
+        }
+
+        pub fn dtable(&self, i: u16) -> VirtqDesc {
+            VirtqDesc::new(&self.dtable, i)
+        }
+
+        pub fn dtable_start(&self) -> GuestAddress {
+            self.start
+        }
+
+        pub fn avail_start(&self) -> GuestAddress {
+            self.avail.start()
+        }
+
+        pub fn used_start(&self) -> GuestAddress {
+            self.used.start()
+        }
+
+        // Creates a new Queue, using the underlying memory regions represented by the VirtQueue.
+        pub fn create_queue(&self, mem: &'a GuestMemoryMmap) -> Queue<&'a GuestMemoryMmap> {
+            let mut q = Queue::new(mem, self.size());
+
+            q.size = self.size();
+            q.ready = true;
+            q.desc_table = self.dtable_start();
+            q.avail_ring = self.avail_start();
+            q.used_ring = self.used_start();
+
+            q
+        }
+
+        pub fn start(&self) -> GuestAddress {
+            self.dtable_start()
+        }
+
+        pub fn end(&self) -> GuestAddress {
+            self.end
+        }
+
+        // Reserve a guest memory area of `len` bytes after the queue.
+        fn alloc(&self, len: u32) -> GuestAddress {
+            let addr = self.next_free_addr.get();
+            self.next_free_addr
+                .set(addr.unchecked_add(u64::from(len)).align_up(16));
+            addr
+        }
+    }
+
+    // High-level description of a descriptor used by `build_chain`.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct ChainDescSpec {
+        // Length of the buffer pointed to by the descriptor.
+        pub len: u32,
+        // Whether the buffer is device write-only.
+        pub writable: bool,
+        // Whether the descriptor is placed in an indirect table instead of the queue
+        // descriptor table.
+        pub indirect: bool,
+    }
+
+    // Writes a descriptor chain described by `specs` to the queue memory, makes it available
+    // (by placing its head in the available ring and incrementing the available ring `idx`),
+    // and returns the head index.
+    //
+    // The descriptors are allocated in the descriptor table after the ones used by previous
+    // calls, and the buffers they point to are allocated in guest memory after the queue. All
+    // the descriptors with `indirect` set are placed in a single indirect table (in order),
+    // which is referenced by the last descriptor of the chain in the descriptor table, so they
+    // have to come after the direct descriptors in `specs`.
+    pub fn build_chain(vq: &VirtQueue, specs: &[ChainDescSpec]) -> u16 {
+        let num_direct = specs.iter().take_while(|spec| !spec.indirect).count();
+        let (direct, indirect) = specs.split_at(num_direct);
+        assert!(
+            indirect.iter().all(|spec| spec.indirect),
+            "indirect descriptors must come after the direct ones"
+        );
+
+        let flags = |spec: &ChainDescSpec, has_next: bool| {
+            let mut flags = 0;
+            if spec.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if has_next {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            flags
+        };
+
+        let num_table_descs = direct.len() + if indirect.is_empty() { 0 } else { 1 };
+        let head = vq.next_free_desc.get();
+        assert!(
+            num_table_descs > 0 && usize::from(head) + num_table_descs <= usize::from(vq.size()),
+            "not enough free descriptors"
+        );
+        vq.next_free_desc.set(head + num_table_descs as u16);
+
+        for (i, spec) in direct.iter().enumerate() {
+            let index = head + i as u16;
+            let has_next = i + 1 < num_table_descs;
+            let addr = vq.alloc(spec.len);
+            vq.dtable(index)
+                .set(addr.0, spec.len, flags(spec, has_next), index + 1);
+        }
+
+        if !indirect.is_empty() {
+            let table_len = (indirect.len() * mem::size_of::<Descriptor>()) as u32;
+            let table_addr = vq.alloc(table_len);
+            for (i, spec) in indirect.iter().enumerate() {
+                let has_next = i + 1 < indirect.len();
+                let addr = vq.alloc(spec.len);
+                let desc = Descriptor::new(addr.0, spec.len, flags(spec, has_next), i as u16 + 1);
+                vq.mem
+                    .write_obj(
+                        desc,
+                        table_addr.unchecked_add((i * mem::size_of::<Descriptor>()) as u64),
+                    )
+                    .unwrap();
+            }
+            vq.dtable(head + direct.len() as u16).set(
+                table_addr.0,
+                table_len,
+                VIRTQ_DESC_F_INDIRECT,
+                0,
+            );
+        }
+
+        let avail_idx = vq.avail.idx().load();
+        vq.avail.ring(avail_idx % vq.size()).store(head);
+        vq.avail.idx().store(avail_idx.wrapping_add(1));
+
+        head
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_utils::*;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress};
+
+    #[test]
+    pub fn test_offset() {
+        assert_eq!(offset_of!(Descriptor, addr), 0);
+        assert_eq!(offset_of!(Descriptor, len), 8);
+        assert_eq!(offset_of!(Descriptor, flags), 12);
+        assert_eq!(offset_of!(Descriptor, next), 14);
+    }
+
+    #[test]
+    fn test_descriptor_subslice() {
+        let desc = Descriptor::new(0x1000, 0x100, 0, 0);
+        assert_eq!(desc.addr_range(), (GuestAddress(0x1000), 0x100));
+
+        // In bounds.
+        assert_eq!(
+            desc.subslice(0, 0x10).unwrap(),
+            (GuestAddress(0x1000), 0x10)
+        );
+        assert_eq!(
+            desc.subslice(0x10, 0x20).unwrap(),
+            (GuestAddress(0x1010), 0x20)
+        );
+        // The length is clamped to the end of the buffer.
+        assert_eq!(
+            desc.subslice(0x10, u32::MAX).unwrap(),
+            (GuestAddress(0x1010), 0xf0)
+        );
+
+        // Exactly at the boundary.
+        assert_eq!(
+            desc.subslice(0, 0x100).unwrap(),
+            (GuestAddress(0x1000), 0x100)
+        );
+        assert_eq!(
+            desc.subslice(0xff, 0x10).unwrap(),
+            (GuestAddress(0x10ff), 1)
+        );
+        assert_eq!(
+            desc.subslice(0x100, 0x10).unwrap(),
+            (GuestAddress(0x1100), 0)
+        );
+
+        // Out of bounds.
+        match desc.subslice(0x101, 0) {
+            Err(Error::DescriptorOutOfBounds) => (),
+            _ => panic!("expected a descriptor out of bounds error"),
+        }
+        let desc = Descriptor::new(u64::MAX - 0xf, 0x100, 0, 0);
+        assert_eq!(
+            desc.subslice(0, 0x10).unwrap(),
+            (GuestAddress(u64::MAX - 0xf), 0x10)
+        );
+        match desc.subslice(0x8, 0x10) {
+            Err(Error::DescriptorOutOfBounds) => (),
+            _ => panic!("expected a descriptor out of bounds error"),
+        }
+    }
+
+    #[cfg(feature = "fuzzing")]
+    #[test]
+    fn test_parse_descriptor_chain_corpus() {
+        fn desc(addr: u64, len: u32, flags: u16, next: u16) -> Vec<u8> {
+            let mut raw = addr.to_le_bytes().to_vec();
+            raw.extend_from_slice(&len.to_le_bytes());
+            raw.extend_from_slice(&flags.to_le_bytes());
+            raw.extend_from_slice(&next.to_le_bytes());
+            raw
+        }
+
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let table = GuestAddress(0x1000);
+        // Each seed is the raw content of a descriptor table with 4 entries, the index of the
+        // chain head, and the expected number of descriptors (or `None` for an error).
+        let corpus: Vec<(Vec<u8>, u16, Option<usize>)> = vec![
+            // A single descriptor.
+            (desc(0x2000, 0x100, 0, 0), 0, Some(1)),
+            // A readable and a writable descriptor.
+            (
+                [
+                    desc(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 1),
+                    desc(0x3000, 0x100, VIRTQ_DESC_F_WRITE, 0),
+                ]
+                .concat(),
+                0,
+                Some(2),
+            ),
+            // An indirect table with 2 descriptors, stored in the first 2 entries.
+            (
+                [
+                    desc(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 1),
+                    desc(0x3000, 0x100, 0, 0),
+                    desc(table.raw_value(), 0x20, VIRTQ_DESC_F_INDIRECT, 0),
+                ]
+                .concat(),
+                2,
+                Some(2),
+            ),
+            // A loop between two descriptors.
+            (
+                [
+                    desc(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 1),
+                    desc(0x3000, 0x100, VIRTQ_DESC_F_NEXT, 0),
+                ]
+                .concat(),
+                0,
+                None,
+            ),
+            // A `next` index out of bounds.
+            (desc(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 4), 0, None),
+            // A head out of bounds.
+            (desc(0x2000, 0x100, 0, 0), 4, None),
+        ];
+
+        for (raw, head, expected) in corpus {
+            m.write_slice(&[0u8; 4 * VIRTQ_DESCRIPTOR_SIZE], table)
+                .unwrap();
+            m.write_slice(&raw, table).unwrap();
+            let result = parse_descriptor_chain(m, table, 4, head);
+            assert_eq!(result.ok().map(|descs| descs.len()), expected);
+        }
+
+        match parse_descriptor_chain(m, table, 4, 4) {
+            Err(Error::InvalidDescriptorIndex) => (),
+            _ => panic!("expected an invalid descriptor index error"),
+        }
+    }
+
+    #[test]
+    fn test_little_endian_layout() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+
+        // A descriptor as laid out in guest memory, with the least significant bytes first.
+        let raw = [
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, // addr
+            0x04, 0x03, 0x02, 0x01, // len
+            0x03, 0x00, // flags
+            0x02, 0x01, // next
+        ];
+        m.write_slice(&raw, GuestAddress(0x100)).unwrap();
+        let desc: Descriptor = m.read_obj(GuestAddress(0x100)).unwrap();
+        assert_eq!(desc.addr(), GuestAddress(0x0102_0304_0506_0708));
+        assert_eq!(desc.len(), 0x0102_0304);
+        assert_eq!(desc.flags(), VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        assert_eq!(desc.next(), 0x0102);
+        assert_eq!(
+            desc,
+            Descriptor::new(
+                0x0102_0304_0506_0708,
+                0x0102_0304,
+                VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+                0x0102
+            )
+        );
+
+        // Used elements are written the same way.
+        m.write_obj(VirtqUsedElem::new(0x0102, 0x0304_0506), GuestAddress(0x200))
+            .unwrap();
+        let mut raw = [0u8; 8];
+        m.read_slice(&mut raw, GuestAddress(0x200)).unwrap();
+        assert_eq!(raw, [0x02, 0x01, 0x00, 0x00, 0x06, 0x05, 0x04, 0x03]);
+    }
+
+    #[test]
+    fn test_checked_new_descriptor_chain() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        assert!(vq.end().0 < 0x1000);
+
+        // index >= queue_size
+        assert!(
+            DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 16)
+                .next()
+                .is_none()
+        );
+
+        // desc_table address is way off
+        assert!(
+            DescriptorChain::<&GuestMemoryMmap>::new(m, GuestAddress(0x00ff_ffff_ffff), 16, 0)
+                .next()
+                .is_none()
+        );
+
+        {
+            // the first desc has a normal len, and the next_descriptor flag is set
+            vq.dtable(0).addr().store(0x1000);
+            vq.dtable(0).len().store(0x1000);
+            vq.dtable(0).flags().store(VIRTQ_DESC_F_NEXT);
+            //..but the the index of the next descriptor is too large
+            vq.dtable(0).next().store(16);
+
+            let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+            c.next().unwrap();
+            assert!(c.next().is_none());
+        }
+
+        // finally, let's test an ok chain
+        {
+            vq.dtable(0).next().store(1);
+            vq.dtable(1).set(0x2000, 0x1000, 0, 0);
+
+            let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+
+            assert_eq!(
+                c.memory() as *const GuestMemoryMmap,
+                m as *const GuestMemoryMmap
+            );
+            assert_eq!(c.desc_table, vq.dtable_start());
+            assert_eq!(c.queue_size, 16);
+            assert_eq!(c.ttl, c.queue_size);
+            let desc = c.next().unwrap();
+            assert_eq!(desc.addr(), GuestAddress(0x1000));
+            assert_eq!(desc.len(), 0x1000);
+            assert_eq!(desc.flags(), VIRTQ_DESC_F_NEXT);
+            assert_eq!(desc.next(), 1);
+
+            assert!(c.next().is_some());
+            assert!(c.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_writes_only_to_used_ring() {
+        use std::io::{Read, Write};
+        use std::sync::Arc;
+        use vm_memory::guest_memory::GuestMemoryIterator;
+        use vm_memory::{AtomicAccess, GuestRegionMmap, MmapRegion};
+
+        // A region that panics on writes outside of the allowed range of guest addresses.
+        struct GuardedRegion {
+            inner: Arc<GuestRegionMmap>,
+            writable: (GuestAddress, u64),
+        }
+
+        impl GuardedRegion {
+            fn check_write(&self, addr: MemoryRegionAddress, len: usize) {
+                let start = self.inner.start_addr().unchecked_add(addr.raw_value());
+                let (allowed, allowed_len) = self.writable;
+                assert!(
+                    start >= allowed
+                        && start.unchecked_add(len as u64) <= allowed.unchecked_add(allowed_len),
+                    "write of {} bytes at {:#x}",
+                    len,
+                    start.raw_value()
+                );
+            }
+        }
+
+        impl Bytes<MemoryRegionAddress> for GuardedRegion {
+            type E = GuestMemoryError;
+
+            fn write(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<usize, Self::E> {
+                self.check_write(addr, buf.len());
+                self.inner.write(buf, addr)
+            }
+
+            fn read(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<usize, Self::E> {
+                self.inner.read(buf, addr)
+            }
+
+            fn write_slice(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<(), Self::E> {
+                self.check_write(addr, buf.len());
+                self.inner.write_slice(buf, addr)
+            }
+
+            fn read_slice(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<(), Self::E> {
+                self.inner.read_slice(buf, addr)
+            }
+
+            fn read_from<F: Read>(
+                &self,
+                addr: MemoryRegionAddress,
+                src: &mut F,
+                count: usize,
+            ) -> Result<usize, Self::E> {
+                self.check_write(addr, count);
+                self.inner.read_from(addr, src, count)
+            }
+
+            fn read_exact_from<F: Read>(
+                &self,
+                addr: MemoryRegionAddress,
+                src: &mut F,
+                count: usize,
+            ) -> Result<(), Self::E> {
+                self.check_write(addr, count);
+                self.inner.read_exact_from(addr, src, count)
+            }
+
+            fn write_to<F: Write>(
+                &self,
+                addr: MemoryRegionAddress,
+                dst: &mut F,
+                count: usize,
+            ) -> Result<usize, Self::E> {
+                self.inner.write_to(addr, dst, count)
+            }
+
+            fn write_all_to<F: Write>(
+                &self,
+                addr: MemoryRegionAddress,
+                dst: &mut F,
+                count: usize,
+            ) -> Result<(), Self::E> {
+                self.inner.write_all_to(addr, dst, count)
+            }
+
+            fn store<T: AtomicAccess>(
+                &self,
+                val: T,
+                addr: MemoryRegionAddress,
+                order: Ordering,
+            ) -> Result<(), Self::E> {
+                self.check_write(addr, size_of::<T>());
+                self.inner.store(val, addr, order)
+            }
+
+            fn load<T: AtomicAccess>(
+                &self,
+                addr: MemoryRegionAddress,
+                order: Ordering,
+            ) -> Result<T, Self::E> {
+                self.inner.load(addr, order)
+            }
+        }
+
+        impl GuestMemoryRegion for GuardedRegion {
+            fn len(&self) -> u64 {
+                self.inner.len()
+            }
+
+            fn start_addr(&self) -> GuestAddress {
+                self.inner.start_addr()
+            }
+        }
+
+        struct GuardedMemory {
+            regions: Vec<GuardedRegion>,
+        }
+
+        impl<'a> GuestMemoryIterator<'a, GuardedRegion> for GuardedMemory {
+            type Iter = std::slice::Iter<'a, GuardedRegion>;
+        }
+
+        impl GuestMemory for GuardedMemory {
+            type R = GuardedRegion;
+            type I = Self;
+
+            fn num_regions(&self) -> usize {
+                self.regions.len()
+            }
+
+            fn find_region(&self, addr: GuestAddress) -> Option<&GuardedRegion> {
+                self.regions
+                    .iter()
+                    .find(|r| addr >= r.start_addr() && addr <= r.last_addr())
+            }
+
+            fn iter(&self) -> std::slice::Iter<'_, GuardedRegion> {
+                self.regions.iter()
+            }
+        }
+
+        let region = Arc::new(
+            GuestRegionMmap::new(MmapRegion::new(0x10000).unwrap(), GuestAddress(0)).unwrap(),
+        );
+        let m = &GuestMemoryMmap::from_arc_regions(vec![region.clone()]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let used_ring_len = 6 + 16 * 8;
+        let guarded = GuardedMemory {
+            regions: vec![GuardedRegion {
+                inner: region,
+                writable: (vq.used_start(), used_ring_len),
+            }],
+        };
+
+        // The guard catches writes outside of the used ring.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            guarded.write_obj(0u16, vq.avail_start())
+        }));
+        assert!(result.is_err());
+
+        let mut q = Queue::new(&guarded, 16);
+        q.size = 16;
+        q.ready = true;
+        q.desc_table = vq.start();
+        q.avail_ring = vq.avail_start();
+        q.used_ring = vq.used_start();
+        q.set_event_idx(true);
+        q.set_used_overflow_check(true);
+        assert!(q.is_valid());
+
+        let spec = ChainDescSpec {
+            len: 0x100,
+            writable: false,
+            indirect: true,
+        };
+        build_chain(&vq, &[ChainDescSpec::default(); 2]);
+        build_chain(&vq, &[spec; 3]);
+
+        let chains: Vec<(u16, usize)> = q
+            .iter()
+            .unwrap()
+            .map(|chain| (chain.head_index(), chain.count()))
+            .collect();
+        assert_eq!(chains, [(0, 2), (2, 3)]);
+        for &(head, _) in chains.iter() {
+            q.add_used(head, 0x10).unwrap();
+        }
+        q.needs_notification().unwrap();
+        q.enable_notification().unwrap();
+        q.disable_notification().unwrap();
+        assert_eq!(vq.used.idx().load(), 2);
+    }
+
+    #[cfg(all(feature = "backend-mmap", unix))]
+    #[test]
+    fn test_permission_checked() {
+        use vm_memory::{GuestRegionMmap, MmapRegion};
+
+        // The queue lives in the first region, while the second one is read-only.
+        let rw = MmapRegion::new(0x10000).unwrap();
+        let ro = MmapRegion::build(
+            None,
+            0x1000,
+            libc::PROT_READ,
+            libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_PRIVATE,
+        )
+        .unwrap();
+        let m = &GuestMemoryMmap::from_regions(vec![
+            GuestRegionMmap::new(rw, GuestAddress(0)).unwrap(),
+            GuestRegionMmap::new(ro, GuestAddress(0x10000)).unwrap(),
+        ])
+        .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // Readable descriptors may point anywhere, and writable ones to the first region.
+        vq.dtable(0).set(0x10000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x8000, 0x1000, VIRTQ_DESC_F_NEXT, 2);
+        // A writable buffer that starts in the first region and ends in the read-only one.
+        vq.dtable(2).set(0xf800, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+
+        let mut c =
+            DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0).permission_checked();
+        assert!(c.next_descriptor().unwrap().is_ok());
+        assert!(c.next_descriptor().unwrap().is_ok());
+        match c.next_descriptor() {
+            Some(Err(Error::InvalidChain)) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        assert!(c.next_descriptor().is_none());
+
+        // Unmapped buffers are rejected as well.
+        vq.dtable(2).set(0x20000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert!(c.permission_checked().nth(2).unwrap().is_err());
+
+        // The default iterator doesn't look at the permissions.
+        vq.dtable(2).set(0x10000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert_eq!(c.count(), 3);
+    }
+
+    #[test]
+    fn test_descriptors_consumed() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let spec = |len, writable, indirect| ChainDescSpec {
+            len,
+            writable,
+            indirect,
+        };
+        // The chain switches to an indirect table with 2 descriptors after the first one.
+        build_chain(
+            &vq,
+            &[
+                spec(0x40, false, false),
+                spec(0x50, false, true),
+                spec(0x60, true, true),
+            ],
+        );
+
+        let mut chain = q.iter().unwrap().next().unwrap();
+        assert_eq!(chain.descriptors_consumed(), 0);
+        assert_eq!(chain.remaining_ttl(), 16);
+        let expected = [(0x40, 15), (0x50, 1), (0x60, 0)];
+        for (i, &(len, ttl)) in expected.iter().enumerate() {
+            assert_eq!(chain.next().unwrap().len(), len);
+            assert_eq!(usize::from(chain.descriptors_consumed()), i + 1);
+            assert_eq!(chain.remaining_ttl(), ttl);
+        }
+        assert!(chain.next().is_none());
+        assert_eq!(chain.descriptors_consumed(), 3);
+
+        // Clones keep the count, and cursors created from the head start over.
+        assert_eq!(chain.clone().descriptors_consumed(), 3);
+        let chain = chain.clone_from_head();
+        assert_eq!(chain.descriptors_consumed(), 0);
+        assert_eq!(chain.remaining_ttl(), 16);
+
+        // Failing to return a descriptor doesn't count.
+        vq.dtable(0).set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 16);
+        let mut chain = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert!(chain.next_descriptor().unwrap().is_ok());
+        assert!(chain.next_descriptor().unwrap().is_err());
+        assert_eq!(chain.descriptors_consumed(), 1);
+        assert_eq!(chain.remaining_ttl(), 0);
+    }
+
+    #[test]
+    fn test_clone_from_head() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let spec = |len, writable, indirect| ChainDescSpec {
+            len,
+            writable,
+            indirect,
+        };
+        build_chain(
+            &vq,
+            &[
+                spec(0x10, false, false),
+                spec(0x20, false, false),
+                spec(0x30, true, false),
+            ],
+        );
+        // The second chain switches to an indirect table halfway through.
+        build_chain(
+            &vq,
+            &[
+                spec(0x40, false, false),
+                spec(0x50, false, true),
+                spec(0x60, true, true),
+            ],
+        );
+
+        for mut chain in q.iter().unwrap() {
+            // Consume the header descriptor.
+            chain.next().unwrap();
+            let rest: Vec<_> = chain.clone().collect();
+
+            let first: Vec<_> = chain.clone_from_head().collect();
+            // Exhaust the chain itself, which doesn't affect new cursors.
+            assert_eq!(chain.by_ref().collect::<Vec<_>>(), rest);
+            let second: Vec<_> = chain.clone_from_head().collect();
+
+            assert_eq!(first.len(), 3);
+            assert_eq!(first, second);
+            assert_eq!(&first[1..], &rest[..]);
+            assert_eq!(chain.clone_from_head().head_index(), chain.head_index());
+        }
+    }
+
+    #[test]
+    fn test_directional() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let flags = [
+            VIRTQ_DESC_F_WRITE,
+            0,
+            0,
+            VIRTQ_DESC_F_WRITE,
+            0,
+            VIRTQ_DESC_F_WRITE,
+        ];
+        for (i, f) in flags.iter().enumerate() {
+            let i = i as u16;
+            let next_flag = if usize::from(i) < flags.len() - 1 {
+                VIRTQ_DESC_F_NEXT
+            } else {
+                0
+            };
+            vq.dtable(i)
+                .set(0x1000 * u64::from(i + 1), 0x100, f | next_flag, i + 1);
+        }
+
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        let tagged: Vec<_> = c.directional().collect();
+        assert_eq!(tagged.len(), flags.len());
+        for (i, (direction, desc)) in tagged.iter().enumerate() {
+            // The order of the chain is preserved.
+            assert_eq!(desc.addr(), GuestAddress(0x1000 * (i as u64 + 1)));
+            let expected = if flags[i] & VIRTQ_DESC_F_WRITE != 0 {
+                Direction::Writable
+            } else {
+                Direction::Readable
+            };
+            assert_eq!(*direction, expected);
+        }
+    }
+
+    #[test]
+    fn test_skip_to_writable() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // Two readable descriptors, followed by a writable one with a `next` index that's out
+        // of bounds, so reading past it fails.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2)
+            .set(0x3000, 0x100, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 16);
+
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        let mut readable = c.clone().readable();
+        assert!(readable.next_descriptor().unwrap().is_ok());
+        assert!(readable.next_descriptor().unwrap().is_ok());
+        match readable.next_descriptor() {
+            Some(Err(Error::InvalidDescriptorIndex)) => (),
+            _ => panic!("expected an invalid descriptor index error"),
+        }
+
+        // The fast path stops at the writable descriptor.
+        let mut readable = c.clone().readable().skip_to_writable();
+        assert_eq!(
+            readable.next_descriptor().unwrap().unwrap().addr().0,
+            0x1000
+        );
+        assert_eq!(
+            readable.next_descriptor().unwrap().unwrap().addr().0,
+            0x2000
+        );
+        assert!(readable.next_descriptor().is_none());
+        assert!(readable.next_descriptor().is_none());
+        assert_eq!(c.clone().readable().skip_to_writable().count(), 2);
+
+        // Writable iterators are not affected.
+        let mut writable = c.writable().skip_to_writable();
+        assert_eq!(
+            writable.next_descriptor().unwrap().unwrap().addr().0,
+            0x3000
+        );
+        assert!(writable.next_descriptor().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_has_overlapping_buffers() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let chain = || DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+
+        // Disjoint (but adjacent) buffers.
+        vq.dtable(0).set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2000, 0x1000, 0, 0);
+        assert!(!chain().has_overlapping_buffers().unwrap());
+
+        // The second buffer starts within the first one.
+        vq.dtable(1).set(0x1fff, 0x1000, 0, 0);
+        assert!(chain().has_overlapping_buffers().unwrap());
+
+        // The position of the chain doesn't matter, and isn't changed.
+        let mut c = chain();
+        c.next().unwrap();
+        assert!(c.has_overlapping_buffers().unwrap());
+        assert_eq!(c.count(), 1);
+
+        // Empty buffers don't overlap anything, and the ends of buffers don't overflow.
+        vq.dtable(1).set(0x1800, 0, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(u64::MAX, 0x1000, 0, 0);
+        assert!(!chain().has_overlapping_buffers().unwrap());
+
+        // Errors are reported.
+        vq.dtable(2).set(0x3000, 0x1000, VIRTQ_DESC_F_NEXT, 16);
+        match chain().has_overlapping_buffers() {
+            Err(Error::InvalidDescriptorIndex) => (),
+            _ => panic!("expected an invalid descriptor index error"),
+        }
+    }
+
+    #[test]
+    fn test_cyclic_descriptor_chain() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // A self-referential descriptor.
+        vq.dtable(0).set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 0);
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        for _ in 0..15 {
+            assert!(c.next_descriptor().unwrap().is_ok());
+        }
+        match c.next_descriptor() {
+            Some(Err(Error::InvalidChain)) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        assert!(c.next_descriptor().is_none());
+
+        // The infallible iterator simply stops.
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert_eq!(c.count(), 15);
+
+        // A chain that uses all the descriptors of the table is fine.
+        for j in 0..15 {
+            vq.dtable(j).set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, j + 1);
+        }
+        vq.dtable(15).set(0x1000, 0x1000, 0, 0);
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        for _ in 0..16 {
+            assert!(c.next_descriptor().unwrap().is_ok());
+        }
+        assert!(c.next_descriptor().is_none());
+    }
+
+    #[test]
+    fn test_next_descriptor_errors() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The descriptor table is not backed by guest memory.
+        let mut c =
+            DescriptorChain::<&GuestMemoryMmap>::new(m, GuestAddress(0x00ff_ffff_ffff), 16, 0);
+        match c.next_descriptor() {
+            Some(Err(Error::GuestMemory(_))) => (),
+            _ => panic!("expected a guest memory error"),
+        }
+        assert!(c.next_descriptor().is_none());
+
+        // The second descriptor points to an indirect table that's not backed by guest memory.
+        vq.dtable(0).set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x10_0000, 0x20, VIRTQ_DESC_F_INDIRECT, 0);
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert!(c.next_descriptor().unwrap().is_ok());
+        match c.next_descriptor() {
+            Some(Err(Error::GuestMemory(_))) => (),
+            _ => panic!("expected a guest memory error"),
+        }
+        assert!(c.next_descriptor().is_none());
+
+        // The infallible iterator can't tell the difference with the end of the chain.
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert_eq!(c.count(), 1);
+
+        // The errors are propagated by the readable/writable iterators as well.
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0).writable();
+        match c.next_descriptor() {
+            Some(Err(Error::GuestMemory(_))) => (),
+            _ => panic!("expected a guest memory error"),
+        }
+
+        // An indirect table that contains another indirect descriptor.
+        vq.dtable(1).set(0x8000, 0x10, VIRTQ_DESC_F_INDIRECT, 0);
+        m.write_obj(
+            Descriptor::new(0x9000, 0x10, VIRTQ_DESC_F_INDIRECT, 0),
+            GuestAddress(0x8000),
+        )
+        .unwrap();
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0).readable();
+        assert!(c.next_descriptor().unwrap().is_ok());
+        match c.next_descriptor() {
+            Some(Err(Error::InvalidIndirectDescriptor)) => (),
+            _ => panic!("expected an invalid indirect descriptor error"),
+        }
+        assert!(c.next_descriptor().is_none());
+    }
+
+    #[test]
+    fn test_new_from_indirect_descriptor() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // create a chain with a descriptor pointing to an indirect table
+        let desc = vq.dtable(0);
+        desc.set(0x1000, 0x1000, VIRTQ_DESC_F_INDIRECT, 0);
+
+        let mut c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 0);
+
+        // The chain logic hasn't parsed the indirect descriptor yet.
+        assert!(!c.is_indirect);
+
+        let region = m.find_region(GuestAddress(0)).unwrap();
+        let dtable = region
+            .get_slice(MemoryRegionAddress(0x1000u64), VirtqDesc::dtable_len(4))
+            .unwrap();
+        // create an indirect table with 4 chained descriptors
+        let mut indirect_table = Vec::with_capacity(4_usize);
+        for j in 0..4 {
+            let desc = VirtqDesc::new(&dtable, j);
+            if j < 3 {
+                desc.set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, (j + 1) as u16);
+            } else {
+                desc.set(0x1000, 0x1000, 0, 0_u16);
+            }
+            indirect_table.push(desc);
+        }
+
+        assert_eq!(c.head_index(), 0);
+        // try to iterate through the first indirect descriptor chain
+        for j in 0..4 {
+            let desc = c.next().unwrap();
+            assert!(c.is_indirect);
+            if j < 3 {
+                assert_eq!(desc.flags(), VIRTQ_DESC_F_NEXT);
+                assert_eq!(desc.next(), j + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collect_descriptors() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The chain is (0, 1), with 1 pointing to an indirect table with 2 descriptors.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x8000, 0x20, VIRTQ_DESC_F_INDIRECT, 0);
+        let region = m.find_region(GuestAddress(0)).unwrap();
+        let dtable = region
+            .get_slice(MemoryRegionAddress(0x8000u64), VirtqDesc::dtable_len(2))
+            .unwrap();
+        VirtqDesc::new(&dtable, 0).set(0x2000, 0x200, VIRTQ_DESC_F_NEXT, 1);
+        VirtqDesc::new(&dtable, 1).set(0x3000, 0x300, VIRTQ_DESC_F_WRITE, 0);
+
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        let descriptors = c.collect_descriptors().unwrap();
+        assert_eq!(
+            descriptors,
+            vec![
+                Descriptor::new(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1),
+                Descriptor::new(0x2000, 0x200, VIRTQ_DESC_F_NEXT, 1),
+                Descriptor::new(0x3000, 0x300, VIRTQ_DESC_F_WRITE, 0),
+            ]
+        );
+        // The chain itself is not consumed.
+        assert_eq!(c.count(), 3);
+
+        // Errors are reported instead of truncating the result.
+        vq.dtable(1).set(0x8000, 0x18, VIRTQ_DESC_F_INDIRECT, 0);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        match c.collect_descriptors() {
+            Err(Error::InvalidIndirectDescriptorTable) => (),
+            _ => panic!("expected an invalid indirect descriptor table error"),
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The chain is (0, 1, 2), with 0 being readable and 1, 2 writable.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2000, 0x1000, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable(2).set(0x4000, 0x300, VIRTQ_DESC_F_WRITE, 0);
+
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        let segments = c.segments(true, 512).unwrap();
+        assert_eq!(segments.len(), 8 + 2);
+        for (i, &(addr, len)) in segments[..8].iter().enumerate() {
+            assert_eq!(addr, GuestAddress(0x2000 + 512 * i as u64));
+            assert_eq!(len, 512);
+        }
+        assert_eq!(segments[8], (GuestAddress(0x4000), 512));
+        assert_eq!(segments[9], (GuestAddress(0x4200), 0x100));
+
+        // Buffers that are smaller than the segment size are not split.
+        assert_eq!(
+            c.segments(false, 512).unwrap(),
+            vec![(GuestAddress(0x1000), 0x100)]
+        );
+        assert_eq!(
+            c.segments(true, 0).unwrap(),
+            vec![
+                (GuestAddress(0x2000), 0x1000),
+                (GuestAddress(0x4000), 0x300)
+            ]
+        );
+        // The chain itself is not consumed.
+        assert_eq!(c.count(), 3);
+
+        // Errors are reported instead of truncating the result.
+        vq.dtable(2)
+            .set(0x4000, 0x300, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 16);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        match c.segments(true, 512) {
+            Err(Error::InvalidDescriptorIndex) => (),
+            _ => panic!("expected an invalid descriptor index error"),
+        }
+    }
+
+    #[test]
+    fn test_copy_to_and_from_chain() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The chain has 12 readable bytes in 2 buffers, followed by 16 writable bytes in 2
+        // buffers.
+        vq.dtable(0).set(0x1000, 8, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x1100, 4, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2)
+            .set(0x2000, 6, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 3);
+        vq.dtable(3).set(0x3000, 10, VIRTQ_DESC_F_WRITE, 0);
+        let data: Vec<u8> = (1..=20).collect();
+        m.write_slice(&data[..8], GuestAddress(0x1000)).unwrap();
+        m.write_slice(&data[8..12], GuestAddress(0x1100)).unwrap();
+
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+
+        // Smaller buffers are copied entirely, larger ones are truncated.
+        let mut buf = [0u8; 5];
+        assert_eq!(c.read_all_from_readable(&mut buf).unwrap(), 5);
+        assert_eq!(buf, data[..5]);
+        let mut buf = [0u8; 20];
+        assert_eq!(c.read_all_from_readable(&mut buf).unwrap(), 12);
+        assert_eq!(buf[..12], data[..12]);
+        assert_eq!(buf[12..], [0u8; 8]);
+
+        assert_eq!(c.write_all_to_writable(&data[..4]).unwrap(), 4);
+        let mut written = [0u8; 6];
+        m.read_slice(&mut written, GuestAddress(0x2000)).unwrap();
+        assert_eq!(written, [1, 2, 3, 4, 0, 0]);
+
+        assert_eq!(c.write_all_to_writable(&data).unwrap(), 16);
+        m.read_slice(&mut written, GuestAddress(0x2000)).unwrap();
+        assert_eq!(written, data[..6]);
+        let mut written = [0u8; 11];
+        m.read_slice(&mut written, GuestAddress(0x3000)).unwrap();
+        assert_eq!(written[..10], data[6..16]);
+        assert_eq!(written[10], 0);
+
+        // The copies don't consume the chain.
+        assert_eq!(c.count(), 4);
+
+        // Buffers outside of guest memory are reported.
+        vq.dtable(3).set(0xfffc, 10, VIRTQ_DESC_F_WRITE, 0);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        match c.write_all_to_writable(&data) {
+            Err(Error::GuestMemory(_)) => (),
+            _ => panic!("expected a guest memory error"),
+        }
+    }
+
+    #[test]
+    fn test_buffer_slices() {
+        let m = &GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ])
+        .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // A single descriptor.
+        vq.dtable(0).set(0x1000, 0x100, 0, 0);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        let slices = c.buffer_slices().unwrap();
+        assert_eq!(slices.len(), 1);
+        assert_eq!(slices[0].len(), 0x100);
+        slices[0].write_obj(0x1234_5678u32, 0x10).unwrap();
+        assert_eq!(
+            m.read_obj::<u32>(GuestAddress(0x1010)).unwrap(),
+            0x1234_5678
+        );
+
+        // A chain with buffers in both regions.
+        vq.dtable(1).set(0x2000, 0x200, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2).set(0x18000, 0x300, VIRTQ_DESC_F_WRITE, 0);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 1);
+        let slices = c.buffer_slices().unwrap();
+        assert_eq!(slices.len(), 2);
+        assert_eq!(slices[0].len(), 0x200);
+        assert_eq!(slices[1].len(), 0x300);
+        m.write_obj(0xabu8, GuestAddress(0x18002)).unwrap();
+        assert_eq!(slices[1].read_obj::<u8>(2).unwrap(), 0xab);
+        // The chain itself is not consumed.
+        assert_eq!(c.count(), 2);
+
+        // Buffers crossing a region boundary, or not mapped, are rejected.
+        for addr in [0xff00, 0x20000] {
+            vq.dtable(2).set(addr, 0x200, VIRTQ_DESC_F_WRITE, 0);
+            let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 1);
+            match c.buffer_slices() {
+                Err(Error::GuestMemory(_)) => (),
+                _ => panic!("expected a guest memory error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_indirect_table_across_regions() {
+        // The boundary between the regions is either between two descriptors of the indirect
+        // table at 0xffe0, or in the middle of one.
+        for boundary in [0x10000, 0xfff8] {
+            let m = &GuestMemoryMmap::from_ranges(&[
+                (GuestAddress(0), boundary),
+                (GuestAddress(boundary as u64), 0x10000),
+            ])
+            .unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+            let table = GuestAddress(0xffe0);
+            vq.dtable(0)
+                .set(table.raw_value(), 4 * 16, VIRTQ_DESC_F_INDIRECT, 0);
+            for i in 0..4u16 {
+                let flags = if i < 3 { VIRTQ_DESC_F_NEXT } else { 0 };
+                let desc = Descriptor::new(0x1000 * u64::from(i + 1), 0x100, flags, i + 1);
+                m.write_obj(desc, table.unchecked_add(16 * u64::from(i)))
+                    .unwrap();
+            }
+
+            let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+            let addrs: Vec<u64> = c
+                .collect_descriptors()
+                .unwrap()
+                .iter()
+                .map(|desc| desc.addr().raw_value())
+                .collect();
+            assert_eq!(addrs, [0x1000, 0x2000, 0x3000, 0x4000]);
+        }
+    }
+
+    #[test]
+    fn test_indirect_table_in_other_region() {
+        let m = &GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10_0000), 0x10000),
+        ])
+        .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The indirect table lives in the second region, and its last descriptor points back
+        // to a buffer in the first one.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x10_0000, 0x30, VIRTQ_DESC_F_INDIRECT, 0);
+        let region = m.find_region(GuestAddress(0x10_0000)).unwrap();
+        let dtable = region
+            .get_slice(MemoryRegionAddress(0), VirtqDesc::dtable_len(3))
+            .unwrap();
+        VirtqDesc::new(&dtable, 0).set(0x10_1000, 0x200, VIRTQ_DESC_F_NEXT, 1);
+        VirtqDesc::new(&dtable, 1).set(0x10_2000, 0x300, VIRTQ_DESC_F_NEXT, 2);
+        VirtqDesc::new(&dtable, 2).set(0x2000, 0x400, VIRTQ_DESC_F_WRITE, 0);
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        let addrs: Vec<(u64, u32)> = core::iter::from_fn(|| c.next_descriptor())
+            .map(|desc| desc.map(|desc| (desc.addr().raw_value(), desc.len())))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                (0x1000, 0x100),
+                (0x10_1000, 0x200),
+                (0x10_2000, 0x300),
+                (0x2000, 0x400)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_total_len() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The chain is (0, 1, 2, 3), with 0, 1 being readable and 2, 3 writable.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2000, 0x200, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable(2)
+            .set(0x3000, 0x300, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 3);
+        vq.dtable(3).set(0x4000, 0x400, VIRTQ_DESC_F_WRITE, 0);
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert_eq!(c.total_len().unwrap(), (0x300, 0x700));
+
+        // Walking the chain doesn't consume it, and only the remaining descriptors count.
+        c.next().unwrap();
+        assert_eq!(c.total_len().unwrap(), (0x200, 0x700));
+        assert_eq!(c.count(), 3);
+
+        // The totals saturate instead of overflowing.
+        vq.dtable(0).set(0x1000, u32::MAX, VIRTQ_DESC_F_NEXT, 1);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert_eq!(c.total_len().unwrap(), (u32::MAX, 0x700));
+
+        // Chains longer than the chain byte limit are rejected instead of truncated.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        c.max_chain_bytes = 0xa00;
+        assert_eq!(c.total_len().unwrap(), (0x300, 0x700));
+        c.max_chain_bytes = 0x500;
+        match c.total_len() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+
+        // The descriptors of an indirect table are taken into account as well.
+        vq.dtable(4).set(0x8000, 0x30, VIRTQ_DESC_F_INDIRECT, 0);
+        let region = m.find_region(GuestAddress(0)).unwrap();
+        let dtable = region
+            .get_slice(MemoryRegionAddress(0x8000u64), VirtqDesc::dtable_len(3))
+            .unwrap();
+        VirtqDesc::new(&dtable, 0).set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        VirtqDesc::new(&dtable, 1).set(0x2000, 0x20, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        VirtqDesc::new(&dtable, 2).set(0x3000, 0x40, VIRTQ_DESC_F_WRITE, 0);
+
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 4);
+        assert_eq!(c.total_len().unwrap(), (0x10, 0x60));
+        assert_eq!(c.count(), 3);
+    }
+
+    #[test]
+    fn test_indirect_table_cycle() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let table = GuestAddress(0x2000);
+        let write_table = |links: &[(u16, u16)]| {
+            for &(index, next) in links {
+                let desc = Descriptor::new(0x4000, 0x10, VIRTQ_DESC_F_NEXT, next);
+                m.write_obj(desc, table.unchecked_add(u64::from(index) * 16))
+                    .unwrap();
+            }
+        };
+        // The indirect table has 64 descriptors.
+        vq.dtable(0).set(table.0, 64 * 16, VIRTQ_DESC_F_INDIRECT, 0);
+
+        // The chain (0, 40, 3, 40, ...) has a short cycle in a large table, which is detected
+        // when descriptor 40 is visited again, instead of after walking 64 descriptors.
+        write_table(&[(0, 40), (40, 3), (3, 40)]);
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        for _ in 0..3 {
+            assert!(c.next_descriptor().unwrap().is_ok());
+        }
+        match c.next_descriptor() {
+            Some(Err(Error::InvalidChain)) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        assert!(c.next_descriptor().is_none());
+
+        // A descriptor pointing to itself is a cycle as well.
+        write_table(&[(0, 0)]);
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        match c.collect_descriptors() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+
+        // Chains that weave through the table without cycles are fine, and walking a copy of
+        // the chain doesn't affect the original one.
+        write_table(&[(0, 63), (63, 1)]);
+        m.write_obj(Descriptor::new(0x4000, 0x10, 0, 0), table.unchecked_add(16))
+            .unwrap();
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert!(c.next_descriptor().unwrap().is_ok());
+        assert_eq!(c.collect_descriptors().unwrap().len(), 2);
+        assert_eq!(c.clone_from_head().count(), 3);
+        assert_eq!(c.count(), 2);
+    }
+
+    #[test]
+    fn test_indirect_descriptor_err() {
+        {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+            // create a chain with a descriptor pointing to an indirect table
+            let desc = vq.dtable(0);
+            desc.set(0x1001, 0x1000, VIRTQ_DESC_F_INDIRECT, 0);
+
+            let mut c: DescriptorChain<&GuestMemoryMmap> =
+                DescriptorChain::new(m, vq.start(), 16, 0);
+
+            assert!(c.next().is_none());
+        }
+
+        {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+            // create a chain with a descriptor pointing to an indirect table
+            let desc = vq.dtable(0);
+            desc.set(0x1000, 0x1001, VIRTQ_DESC_F_INDIRECT, 0);
+
+            let mut c: DescriptorChain<&GuestMemoryMmap> =
+                DescriptorChain::new(m, vq.start(), 16, 0);
+
+            assert!(c.next().is_none());
+        }
+
+        {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+            // The descriptor pointing to the indirect table also has the NEXT flag set.
+            vq.dtable(0)
+                .set(0x1000, 0x10, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT, 1);
+            vq.dtable(1).set(0x2000, 0x1000, 0, 0);
+            m.write_obj(Descriptor::new(0x3000, 0x1000, 0, 0), GuestAddress(0x1000))
+                .unwrap();
+
+            let mut c: DescriptorChain<&GuestMemoryMmap> =
+                DescriptorChain::new(m, vq.start(), 16, 0);
+            match c.next_descriptor() {
+                Some(Err(Error::InvalidIndirectDescriptor)) => (),
+                _ => panic!("expected an invalid indirect descriptor error"),
+            }
+            assert!(c.next_descriptor().is_none());
+        }
+
+        {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+            // The indirect table doesn't contain any descriptor.
+            vq.dtable(0).set(0x1000, 0, VIRTQ_DESC_F_INDIRECT, 0);
+
+            let mut c: DescriptorChain<&GuestMemoryMmap> =
+                DescriptorChain::new(m, vq.start(), 16, 0);
+            match c.next_descriptor() {
+                Some(Err(Error::InvalidIndirectDescriptorTable)) => (),
+                _ => panic!("expected an invalid indirect descriptor table error"),
+            }
+            assert!(c.next_descriptor().is_none());
+        }
+
+        {
+            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+            // The indirect table only holds half a descriptor.
+            vq.dtable(0).set(0x1000, 8, VIRTQ_DESC_F_INDIRECT, 0);
+
+            let mut c: DescriptorChain<&GuestMemoryMmap> =
+                DescriptorChain::new(m, vq.start(), 16, 0);
+            match c.next_descriptor() {
+                Some(Err(Error::InvalidIndirectDescriptorTable)) => (),
+                _ => panic!("expected an invalid indirect descriptor table error"),
+            }
+            assert!(c.next_descriptor().is_none());
+        }
+    }
+
+    #[test]
+    fn test_ring_component_sizes() {
+        type Q<'a> = Queue<&'a GuestMemoryMmap>;
+
+        assert_eq!(Q::desc_table_size(16), 256);
+        assert_eq!(Q::avail_ring_size(16), 38);
+        assert_eq!(Q::used_ring_size(16), 134);
+        assert_eq!(Q::desc_table_size(256), 4096);
+        assert_eq!(Q::avail_ring_size(256), 518);
+        assert_eq!(Q::used_ring_size(256), 2054);
+
+        // The sizes match the ones checked by `is_valid`.
+        for &size in [1u16, 16, 256, VIRTQ_MAX_SIZE].iter() {
+            assert_eq!(
+                ring_sizes(u64::from(size)),
+                Some((
+                    Q::desc_table_size(size),
+                    Q::avail_ring_size(size),
+                    Q::used_ring_size(size)
+                ))
+            );
+        }
+
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = Q::new(m, 256);
+        q.size = 256;
+        q.ready = true;
+        // The rings are laid out back to back, with the used ring aligned to 4 bytes.
+        q.desc_table = GuestAddress(0x1000);
+        q.avail_ring = q.desc_table.unchecked_add(Q::desc_table_size(256));
+        q.used_ring = q.avail_ring.unchecked_add(Q::avail_ring_size(256) + 2);
+        assert!(q.is_valid());
+        // The rings overlap when the used ring is moved back by a 4 byte step.
+        q.used_ring = GuestAddress(q.used_ring.0 - 4);
+        assert!(!q.is_valid());
+    }
+
+    #[test]
+    fn test_ring_sizes() {
+        assert_eq!(ring_sizes(16), Some((16 * 16, 6 + 16 * 2, 6 + 16 * 8)));
+        assert_eq!(
+            ring_sizes(u64::from(u16::MAX)),
+            Some((
+                16 * 0xffff,
+                VIRTQ_AVAIL_RING_META_SIZE + 2 * 0xffff,
+                VIRTQ_USED_RING_META_SIZE + 8 * 0xffff
+            ))
+        );
+
+        // Overflows are reported instead of wrapping around to small sizes.
+        assert_eq!(ring_sizes(u64::MAX), None);
+        assert_eq!(ring_sizes(u64::MAX / 16 + 1), None);
+        assert_eq!(ring_sizes(u64::MAX / 8), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert_eq!(q.validate(), Ok(()));
+
+        q.ready = false;
+        assert_eq!(q.validate(), Err(QueueError::NotReady));
+        q.ready = true;
+
+        for size in [0, 11, 32].iter() {
+            q.size = *size;
+            assert_eq!(q.validate(), Err(QueueError::InvalidSize));
+        }
+        q.size = 16;
+
+        q.desc_table = GuestAddress(0xffff_fff0);
+        assert_eq!(q.validate(), Err(QueueError::DescTableOutOfBounds));
+        q.desc_table = GuestAddress(0x1008);
+        assert_eq!(q.validate(), Err(QueueError::DescTableMisaligned));
+        q.desc_table = vq.dtable_start();
+
+        q.avail_ring = GuestAddress(0xffff_fff0);
+        assert_eq!(q.validate(), Err(QueueError::AvailRingOutOfBounds));
+        q.avail_ring = GuestAddress(0x1001);
+        assert_eq!(q.validate(), Err(QueueError::AvailRingMisaligned));
+        q.avail_ring = vq.avail_start();
+
+        q.used_ring = GuestAddress(0xffff_fff0);
+        assert_eq!(q.validate(), Err(QueueError::UsedRingOutOfBounds));
+        q.used_ring = GuestAddress(0x1002);
+        assert_eq!(q.validate(), Err(QueueError::UsedRingMisaligned));
+        q.used_ring = vq.used_start();
+
+        q.avail_ring = vq.dtable_start();
+        assert_eq!(q.validate(), Err(QueueError::DescTableOverlapsAvailRing));
+        q.avail_ring = vq.avail_start();
+        q.used_ring = vq.dtable_start();
+        assert_eq!(q.validate(), Err(QueueError::DescTableOverlapsUsedRing));
+        q.used_ring = vq.avail_start();
+        assert_eq!(q.validate(), Err(QueueError::AvailRingOverlapsUsedRing));
+        q.used_ring = vq.used_start();
+
+        assert_eq!(q.validate(), Ok(()));
+        assert!(q.is_valid());
+    }
+
+    #[test]
+    fn test_validate_extents() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // The rings were laid out for 8 entries.
+        let (desc_table, avail_ring, used_ring) = ring_sizes(8).unwrap();
+        let extents = RingExtents {
+            desc_table,
+            avail_ring,
+            used_ring,
+        };
+        q.set_size(8).unwrap();
+        assert_eq!(q.validate_extents(extents), Ok(()));
+
+        // Changing the size afterwards goes unnoticed by `validate`, but means the device
+        // looks for the ring entries in the wrong places.
+        q.set_size(16).unwrap();
+        assert_eq!(q.validate(), Ok(()));
+        assert_eq!(
+            q.validate_extents(extents),
+            Err(QueueError::DescTableTooSmall)
+        );
+        assert_eq!(
+            q.validate_extents(RingExtents {
+                desc_table: 16 * 16,
+                ..extents
+            }),
+            Err(QueueError::AvailRingTooSmall)
+        );
+        assert_eq!(
+            q.validate_extents(RingExtents {
+                desc_table: 16 * 16,
+                avail_ring: 6 + 16 * 2,
+                ..extents
+            }),
+            Err(QueueError::UsedRingTooSmall)
+        );
+
+        // The regular validation still applies.
+        q.ready = false;
+        assert_eq!(q.validate_extents(extents), Err(QueueError::NotReady));
+    }
+
+    #[test]
+    fn test_validate_custom_layout() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        // The default layout matches the one of `VirtQueue::new`.
+        let vq = VirtQueueBuilder::new(16).build(m);
+        let vq2 = VirtQueue::new(GuestAddress(0), m, 16);
+        assert_eq!(vq.avail_start(), vq2.avail_start());
+        assert_eq!(vq.used_start(), vq2.used_start());
+        assert_eq!(vq.end(), vq2.end());
+        assert_eq!(vq.create_queue(m).validate(), Ok(()));
+
+        // Padding moves the rings, which are still aligned.
+        let vq = VirtQueueBuilder::new(16)
+            .desc_table(GuestAddress(0x1000))
+            .avail_padding(0x101)
+            .used_padding(0x3)
+            .build(m);
+        assert_eq!(vq.avail_start(), GuestAddress(0x1000 + 0x100 + 0x102));
+        assert_eq!(vq.used_start().0 % 4, 0);
+        assert!(vq.used_start() >= vq.avail.end().unchecked_add(3));
+        assert_eq!(vq.create_queue(m).validate(), Ok(()));
+
+        // The used ring is placed inside the descriptor table.
+        let vq = VirtQueueBuilder::new(16)
+            .used_ring(GuestAddress(0x80))
+            .build(m);
+        assert_eq!(
+            vq.create_queue(m).validate(),
+            Err(QueueError::DescTableOverlapsUsedRing)
+        );
+
+        // The available and used rings overlap.
+        let vq = VirtQueueBuilder::new(16)
+            .avail_ring(GuestAddress(0x2000))
+            .used_ring(GuestAddress(0x2010))
+            .build(m);
+        assert_eq!(
+            vq.create_queue(m).validate(),
+            Err(QueueError::AvailRingOverlapsUsedRing)
+        );
+        // The queue ends with the structure that ends last.
+        assert_eq!(vq.end(), vq.used.end());
+
+        // The available ring is misaligned.
+        let vq = VirtQueueBuilder::new(16)
+            .avail_ring(GuestAddress(0x2001))
+            .build(m);
+        assert_eq!(
+            vq.create_queue(m).validate(),
+            Err(QueueError::AvailRingMisaligned)
+        );
+    }
+
+    #[test]
+    fn test_max_size() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20_0000)]).unwrap();
+
+        assert_eq!(
+            Queue::try_new(m, VIRTQ_MAX_SIZE).unwrap().max_size(),
+            VIRTQ_MAX_SIZE
+        );
+        assert_eq!(Queue::try_new(m, 1).unwrap().max_size(), 1);
+        for max_size in [0, 3, 24, VIRTQ_MAX_SIZE + 1, u16::MAX].iter() {
+            match Queue::try_new(m, *max_size) {
+                Err(Error::InvalidSize) => (),
+                _ => panic!("expected an invalid maximum size error"),
+            }
+        }
+
+        // A queue with the largest size allowed by the standard is valid.
+        let mut q = Queue::new(m, u16::MAX);
+        q.desc_table = GuestAddress(0);
+        q.avail_ring = GuestAddress(0x8_0000);
+        q.used_ring = GuestAddress(0x10_0000);
+        q.ready = true;
+        q.set_size(VIRTQ_MAX_SIZE).unwrap();
+        assert_eq!(q.size, VIRTQ_MAX_SIZE);
+        assert_eq!(q.validate(), Ok(()));
+
+        // Sizes above it are rejected even if `max_size` allows them.
+        assert_eq!(q.set_size(u16::MAX), Err(QueueError::InvalidSize));
+        assert_eq!(q.size, VIRTQ_MAX_SIZE);
+        q.size = u16::MAX;
+        assert_eq!(q.validate(), Err(QueueError::InvalidSize));
+
+        // A driver writing 65536 to a 32-bit size register wraps around to 0.
+        let regs = QueueRegisters {
+            size: 65536u32 as u16,
+            ..Default::default()
+        };
+        let q = Queue::from_registers(m, VIRTQ_MAX_SIZE, regs);
+        assert_eq!(q.size, VIRTQ_MAX_SIZE);
+        let mut q = q;
+        q.ready = true;
+        q.size = regs.size;
+        assert_eq!(q.validate(), Err(QueueError::InvalidSize));
+    }
+
+    #[test]
+    fn test_queue_and_iterator() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+
+        // q is currently valid
+        assert!(q.is_valid());
+
+        // shouldn't be valid when not marked as ready
+        q.ready = false;
+        assert!(!q.is_valid());
+        q.ready = true;
+
+        // or when size > max_size
+        q.size = q.max_size << 1;
+        assert!(!q.is_valid());
+        q.size = q.max_size;
+
+        // or when size is 0
+        q.size = 0;
+        assert!(!q.is_valid());
+        q.size = q.max_size;
+
+        // or when size is not a power of 2
+        q.size = 11;
+        assert!(!q.is_valid());
+        q.size = q.max_size;
+
+        // or if the various addresses are off
+
+        q.desc_table = GuestAddress(0xffff_ffff);
+        assert!(!q.is_valid());
+        q.desc_table = GuestAddress(0x1001);
+        assert!(!q.is_valid());
+        q.desc_table = vq.dtable_start();
+
+        q.avail_ring = GuestAddress(0xffff_ffff);
+        assert!(!q.is_valid());
+        q.avail_ring = GuestAddress(0x1001);
+        assert!(!q.is_valid());
+        q.avail_ring = vq.avail_start();
+
+        q.used_ring = GuestAddress(0xffff_ffff);
+        assert!(!q.is_valid());
+        q.used_ring = GuestAddress(0x1001);
+        assert!(!q.is_valid());
+        q.used_ring = vq.used_start();
+
+        // or if the rings overlap
+        q.used_ring = vq.dtable_start();
+        assert!(!q.is_valid());
+        // The last byte of the descriptor table is shared with the used ring.
+        q.used_ring = vq.dtable_start().unchecked_add(16 * 16 - 4);
+        assert!(!q.is_valid());
+        q.used_ring = vq.avail_start();
+        assert!(!q.is_valid());
+        q.used_ring = vq.used_start();
+        q.avail_ring = vq.dtable_start().unchecked_add(16 * 16 - 2);
+        assert!(!q.is_valid());
+        q.avail_ring = vq.avail_start();
+        // Adjacent rings are fine (the available ring directly follows the descriptor table).
+        assert_eq!(vq.avail_start(), vq.dtable_start().unchecked_add(16 * 16));
+        assert!(q.is_valid());
+
+        {
+            // an invalid queue should return an iterator with no next
+            q.ready = false;
+            let mut i = q.iter().unwrap();
+            assert!(i.next().is_none());
+        }
+
+        q.ready = true;
+
+        // now let's create two simple descriptor chains
+
+        {
+            for j in 0..5 {
+                vq.dtable(j).set(
+                    0x1000 * (j + 1) as u64,
+                    0x1000,
+                    VIRTQ_DESC_F_NEXT,
+                    (j + 1) as u16,
+                );
+            }
+
+            // the chains are (0, 1) and (2, 3, 4)
+            vq.dtable(1).flags().store(0);
+            vq.dtable(4).flags().store(0);
+            vq.avail.ring(0).store(0);
+            vq.avail.ring(1).store(2);
+            vq.avail.idx().store(2);
+
+            let mut i = q.iter().unwrap();
+
+            {
+                let mut c = i.next().unwrap();
+                assert_eq!(c.head_index(), 0);
+
+                c.next().unwrap();
+                assert!(c.next().is_some());
+                assert!(c.next().is_none());
+                assert_eq!(c.head_index(), 0);
+            }
+
+            {
+                let mut c = i.next().unwrap();
+                assert_eq!(c.head_index(), 2);
+
+                c.next().unwrap();
+                c.next().unwrap();
+                c.next().unwrap();
+                assert!(c.next().is_none());
+                assert_eq!(c.head_index(), 2);
+            }
+        }
+
+        // also test go_to_previous_position() works as expected
+        {
+            assert!(q.iter().unwrap().next().is_none());
+            q.go_to_previous_position();
+            let mut c = q.iter().unwrap().next().unwrap();
+            c.next().unwrap();
+            c.next().unwrap();
+            c.next().unwrap();
+            assert!(c.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_validate_order() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // (0, 1, 2) is read, write, write, and (3, 4, 5) is read, write, read.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2000, 0x100, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable(2).set(0x3000, 0, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable(3).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 4);
+        vq.dtable(4)
+            .set(0x2000, 0x100, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 5);
+        vq.dtable(5).set(0x3000, 0x100, 0, 0);
+
+        // Empty writable descriptors are not rejected by this check.
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert!(c.validate_order().is_ok());
+        assert_eq!(c.count(), 3);
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 3);
+        match c.validate_order() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        // Once the writable descriptor is consumed, the rest of the chain is valid on its own.
+        c.next().unwrap();
+        c.next().unwrap();
+        assert!(c.validate_order().is_ok());
+
+        // A chain with only writable descriptors is valid as well.
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 1);
+        assert!(c.validate_order().is_ok());
+    }
+
+    #[test]
+    fn test_avail_iter_validated() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // The first chain is valid, the second one points its last descriptor outside of guest
+        // memory, the third one has an empty writable descriptor, and the fourth one has a
+        // readable descriptor after a writable one.
+        let valid = build_chain(&vq, &[ChainDescSpec::default(); 2]);
+        vq.dtable(4).set(0x8000, 0x100, VIRTQ_DESC_F_NEXT, 5);
+        vq.dtable(5).set(0xff80, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable(6).set(0x8000, 0, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable(7)
+            .set(0x8000, 0x100, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 8);
+        vq.dtable(8).set(0x8100, 0x100, 0, 0);
+        for (i, head) in [4u16, 6, 7].iter().enumerate() {
+            vq.avail.ring(i as u16 + 1).store(*head);
+        }
+        vq.avail.idx().store(4);
+
+        let mut iter = q.iter().unwrap().validated();
+        assert_eq!(iter.len(), 4);
+        assert_eq!(iter.next().unwrap().unwrap().head_index(), valid);
+        match iter.next().unwrap() {
+            Err(Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(addr))) => {
+                assert_eq!(addr, GuestAddress(0xff80))
+            }
+            _ => panic!("expected an invalid guest address error"),
+        }
+        for _ in 0..2 {
+            match iter.next().unwrap() {
+                Err(Error::InvalidChain) => (),
+                _ => panic!("expected an invalid chain error"),
+            }
+        }
+        assert!(iter.next().is_none());
+        assert_eq!(q.next_avail(), 4);
+
+        // The checks can be disabled individually.
+        q.set_next_avail(1);
+        let checks = ChainValidation {
+            addresses_in_range: false,
+            no_empty_writable: false,
+            ..Default::default()
+        };
+        let results: Vec<_> = q.iter().unwrap().validated_with(checks).collect();
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(results[2].is_err());
+
+        let checks = ChainValidation {
+            readable_before_writable: false,
+            ..checks
+        };
+        q.set_next_avail(1);
+        assert!(q.iter().unwrap().validated_with(checks).all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_avail_iter_with_indices() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let spec = ChainDescSpec::default();
+        let heads = [
+            build_chain(&vq, &[spec, spec]),
+            build_chain(&vq, &[spec]),
+            build_chain(&vq, &[spec, spec, spec]),
+        ];
+
+        let iter = q.iter().unwrap().with_indices();
+        assert_eq!(iter.len(), 3);
+        let mut count = 0;
+        for (i, (head, chain)) in iter.enumerate() {
+            assert_eq!(head, heads[i]);
+            assert_eq!(head, chain.head_index());
+            count += 1;
+        }
+        assert_eq!(count, 3);
+        assert_eq!(q.next_avail(), 3);
+    }
+
+    #[test]
+    fn test_take_while_chain() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+
+        // Three single descriptor chains, with heads 0, 1 and 2.
+        for j in 0..3 {
+            vq.dtable(j).set(0x1000 * (j + 1) as u64, 0x1000, 0, 0);
+            vq.avail.ring(j).store(j);
+        }
+        vq.avail.idx().store(3);
+
+        let mut count = 0;
+        let heads: Vec<u16> = q
+            .iter()
+            .unwrap()
+            .take_while_chain(|_| {
+                count += 1;
+                count <= 2
+            })
+            .map(|c| c.head_index())
+            .collect();
+        assert_eq!(heads, vec![0, 1]);
+        assert_eq!(q.next_avail(), 2);
+
+        // The rejected chain is still available.
+        let mut i = q.iter().unwrap();
+        assert_eq!(i.next().unwrap().head_index(), 2);
+        assert!(i.next().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_available() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        assert!(q.snapshot_available().unwrap().is_empty());
+
+        // The chains are (0, 1) and (2).
+        vq.dtable(0).set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable(2).set(0x3000, 0x1000, 0, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.ring(1).store(2);
+        vq.avail.idx().store(2);
+
+        let snapshot = q.snapshot_available().unwrap();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].head_index(), 0);
+        assert_eq!(snapshot[0].descriptors().len(), 2);
+        assert_eq!(snapshot[1].head_index(), 2);
+        assert_eq!(snapshot[1].descriptors().len(), 1);
+
+        // Taking the snapshot didn't consume anything.
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.snapshot_available().unwrap(), snapshot);
+
+        let chains: Vec<_> = q.iter().unwrap().collect();
+        assert_eq!(chains.len(), snapshot.len());
+        for (chain, owned) in chains.into_iter().zip(snapshot.iter()) {
+            assert_eq!(chain.head_index(), owned.head_index());
+            assert_eq!(chain.collect::<Vec<_>>(), owned.descriptors());
+        }
+        assert!(q.snapshot_available().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_analyze_available() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert!(q.analyze_available().unwrap().is_empty());
+
+        let spec = |len, writable, indirect| ChainDescSpec {
+            len,
+            writable,
+            indirect,
+        };
+
+        let head0 = build_chain(
+            &vq,
+            &[
+                spec(0x10, false, false),
+                spec(0x20, false, false),
+                spec(0x30, true, false),
+            ],
+        );
+        let head1 = build_chain(&vq, &[spec(0x40, true, false), spec(0x50, true, false)]);
+        // The descriptors of the indirect table are counted instead of the indirect one.
+        let head2 = build_chain(
+            &vq,
+            &[
+                spec(0x60, false, false),
+                spec(0x70, false, true),
+                spec(0x80, true, true),
+            ],
+        );
+
+        let summaries = q.analyze_available().unwrap();
+        assert_eq!(summaries.len(), 3);
+
+        assert_eq!(summaries[0].head_index(), head0);
+        assert_eq!(summaries[0].readable_count(), 2);
+        assert_eq!(summaries[0].readable_len(), 0x30);
+        assert_eq!(summaries[0].writable_count(), 1);
+        assert_eq!(summaries[0].writable_len(), 0x30);
+
+        assert_eq!(summaries[1].head_index(), head1);
+        assert_eq!(summaries[1].readable_count(), 0);
+        assert_eq!(summaries[1].readable_len(), 0);
+        assert_eq!(summaries[1].writable_count(), 2);
+        assert_eq!(summaries[1].writable_len(), 0x90);
+
+        assert_eq!(summaries[2].head_index(), head2);
+        assert_eq!(summaries[2].readable_count(), 2);
+        assert_eq!(summaries[2].readable_len(), 0xd0);
+        assert_eq!(summaries[2].writable_count(), 1);
+        assert_eq!(summaries[2].writable_len(), 0x80);
+
+        // Nothing was consumed.
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.iter().unwrap().count(), 3);
+        assert!(q.analyze_available().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_available_descriptor_chains() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        assert_eq!(q.available_descriptor_chains().unwrap(), 0);
+
+        let spec = ChainDescSpec {
+            len: 0x100,
+            writable: false,
+            indirect: false,
+        };
+        for _ in 0..5 {
+            build_chain(&vq, &[spec]);
+        }
+        assert_eq!(q.available_descriptor_chains().unwrap(), 5);
+        // Nothing was consumed.
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.available_descriptor_chains().unwrap(), 5);
+
+        q.iter().unwrap().take(2).for_each(drop);
+        assert_eq!(q.available_descriptor_chains().unwrap(), 3);
+        q.iter().unwrap().for_each(drop);
+        assert_eq!(q.available_descriptor_chains().unwrap(), 0);
+
+        // The indices wrap around.
+        q.set_next_avail(u16::MAX - 1);
+        vq.avail.idx().store(2);
+        assert_eq!(q.available_descriptor_chains().unwrap(), 4);
+        assert_eq!(q.next_avail(), u16::MAX - 1);
+    }
+
+    #[test]
+    fn test_avail_event() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        for &size in [16, VIRTQ_MAX_SIZE].iter() {
+            let vq = VirtQueue::new(GuestAddress(0), m, size);
+            let q = vq.create_queue(m);
+
+            q.set_avail_event(0xabcd, Ordering::Release).unwrap();
+            assert_eq!(q.avail_event(Ordering::Acquire).unwrap(), 0xabcd);
+            assert_eq!(vq.used.event().load(), 0xabcd);
+
+            vq.used.event().store(7);
+            assert_eq!(q.avail_event(Ordering::Relaxed).unwrap(), 7);
+        }
+
+        let mut q = Queue::new(m, 16);
+        q.used_ring = GuestAddress(0x10_0000);
+        assert!(q.avail_event(Ordering::Relaxed).is_err());
+    }
+
+    #[test]
+    fn test_event_offsets_max_size() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, VIRTQ_MAX_SIZE);
+        let mut q = vq.create_queue(m);
+        assert!(q.is_valid());
+        q.set_event_idx(true);
+
+        // `avail_event` is right after the last used ring entry.
+        q.set_next_avail(0x1234);
+        q.enable_notification().unwrap();
+        assert_eq!(vq.used.event().load(), 0x1234);
+        assert_eq!(
+            m.read_obj::<u16>(vq.used.start().unchecked_add(4 + 32768 * 8))
+                .unwrap(),
+            0x1234
+        );
+
+        // `used_event` is right after the last available ring entry.
+        vq.avail.event().store(2);
+        assert_eq!(
+            m.read_obj::<u16>(vq.avail.start().unchecked_add(4 + 32768 * 2))
+                .unwrap(),
+            2
+        );
+        q.add_used(1, 0).unwrap();
+        assert!(q.needs_notification().unwrap());
+        q.add_used(2, 0).unwrap();
+        assert!(!q.needs_notification().unwrap());
+        q.add_used(3, 0).unwrap();
+        assert!(q.needs_notification().unwrap());
+        assert_eq!(vq.used.ring(0).load().id(), 1);
+        assert_eq!(vq.used.ring(2).load().id(), 3);
+    }
+
+    #[test]
+    fn test_set_notification_data() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let heads: Vec<u16> = (0..3)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+
+        // The notification says two chains are available, so the third one, which the driver
+        // posted afterwards, is not seen until the ring is read again.
+        q.set_notification_data(2);
+        let c: Vec<u16> = q.iter().unwrap().map(|c| c.head_index()).collect();
+        assert_eq!(c, heads[..2]);
+
+        // A stale notification doesn't hide chains or replay consumed ones.
+        q.set_notification_data(1);
+        let c: Vec<u16> = q.iter().unwrap().map(|c| c.head_index()).collect();
+        assert_eq!(c, heads[2..]);
+        q.set_notification_data(3);
+        assert_eq!(q.iter().unwrap().count(), 0);
+
+        // Values too far ahead are ignored as well.
+        q.set_notification_data(3 + 17);
+        assert_eq!(q.cached_avail_idx, Wrapping(3));
+        assert_eq!(q.iter().unwrap().count(), 0);
+
+        // The index wraps around like the one of the available ring.
+        q.set_next_avail(u16::MAX);
+        q.set_notification_data(1);
+        assert_eq!(q.cached_avail_idx, Wrapping(1));
+    }
+
+    #[test]
+    fn test_cached_avail_idx() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let spec = ChainDescSpec {
+            len: 0x100,
+            writable: false,
+            indirect: false,
+        };
+        build_chain(&vq, &[spec]);
+        build_chain(&vq, &[spec]);
+
+        assert_eq!(q.iter().unwrap().next().unwrap().head_index(), 0);
+        assert_eq!(q.cached_avail_idx, Wrapping(2));
+
+        // A chain made available while the cache isn't exhausted is not seen yet.
+        build_chain(&vq, &[spec]);
+        assert_eq!(q.iter().unwrap().count(), 1);
+        // It is seen once the cache is exhausted.
+        assert_eq!(q.iter().unwrap().count(), 1);
+        assert_eq!(q.cached_avail_idx, Wrapping(3));
+        assert_eq!(q.iter().unwrap().count(), 0);
+
+        // Explicit refreshes pick up new chains at any time.
+        build_chain(&vq, &[spec]);
+        build_chain(&vq, &[spec]);
+        assert_eq!(q.iter().unwrap().take(1).count(), 1);
+        build_chain(&vq, &[spec]);
+        assert_eq!(q.refresh_avail_idx().unwrap(), Wrapping(6));
+        assert_eq!(q.iter().unwrap().count(), 2);
+
+        // Moving `next_avail` around invalidates the cache.
+        q.set_next_avail(2);
+        assert_eq!(q.iter().unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_cached_avail_idx_after_pop() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let heads: Vec<u16> = (0..4)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+
+        // `pop` moves past the cached index, which is then refreshed by `iter`.
+        assert_eq!(q.iter().unwrap().next().unwrap().head_index(), heads[0]);
+        assert_eq!(q.cached_avail_idx, Wrapping(4));
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        for &head in &heads[1..] {
+            assert_eq!(q.pop().unwrap().unwrap().head_index(), head);
+        }
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), 4);
+        let iter = q.iter().unwrap();
+        assert_eq!(iter.len(), 0);
+        assert_eq!(iter.count(), 0);
+
+        // Chains are not yielded twice when `pop` consumes some of the cached ones.
+        let heads: Vec<u16> = (0..3)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), heads[0]);
+        let c: Vec<u16> = q.iter().unwrap().map(|c| c.head_index()).collect();
+        assert_eq!(c, heads[1..]);
+        assert_eq!(q.iter().unwrap().count(), 0);
+
+        // The same goes for `pop_descriptor_chain`.
+        let mut heads: Vec<u16> = (0..3)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+        assert_eq!(q.iter().unwrap().next().unwrap().head_index(), heads[0]);
+        heads.push(build_chain(&vq, &[ChainDescSpec::default()]));
+        for &head in &heads[1..] {
+            assert_eq!(q.pop_descriptor_chain().unwrap().head_index(), head);
+        }
+        assert_eq!(q.iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_process() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let heads: Vec<u16> = (0..3)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+
+        // The chains are processed in order, and `f` errors are propagated.
+        let mut seen = Vec::new();
+        let result = q.process(|chain| {
+            if seen.len() == 1 {
+                return Err(Error::InvalidChain);
+            }
+            seen.push(chain.head_index());
+            Ok(())
+        });
+        match result {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        assert_eq!(seen, heads[..1]);
+        // The failing chain is still available.
+        assert_eq!(q.next_avail(), 1);
+        assert!(q.take_last_error().is_none());
+
+        let mut seen = Vec::new();
+        let count = q
+            .process(|chain| {
+                seen.push(chain.head_index());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(seen, heads[1..]);
+        assert_eq!(q.next_avail(), 3);
+        assert_eq!(q.process(|_| Ok(())).unwrap(), 0);
+        // `iter` doesn't replay the chains consumed by `process`.
+        assert_eq!(q.iter().unwrap().count(), 0);
+
+        // The chain that failed is the first one yielded by `iter`.
+        let heads: Vec<u16> = (0..2)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+        assert!(q.process(|_| Err(Error::InvalidChain)).is_err());
+        let c: Vec<u16> = q.iter().unwrap().map(|c| c.head_index()).collect();
+        assert_eq!(c, heads);
+
+        // Errors accessing the chains are propagated as well, without consuming anything.
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        let avail_ring = q.avail_ring;
+        q.avail_ring = GuestAddress(0x1_0000);
+        match q.process(|_| Ok(())) {
+            Err(Error::GuestMemory(_)) => (),
+            _ => panic!("expected a guest memory error"),
+        }
+        assert_eq!(q.next_avail(), 5);
+        q.avail_ring = avail_ring;
+        assert_eq!(q.process(|_| Ok(())).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_pop_descriptor_chain() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert!(q.pop_descriptor_chain().is_none());
+
+        let spec = ChainDescSpec::default();
+        for len in 1..5 {
+            build_chain(&vq, &vec![spec; len]);
+        }
+
+        // The chains are the same as the ones yielded by `iter`.
+        let expected: Vec<(u16, usize)> = q
+            .clone()
+            .iter()
+            .unwrap()
+            .map(|c| (c.head_index(), c.count()))
+            .collect();
+        assert_eq!(expected.len(), 4);
+        for (i, &(head, len)) in expected.iter().enumerate() {
+            let chain = q.pop_descriptor_chain().unwrap();
+            assert_eq!(chain.head_index(), head);
+            assert_eq!(chain.count(), len);
+            assert_eq!(q.next_avail(), i as u16 + 1);
+        }
+        assert!(q.pop_descriptor_chain().is_none());
+        assert!(q.take_last_error().is_none());
+
+        // Errors are recorded, and nothing is consumed.
+        build_chain(&vq, &[spec]);
+        q.avail_ring = GuestAddress(0x20000);
+        assert!(q.pop_descriptor_chain().is_none());
+        assert_eq!(q.take_last_error(), Some(ErrorKind::GuestMemory));
+        assert_eq!(q.next_avail(), 4);
+    }
+
+    #[test]
+    fn test_peek_pop() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        assert!(q.peek().unwrap().is_none());
+        assert!(q.pop().unwrap().is_none());
+
+        let spec = |len, writable| ChainDescSpec {
+            len,
+            writable,
+            indirect: false,
+        };
+        let head0 = build_chain(&vq, &[spec(0x10, false), spec(0x20, true)]);
+        let head1 = build_chain(&vq, &[spec(0x30, true)]);
+
+        // Peeking and then dropping the chain leaves it available.
+        let chain = q.peek().unwrap().unwrap();
+        assert_eq!(chain.head_index(), head0);
+        assert_eq!(chain.count(), 2);
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.peek().unwrap().unwrap().head_index(), head0);
+
+        // Peeking and then popping consumes the same chain.
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), head0);
+        assert_eq!(q.next_avail(), 1);
+        assert_eq!(q.peek().unwrap().unwrap().head_index(), head1);
+        assert_eq!(q.iter().unwrap().next().unwrap().head_index(), head1);
+        assert!(q.peek().unwrap().is_none());
+        assert!(q.pop().unwrap().is_none());
+        assert_eq!(q.next_avail(), 2);
+
+        // Errors are reported instead of looking like an empty queue, and nothing is consumed.
+        q.avail_ring = GuestAddress(0xfffc);
+        m.write_obj::<u16>(3, GuestAddress(0xfffe)).unwrap();
+        assert!(q.peek().is_err());
+        assert!(q.pop().is_err());
+        assert_eq!(q.next_avail(), 2);
+    }
+
+    #[test]
+    fn test_build_chain() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let spec = |len, writable, indirect| ChainDescSpec {
+            len,
+            writable,
+            indirect,
+        };
+
+        let head0 = build_chain(
+            &vq,
+            &[
+                spec(0x10, false, false),
+                spec(0x20, true, false),
+                spec(0x30, true, false),
+            ],
+        );
+        let head1 = build_chain(
+            &vq,
+            &[
+                spec(0x40, false, false),
+                spec(0x50, false, true),
+                spec(0x60, true, true),
+            ],
+        );
+        assert_eq!(head0, 0);
+        assert_eq!(head1, 3);
+        assert_eq!(vq.avail.idx().load(), 2);
+
+        let mut i = q.iter().unwrap();
+
+        let c = i.next().unwrap();
+        assert_eq!(c.head_index(), head0);
+        let descs: Vec<_> = c.collect();
+        assert_eq!(descs.len(), 3);
+        assert!(!descs[0].is_write_only());
+        assert!(descs[1].is_write_only());
+        assert!(descs[2].is_write_only());
+        assert_eq!(
+            descs.iter().map(|d| d.len()).collect::<Vec<_>>(),
+            vec![0x10, 0x20, 0x30]
+        );
+        // The buffers don't overlap with the queue or with each other.
+        assert!(descs[0].addr() >= vq.end());
+        assert!(descs[0].addr().unchecked_add(0x10) <= descs[1].addr());
+        assert!(descs[1].addr().unchecked_add(0x20) <= descs[2].addr());
+
+        let c = i.next().unwrap();
+        assert_eq!(c.head_index(), head1);
+        assert_eq!(c.total_len().unwrap(), (0x90, 0x60));
+        let descs: Vec<_> = c.collect();
+        assert_eq!(descs.len(), 3);
+        assert!(!descs[0].is_write_only());
+        assert!(!descs[1].is_write_only());
+        assert!(descs[2].is_write_only());
+
+        assert!(i.next().is_none());
+    }
+
+    #[test]
+    fn test_descriptor_and_iterator() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+
+        // q is currently valid
+        assert!(q.is_valid());
+
+        for j in 0..7 {
+            vq.dtable(j).set(
+                0x1000 * (j + 1) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (j + 1) as u16,
+            );
+        }
+
+        // the chains are (0, 1), (2, 3, 4) and (5, 6)
+        vq.dtable(1).flags().store(0);
+        vq.dtable(2)
+            .flags()
+            .store(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        vq.dtable(4).flags().store(VIRTQ_DESC_F_WRITE);
+        vq.dtable(5)
+            .flags()
+            .store(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        vq.dtable(6).flags().store(0);
+        vq.avail.ring(0).store(0);
+        vq.avail.ring(1).store(2);
+        vq.avail.ring(2).store(5);
+        vq.avail.idx().store(3);
+
+        let mut i = q.iter().unwrap();
+
+        {
+            let c = i.next().unwrap();
+            assert_eq!(c.head_index(), 0);
+
+            let mut iter = c;
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_none());
+            assert!(iter.next().is_none());
+        }
+
+        {
+            let c = i.next().unwrap();
+            assert_eq!(c.head_index(), 2);
+
+            let mut iter = c.writable();
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_none());
+            assert!(iter.next().is_none());
+        }
+
+        {
+            let c = i.next().unwrap();
+            assert_eq!(c.head_index(), 5);
+
+            let mut iter = c.readable();
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_none());
+            assert!(iter.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_require_writable_bytes() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The chain is (0, 1, 2), with 0 being readable and 1, 2 writable.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1)
+            .set(0x2000, 0x200, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable(2).set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+
+        let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+
+        assert!(c.require_writable_bytes(0).is_ok());
+        assert!(c.require_writable_bytes(0x210).is_ok());
+        match c.require_writable_bytes(0x211) {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+
+        // The check doesn't consume the chain.
+        assert_eq!(c.count(), 3);
+    }
+
+    #[test]
+    fn test_descriptor_prefetch() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert!(!q.descriptor_prefetch());
+
+        // The first chain has an indirect table with more descriptors than are read at once,
+        // and the second one is laid out as (12, 9, 10), so only part of it is contiguous.
+        let spec = ChainDescSpec {
+            len: 0x10,
+            writable: false,
+            indirect: true,
+        };
+        let indirect_head = build_chain(&vq, &[spec; 20]);
+        vq.dtable(12).set(0x8000, 0x10, VIRTQ_DESC_F_NEXT, 9);
+        vq.dtable(9).set(0x8100, 0x20, VIRTQ_DESC_F_NEXT, 10);
+        vq.dtable(10).set(0x8200, 0x30, VIRTQ_DESC_F_WRITE, 0);
+
+        let expected: Vec<Vec<Descriptor>> = [indirect_head, 12]
+            .iter()
+            .map(|&head| q.descriptor_chain(head).unwrap().collect())
+            .collect();
+        assert_eq!(expected[0].len(), 20);
+        assert_eq!(expected[1].len(), 3);
+
+        q.set_descriptor_prefetch(true);
+        assert!(q.descriptor_prefetch());
+        for (&head, descs) in [indirect_head, 12].iter().zip(expected.iter()) {
+            let chain = q.descriptor_chain(head).unwrap();
+            assert_eq!(&chain.clone_from_head().collect::<Vec<_>>(), descs);
+            assert_eq!(&chain.collect::<Vec<_>>(), descs);
+        }
+
+        // The descriptors following the first one of the indirect table are taken from the
+        // cache filled when reading it, so later changes to the table are not observed.
+        let table = GuestAddress(vq.dtable(indirect_head).addr().load());
+        let mut chain = q.descriptor_chain(indirect_head).unwrap();
+        assert_eq!(chain.next().unwrap(), expected[0][0]);
+        m.write_obj(
+            Descriptor::new(0x9000, 0x999, 0, 0),
+            table.unchecked_add(16),
+        )
+        .unwrap();
+        assert_eq!(chain.next().unwrap(), expected[0][1]);
+
+        q.set_descriptor_prefetch(false);
+        let mut chain = q.descriptor_chain(indirect_head).unwrap();
+        chain.next().unwrap();
+        assert_eq!(chain.next().unwrap().len(), 0x999);
+    }
+
+    #[test]
+    fn test_optimized() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let q = vq.create_queue(m).optimized();
+        assert!(q.descriptor_prefetch());
+
+        // A mix of direct and indirect chains of various lengths.
+        let spec = ChainDescSpec::default();
+        let indirect = ChainDescSpec {
+            len: 0x20,
+            writable: true,
+            indirect: true,
+        };
+        build_chain(&vq, &[spec]);
+        build_chain(&vq, &[spec; 4]);
+        build_chain(&vq, &[indirect; 20]);
+        build_chain(&vq, &[spec, indirect, indirect]);
+
+        // Both queues consume the same chains from the same rings, and publish them.
+        let run = |mut q: Queue<&GuestMemoryMmap>| {
+            let mut chains = Vec::new();
+            while let Some(chain) = q.pop().unwrap() {
+                let head = chain.head_index();
+                let descs: Vec<Descriptor> = chain.collect();
+                let len = descs.iter().map(|desc| desc.len()).sum();
+                q.add_used(head, len).unwrap();
+                chains.push((head, descs));
+            }
+            let used: Vec<(u32, u32)> = (0..chains.len() as u16)
+                .map(|i| vq.used.ring(i).load())
+                .map(|elem| (elem.id(), elem.len()))
+                .collect();
+            (chains, used, q.next_used(), vq.used.idx().load())
+        };
+        let expected = run(vq.create_queue(m));
+        assert_eq!(expected.0.len(), 4);
+        for i in 0..4 {
+            vq.used.ring(i).store(VirtqUsedElem::new(0, 0));
+        }
+        vq.used.idx().store(0);
+        assert_eq!(run(q), expected);
+    }
+
+    #[test]
+    fn test_max_chain_bytes() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert_eq!(q.max_chain_bytes(), u64::MAX);
+
+        // Both chains have a readable and a writable descriptor, for a total of 0x300 bytes
+        // and 0x200 bytes respectively.
+        vq.dtable(0).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable(1).set(0x2000, 0x200, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable(2).set(0x3000, 0x100, VIRTQ_DESC_F_NEXT, 3);
+        vq.dtable(3).set(0x4000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.ring(1).store(2);
+        vq.avail.idx().store(2);
+
+        q.set_max_chain_bytes(0x200);
+        assert_eq!(q.max_chain_bytes(), 0x200);
+        let mut chains = q.iter().unwrap();
+
+        let c = chains.next().unwrap();
+        match c.checked_total_len() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        match c.require_writable_bytes(0) {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        // Restarting from the head doesn't bypass the limit.
+        assert!(c.clone_from_head().checked_total_len().is_err());
+
+        let c = chains.next().unwrap();
+        assert_eq!(c.checked_total_len().unwrap(), (0x100, 0x100));
+        assert!(c.require_writable_bytes(0x100).is_ok());
+    }
+
+    #[test]
+    fn test_used_elem_accessors() {
+        let elem = VirtqUsedElem::new(0xfffe, 0x1234_5678);
+        assert_eq!(elem.id(), 0xfffe);
+        assert_eq!(elem.len(), 0x1234_5678);
+
+        // The accessors undo the conversion to little-endian done by the constructor.
+        let bytes = elem.as_slice();
+        assert_eq!(bytes[..4], 0xfffeu32.to_le_bytes());
+        assert_eq!(bytes[4..], 0x1234_5678u32.to_le_bytes());
+        let elem = VirtqUsedElem::from_slice(bytes).unwrap();
+        assert_eq!(elem.id(), 0xfffe);
+        assert_eq!(elem.len(), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_add_used() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        assert_eq!(vq.used.idx().load(), 0);
+
+        //index too large
+        assert!(q.add_used(16, 0x1000).is_err());
+        assert_eq!(vq.used.idx().load(), 0);
+
+        //should be ok
+        assert_eq!(q.add_used(1, 0x1000).unwrap(), 1);
+        assert_eq!(q.next_used, Wrapping(1));
+        assert_eq!(vq.used.idx().load(), 1);
+        let x = vq.used.ring(0).load();
+        assert_eq!(x.id(), 1);
+        assert_eq!(x.len(), 0x1000);
+
+        // The returned index wraps around.
+        q.next_used = Wrapping(u16::MAX);
+        assert_eq!(q.add_used(2, 0x1000).unwrap(), 0);
+        assert_eq!(vq.used.idx().load(), 0);
+    }
+
+    #[test]
+    fn test_add_used_idx_wrap() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // Complete buffers one at a time across the wrap of the used `idx`.
+        q.set_next_used(u16::MAX - 1);
+        let expected = [(u16::MAX, 14), (0, 15), (1, 0), (2, 1)];
+        for (head, &(idx, slot)) in expected.iter().enumerate() {
+            let head = head as u16;
+            assert_eq!(q.add_used(head, 0x100 + u32::from(head)).unwrap(), idx);
+            assert_eq!(q.next_used(), idx);
+            assert_eq!(vq.used.idx().load(), idx);
+            let elem = vq.used.ring(slot).load();
+            assert_eq!(elem.id(), u32::from(head));
+            assert_eq!(elem.len(), 0x100 + u32::from(head));
+        }
+
+        // Same with a batch that straddles the wrap.
+        q.set_next_used(u16::MAX - 2);
+        q.add_used_batch(&[(5, 0x500), (6, 0x600), (7, 0x700), (8, 0x800)])
+            .unwrap();
+        assert_eq!(q.next_used(), 1);
+        assert_eq!(vq.used.idx().load(), 1);
+        for (slot, id) in [(13, 5), (14, 6), (15, 7), (0, 8)] {
+            let elem = vq.used.ring(slot).load();
+            assert_eq!(elem.id(), id);
+            assert_eq!(elem.len(), id * 0x100);
+        }
+    }
+
+    #[test]
+    fn test_add_used_checked() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // The chain has 0x300 writable bytes, including an indirect descriptor.
+        let head = build_chain(
+            &vq,
+            &[
+                ChainDescSpec {
+                    len: 0x1000,
+                    writable: false,
+                    indirect: false,
+                },
+                ChainDescSpec {
+                    len: 0x100,
+                    writable: true,
+                    indirect: false,
+                },
+                ChainDescSpec {
+                    len: 0x200,
+                    writable: true,
+                    indirect: true,
+                },
+            ],
+        );
+
+        match q.add_used_checked(head, 0x301) {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        assert!(q.take_last_error().is_some());
+        assert_eq!(vq.used.idx().load(), 0);
+
+        match q.add_used_checked(16, 0) {
+            Err(Error::InvalidDescriptorIndex) => (),
+            _ => panic!("expected an invalid descriptor index error"),
+        }
+        assert_eq!(vq.used.idx().load(), 0);
+
+        assert_eq!(q.add_used_checked(head, 0x300).unwrap(), 1);
+        let x = vq.used.ring(0).load();
+        assert_eq!(x.id(), u32::from(head));
+        assert_eq!(x.len(), 0x300);
+
+        // The unchecked variant accepts the over-reported length.
+        assert_eq!(q.add_used(head, 0x301).unwrap(), 2);
+    }
+
+    #[cfg(feature = "available-callback")]
+    #[test]
+    fn test_on_became_available() {
+        use std::sync::atomic::AtomicUsize;
+
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        q.set_on_became_available(move || {
+            calls2.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(q.iter().unwrap().count(), 0);
+        assert_eq!(q.available_descriptor_chains().unwrap(), 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // The callback fires on the transition only.
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        assert_eq!(q.available_descriptor_chains().unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        assert_eq!(q.available_descriptor_chains().unwrap(), 2);
+        assert_eq!(q.iter().unwrap().count(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The queue is observed empty again before the next chain is posted.
+        assert_eq!(q.iter().unwrap().count(), 0);
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        assert_eq!(q.iter().unwrap().count(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_into_iterator() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let heads: Vec<u16> = (0..3)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+
+        let mut c = Vec::new();
+        for chain in &mut q {
+            c.push(chain.head_index());
+        }
+        assert_eq!(c, heads);
+        assert_eq!(q.next_avail(), 3);
+        assert_eq!((&mut q).into_iter().count(), 0);
+
+        // The iterator is empty when the available ring can't be read.
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        let avail_ring = q.avail_ring;
+        q.avail_ring = GuestAddress(0x20000);
+        assert_eq!((&mut q).into_iter().count(), 0);
+        assert_eq!(q.take_last_error(), Some(ErrorKind::GuestMemory));
+        q.avail_ring = avail_ring;
+        assert_eq!((&mut q).into_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_iter_budget() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let heads: Vec<u16> = (0..10)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+
+        let c: Vec<u16> = q.iter_budget(4).unwrap().map(|c| c.head_index()).collect();
+        assert_eq!(c, heads[..4]);
+        assert_eq!(q.next_avail(), 4);
+
+        // Chains that aren't yielded are not consumed.
+        {
+            let mut iter = q.iter_budget(4).unwrap();
+            assert_eq!(iter.len(), 4);
+            assert_eq!(iter.next().unwrap().head_index(), heads[4]);
+        }
+        assert_eq!(q.next_avail(), 5);
+
+        // The budget can exceed the number of available chains.
+        let c: Vec<u16> = q
+            .iter_budget(100)
+            .unwrap()
+            .map(|c| c.head_index())
+            .collect();
+        assert_eq!(c, heads[5..]);
+        assert_eq!(q.iter_budget(4).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_descriptor_chain_by_index() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let q = vq.create_queue(m);
+
+        // A chain headed by descriptor 5, which was never made available.
+        vq.dtable(5).set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 6);
+        vq.dtable(6).set(0x2000, 0x200, VIRTQ_DESC_F_WRITE, 0);
+
+        let chain = q.descriptor_chain(5).unwrap();
+        assert_eq!(chain.head_index(), 5);
+        let descs: Vec<(u64, u32)> = chain
+            .map(|desc| (desc.addr().raw_value(), desc.len()))
+            .collect();
+        assert_eq!(descs, [(0x1000, 0x100), (0x2000, 0x200)]);
+        assert_eq!(q.next_avail(), 0);
+
+        match q.descriptor_chain(16) {
+            Err(Error::InvalidDescriptorIndex) => (),
+            _ => panic!("expected an invalid descriptor index error"),
+        }
+    }
+
+    #[test]
+    fn test_avail_iter_len() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        for _ in 0..3 {
+            build_chain(&vq, &[ChainDescSpec::default()]);
+        }
+        let mut iter = q.iter().unwrap();
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.size_hint(), (3, Some(3)));
+        iter.next().unwrap();
+        assert_eq!(iter.len(), 2);
+        assert_eq!(iter.by_ref().count(), 2);
+        assert_eq!(iter.len(), 0);
+
+        // The available ring index wraps around.
+        q.set_next_avail(u16::MAX - 1);
+        vq.avail.idx().store(3);
+        let mut iter = q.iter().unwrap();
+        assert_eq!(iter.len(), 5);
+        assert_eq!(iter.size_hint(), (5, Some(5)));
+        assert_eq!(iter.by_ref().count(), 5);
+        assert_eq!(iter.size_hint(), (0, Some(0)));
+
+        // The iteration stops if the available ring can't be read.
+        q.set_next_avail(0);
+        q.avail_ring = GuestAddress(0x1_0000 - 4);
+        m.write_obj(2u16, GuestAddress(0x1_0000 - 2)).unwrap();
+        let mut iter = q.iter().unwrap();
+        assert_eq!(iter.len(), 2);
+        assert!(iter.next().is_none());
+        assert_eq!(iter.len(), 0);
+    }
+
+    #[test]
+    fn test_undo() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let spec = ChainDescSpec {
+            len: 0x100,
+            writable: false,
+            indirect: false,
+        };
+        let heads: Vec<u16> = (0..4).map(|_| build_chain(&vq, &[spec])).collect();
+
+        assert_eq!(q.iter().unwrap().count(), 4);
+        q.add_used(heads[0], 0).unwrap();
+
+        // The last two chains are given back, and returned again in the same order.
+        q.undo(2);
+        assert_eq!(q.next_avail(), 2);
+        let c: Vec<u16> = q.iter().unwrap().map(|c| c.head_index()).collect();
+        assert_eq!(c, heads[2..]);
+
+        // Undoing all the chains in flight is fine.
+        q.undo(3);
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), heads[1]);
+        q.undo(0);
+        assert_eq!(q.next_avail(), 2);
+    }
+
+    #[test]
+    fn test_undo_in_flight() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        let heads: Vec<u16> = (0..4)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+
+        // The chains in flight are counted regardless of the position in the used ring, which
+        // the device can move on its own.
+        q.set_next_used(10);
+        assert_eq!(q.iter().unwrap().take(3).count(), 3);
+        assert_eq!(q.in_flight, 3);
+        q.add_used(heads[0], 0).unwrap();
+        assert_eq!(q.in_flight, 2);
+        q.undo(2);
+        assert_eq!(q.in_flight, 0);
+        assert_eq!(q.next_avail(), 1);
+
+        // With VIRTIO_F_IN_ORDER, a batch completes all the chains it covers.
+        let mut q = vq.create_queue(m);
+        q.set_in_order(true);
+        assert_eq!(q.iter().unwrap().count(), 4);
+        q.add_used_batch(&[(heads[0], 0), (heads[1], 0), (heads[2], 0)])
+            .unwrap();
+        assert_eq!(q.in_flight, 1);
+        q.undo(1);
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), heads[3]);
+
+        // Peeking doesn't consume anything, while failed additions don't complete anything.
+        q.peek().unwrap();
+        assert!(q.add_used(16, 0).is_err());
+        assert_eq!(q.in_flight, 1);
+    }
+
+    #[test]
+    fn test_requeue_front() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        match q.requeue_front() {
+            Err(Error::NoChainInFlight) => (),
+            _ => panic!("expected a no chain in flight error"),
+        }
+
+        let heads: Vec<u16> = (0..3)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), heads[0]);
+        q.requeue_front().unwrap();
+        assert_eq!(q.next_avail(), 0);
+
+        let c: Vec<u16> = q.iter().unwrap().map(|c| c.head_index()).collect();
+        assert_eq!(c, heads);
+
+        // Chains added to the used ring can't be requeued.
+        for head in heads.iter() {
+            q.add_used(*head, 0).unwrap();
+        }
+        match q.requeue_front() {
+            Err(Error::NoChainInFlight) => (),
+            _ => panic!("expected a no chain in flight error"),
+        }
+        assert_eq!(q.next_avail(), 3);
+
+        // The chains in flight don't depend on the position in the used ring.
+        q.set_next_used(0);
+        match q.requeue_front() {
+            Err(Error::NoChainInFlight) => (),
+            _ => panic!("expected a no chain in flight error"),
+        }
+        let head = build_chain(&vq, &[ChainDescSpec::default()]);
+        q.set_next_used(4);
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), head);
+        q.requeue_front().unwrap();
+        assert_eq!(q.next_avail(), 3);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "undoing more chains than the ones in flight")]
+    fn test_undo_too_many() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        let head = q.pop().unwrap().unwrap().head_index();
+        q.add_used(head, 0).unwrap();
+        q.undo(1);
+    }
+
+    #[test]
+    fn test_set_next_used() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        assert_eq!(q.next_used(), 0);
+
+        q.set_next_used(21);
+        assert_eq!(q.next_used(), 21);
+        // Guest memory is not touched.
+        assert_eq!(vq.used.idx().load(), 0);
+
+        // The next element goes to slot 21 % 16.
+        assert_eq!(q.add_used(3, 0x1000).unwrap(), 22);
+        assert_eq!(q.next_used(), 22);
+        assert_eq!(vq.used.idx().load(), 22);
+        let x = vq.used.ring(5).load();
+        assert_eq!(x.id(), 3);
+        assert_eq!(x.len(), 0x1000);
+    }
+
+    #[test]
+    fn test_add_used_batch() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        assert_eq!(vq.used.idx().load(), 0);
+
+        // An out of bounds index anywhere in the batch means nothing gets written.
+        assert!(q.add_used_batch(&[(1, 0x1000), (16, 0x1000)]).is_err());
+        assert_eq!(q.next_used, Wrapping(0));
+        assert_eq!(vq.used.idx().load(), 0);
+        assert_eq!(vq.used.ring(0).load().id(), 0);
+
+        let elems = [(1, 0x1000), (3, 0x2000), (5, 0x3000)];
+        q.add_used_batch(&elems).unwrap();
+        assert_eq!(q.next_used, Wrapping(3));
+        assert_eq!(vq.used.idx().load(), 3);
+        for (i, &(id, len)) in elems.iter().enumerate() {
+            let x = vq.used.ring(i as u16).load();
+            assert_eq!(x.id(), u32::from(id));
+            assert_eq!(x.len(), len);
+        }
+
+        // An empty batch doesn't change anything.
+        q.add_used_batch(&[]).unwrap();
+        assert_eq!(vq.used.idx().load(), 3);
+
+        // Batches larger than the queue are rejected without writing anything, even when
+        // their length doesn't fit in a `u16`.
+        q.set_in_order(true);
+        for len in [17, usize::from(u16::MAX) + 2].iter() {
+            match q.add_used_batch(&vec![(1, 0x1000); *len]) {
+                Err(Error::UsedRingOverflow) => (),
+                _ => panic!("expected a used ring overflow error"),
+            }
+            assert_eq!(q.next_used, Wrapping(3));
+            assert_eq!(vq.used.idx().load(), 3);
+        }
+    }
+
+    #[test]
+    fn test_avail_idx() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // The `idx` fields of the rings hold different values, and only the one of the
+        // available ring is returned.
+        vq.avail.idx().store(5);
+        vq.used.idx().store(2);
+        assert_eq!(q.avail_idx(Ordering::Acquire).unwrap(), Wrapping(5));
+
+        q.add_used_batch(&[(1, 0x1000), (3, 0x1000)]).unwrap();
+        assert_eq!(vq.used.idx().load(), 2);
+        assert_eq!(q.avail_idx(Ordering::Relaxed).unwrap(), Wrapping(5));
+        vq.avail.idx().store(7);
+        assert_eq!(q.avail_idx(Ordering::Relaxed).unwrap(), Wrapping(7));
+    }
+
+    #[test]
+    fn test_in_order() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // The heads 3, 5, 7 and 9 are made available, in this order.
+        for (i, &head) in [3u16, 5, 7, 9].iter().enumerate() {
+            vq.avail.ring(i as u16).store(head);
+        }
+        vq.avail.idx().store(4);
+
+        assert!(!q.in_order());
+        q.set_in_order(true);
+        assert!(q.in_order());
+
+        // Out of order publications are rejected without writing anything.
+        match q.add_used(5, 0x100) {
+            Err(Error::UsedOutOfOrder) => (),
+            _ => panic!("expected an out of order error"),
+        }
+        match q.add_used_batch(&[(3, 0x100), (7, 0x100)]) {
+            Err(Error::UsedOutOfOrder) => (),
+            _ => panic!("expected an out of order error"),
+        }
+        assert_eq!(q.next_used, Wrapping(0));
+        assert_eq!(vq.used.idx().load(), 0);
+
+        assert_eq!(q.add_used(3, 0x100).unwrap(), 1);
+        assert_eq!(vq.used.ring(0).load().id(), 3);
+
+        // Only the last element of an in order batch is written to the used ring.
+        q.add_used_batch(&[(5, 0x200), (7, 0x300), (9, 0x400)])
+            .unwrap();
+        assert_eq!(vq.used.idx().load(), 4);
+        assert_eq!(vq.used.ring(1).load().id(), 0);
+        assert_eq!(vq.used.ring(2).load().id(), 0);
+        let x = vq.used.ring(3).load();
+        assert_eq!(x.id(), 9);
+        assert_eq!(x.len(), 0x400);
+
+        // Any order is accepted when the feature is disabled.
+        q.set_in_order(false);
+        q.add_used_batch(&[(9, 0x100), (3, 0x100)]).unwrap();
+        assert_eq!(vq.used.idx().load(), 6);
+        assert_eq!(vq.used.ring(4).load().id(), 9);
+        assert_eq!(vq.used.ring(5).load().id(), 3);
+
+        q.set_in_order(true);
+        q.reset();
+        assert!(!q.in_order());
+    }
+
+    #[test]
+    fn test_reset_queue() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue(m);
+        q.size = 8;
+        q.ready = true;
+        q.reset();
+        assert_eq!(q.size, 16);
+        assert_eq!(q.ready, false);
+    }
+
+    #[test]
+    fn test_reset_addresses() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
 
-    /// Return the value present in the used_event field of the avail ring.
-    ///
-    /// If the VIRTIO_F_EVENT_IDX feature bit is not negotiated, the flags field in the available
-    /// ring offers a crude mechanism for the driver to inform the device that it doesn’t want
-    /// interrupts when buffers are used. Otherwise virtq_avail.used_event is a more performant
-    /// alternative where the driver specifies how far the device can progress before interrupting.
-    ///
-    /// Neither of these interrupt suppression methods are reliable, as they are not synchronized
-    /// with the device, but they serve as useful optimizations. So we only ensure access to the
-    /// virtq_avail.used_event is atomic, but do not need to synchronize with other memory accesses.
-    fn used_event(&self, order: Ordering) -> Result<Wrapping<u16>, Error> {
-        // Safe because we have validated the queue and access guest memory through GuestMemory
-        // interfaces.
-        let mem = self.mem.memory();
-        let used_event_addr = self
-            .avail_ring
-            .unchecked_add((4 + self.actual_size() * 2) as u64);
+        let mut q = vq.create_queue(m);
+        q.size = 8;
+        q.set_event_idx(true);
+        q.set_in_order(true);
+        q.next_avail = Wrapping(3);
+        q.next_used = Wrapping(2);
+        q.signalled_used = Some(Wrapping(1));
 
-        mem.load(used_event_addr, order)
-            .map(Wrapping)
-            .map_err(Error::GuestMemory)
+        q.reset_addresses();
+        assert_eq!(q.size, 16);
+        assert!(!q.ready);
+        assert_eq!(q.desc_table, GuestAddress(0));
+        assert_eq!(q.avail_ring, GuestAddress(0));
+        assert_eq!(q.used_ring, GuestAddress(0));
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.next_used(), 0);
+        // The negotiated features survive the partial reset.
+        assert!(q.features.has_event_idx());
+        assert!(q.in_order());
+        assert_eq!(q.signalled_used, Some(Wrapping(1)));
+
+        q.reset();
+        assert!(!q.features.has_event_idx());
+        assert!(!q.in_order());
+        assert_eq!(q.signalled_used, None);
     }
 
-    /// Check whether a notification to the guest is needed.
-    ///
-    /// Please note this method has side effects: once it returns `true`, it considers the
-    /// driver will actually be notified, remember the associated index in the used ring, and
-    /// won't return `true` again until the driver updates `used_event` and/or the notification
-    /// conditions hold once more.
-    pub fn needs_notification(&mut self) -> Result<bool, Error> {
-        let used_idx = self.next_used;
+    #[test]
+    fn test_memory_ordering() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert_eq!(q.memory_ordering(), MemoryOrdering::StrictAcqRel);
 
-        // Complete all the writes in add_used() before reading the event.
-        fence(Ordering::SeqCst);
+        assert_eq!(MemoryOrdering::StrictAcqRel.load_order(), Ordering::Acquire);
+        assert_eq!(
+            MemoryOrdering::StrictAcqRel.store_order(),
+            Ordering::Release
+        );
+        assert_eq!(
+            MemoryOrdering::FencedRelaxed.load_order(),
+            Ordering::Relaxed
+        );
+        assert_eq!(
+            MemoryOrdering::FencedRelaxed.store_order(),
+            Ordering::Relaxed
+        );
 
-        // The VRING_AVAIL_F_NO_INTERRUPT flag isn't supported yet.
-        if self.event_idx_enabled {
-            if let Some(old_idx) = self.signalled_used.replace(used_idx) {
-                let used_event = self.used_event(Ordering::Relaxed)?;
-                // This check looks at `used_idx`, `used_event`, and `old_idx` as if they are on
-                // an axis that wraps around. If `used_idx - used_used - Wrapping(1)` is greater
-                // than or equal to the difference between `used_idx` and `old_idx`, then
-                // `old_idx` is closer to `used_idx` than `used_event` (and thus more recent), so
-                // we don't need to elicit another notification.
-                if (used_idx - used_event - Wrapping(1u16)) >= (used_idx - old_idx) {
-                    return Ok(false);
-                }
-            }
+        for &policy in [MemoryOrdering::FencedRelaxed, MemoryOrdering::StrictAcqRel].iter() {
+            q.set_memory_ordering(policy);
+            assert_eq!(q.memory_ordering(), policy);
+
+            let head = build_chain(&vq, &[ChainDescSpec::default()]);
+            let chain = q.iter().unwrap().next().unwrap();
+            assert_eq!(chain.head_index(), head);
+            let next_used = q.add_used(head, 0x100).unwrap();
+            assert_eq!(vq.used.idx().load(), next_used);
+
+            let (avail_ring, mut used_ring) = q.split();
+            assert_eq!(used_ring.state.memory_ordering, policy);
+            used_ring.add_used(head, 0x100).unwrap();
+            assert_eq!(vq.used.idx().load(), next_used + 1);
+            q = avail_ring.join(used_ring);
+            assert_eq!(q.next_used(), next_used + 1);
         }
 
-        Ok(true)
+        // With VIRTIO_F_ORDER_PLATFORM, the accesses use `SeqCst` regardless of the policy.
+        q.set_order_platform(true);
+        q.set_memory_ordering(MemoryOrdering::FencedRelaxed);
+        assert_eq!(
+            q.ring_order(q.memory_ordering().load_order()),
+            Ordering::SeqCst
+        );
+        q.reset();
+        assert_eq!(q.memory_ordering(), MemoryOrdering::FencedRelaxed);
     }
 
-    /// Goes back one position in the available descriptor chain offered by the driver.
-    /// Rust does not support bidirectional iterators. This is the only way to revert the effect
-    /// of an iterator increment on the queue.
-    pub fn go_to_previous_position(&mut self) {
-        self.next_avail -= Wrapping(1);
-    }
+    #[test]
+    fn test_order_platform() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-    /// Returns the index for the next descriptor in the available ring.
-    pub fn next_avail(&self) -> u16 {
-        self.next_avail.0
-    }
+        let orderings = [
+            Ordering::Relaxed,
+            Ordering::Acquire,
+            Ordering::Release,
+            Ordering::SeqCst,
+        ];
 
-    /// Sets the index for the next descriptor in the available ring.
-    pub fn set_next_avail(&mut self, next_avail: u16) {
-        self.next_avail = Wrapping(next_avail);
-    }
-}
+        assert!(!q.order_platform());
+        for &order in orderings.iter() {
+            assert_eq!(q.ring_order(order), order);
+        }
 
-#[allow(missing_docs)]
-#[cfg(feature = "test-utils")]
-pub mod test_utils {
-    use super::*;
+        q.set_order_platform(true);
+        assert!(q.order_platform());
+        for &order in orderings.iter() {
+            assert_eq!(q.ring_order(order), Ordering::SeqCst);
+        }
 
-    use std::marker::PhantomData;
-    use std::mem;
+        // The ring accesses still work as expected with the stronger orderings.
+        vq.avail.idx().store(3);
+        assert_eq!(q.avail_idx(Ordering::Relaxed).unwrap(), Wrapping(3));
+        assert_eq!(q.add_used(1, 0x1000).unwrap(), 1);
+        assert_eq!(vq.used.idx().load(), 1);
+        q.disable_notification().unwrap();
+        assert_eq!(vq.used.flags().load(), VIRTQ_USED_F_NO_NOTIFY);
 
-    use vm_memory::{
-        GuestAddress, GuestMemoryMmap, GuestMemoryRegion, GuestUsize, VolatileMemory, VolatileRef,
-        VolatileSlice,
-    };
+        q.reset();
+        assert!(!q.order_platform());
+    }
 
-    impl Descriptor {
-        // Only available to unit tests within the local crate.
-        pub fn new(addr: u64, len: u32, flags: u16, next: u16) -> Self {
-            Descriptor {
-                addr,
-                len,
-                flags,
-                next,
+    #[test]
+    fn test_ring_index_wrapping_distance() {
+        use super::ring_index::wrapping_distance;
+
+        assert_eq!(wrapping_distance(Wrapping(0), Wrapping(0)), 0);
+        assert_eq!(wrapping_distance(Wrapping(0xffff), Wrapping(0)), 1);
+        assert_eq!(wrapping_distance(Wrapping(0xfffe), Wrapping(2)), 4);
+        assert_eq!(wrapping_distance(Wrapping(0), Wrapping(0xffff)), 0xffff);
+        assert_eq!(wrapping_distance(Wrapping(1), Wrapping(0)), 0xffff);
+
+        for from in 0..=u16::MAX {
+            for dist in [0u16, 1, 2, 0x100, 0x8000, 0xffff] {
+                let to = Wrapping(from) + Wrapping(dist);
+                assert_eq!(wrapping_distance(Wrapping(from), to), dist);
             }
         }
     }
 
-    // Represents a virtio descriptor in guest memory.
-    pub struct VirtqDesc<'a> {
-        desc: VolatileSlice<'a>,
-    }
-
-    /// Extracts the displacement of a field in a struct
-    #[macro_export]
-    macro_rules! offset_of {
-        ($ty:ty, $field:ident) => {
-            unsafe { &(*std::ptr::null::<$ty>()).$field as *const _ as usize }
-        };
-    }
+    #[test]
+    fn test_ring_index_is_behind() {
+        use super::ring_index::is_behind;
 
-    #[allow(clippy::len_without_is_empty)]
-    impl<'a> VirtqDesc<'a> {
-        pub fn new(dtable: &'a VolatileSlice<'a>, i: u16) -> Self {
-            let desc = dtable
-                .get_slice((i as usize) * Self::dtable_len(1), Self::dtable_len(1))
-                .unwrap();
-            VirtqDesc { desc }
+        // Moving from 0xfffe to 2 crosses 0xfffe, 0xffff, 0 and 1, but not 2.
+        let (old, idx) = (Wrapping(0xfffe), Wrapping(2));
+        for event in [0xfffe, 0xffff, 0, 1] {
+            assert!(is_behind(idx, Wrapping(event), old));
+        }
+        for event in [0xfffd, 2, 3] {
+            assert!(!is_behind(idx, Wrapping(event), old));
         }
 
-        pub fn addr(&self) -> VolatileRef<u64> {
-            self.desc.get_ref(offset_of!(Descriptor, addr)).unwrap()
+        // Check every starting index, so all the transitions across the wrap are covered.
+        for start in 0..=u16::MAX {
+            let old = Wrapping(start);
+            for dist in [0u16, 1, 2, 16, 0x8000] {
+                let idx = old + Wrapping(dist);
+                // The indices in `[old, idx)` were crossed, and the ones around it were not (so
+                // an index that didn't move doesn't cross anything).
+                if dist != 0 {
+                    assert!(is_behind(idx, old, old));
+                    assert!(is_behind(idx, idx - Wrapping(1), old));
+                }
+                assert!(!is_behind(idx, idx, old));
+                assert!(!is_behind(idx, old - Wrapping(1), old));
+            }
         }
+    }
 
-        pub fn len(&self) -> VolatileRef<u32> {
-            self.desc.get_ref(offset_of!(Descriptor, len)).unwrap()
+    #[test]
+    fn test_queue_features() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // Only indirect descriptors are accepted by default.
+        let default = q.features();
+        assert_eq!(default.bits(), QueueFeatures::INDIRECT_DESC);
+        assert!(default.has_indirect());
+        assert!(!default.has_event_idx());
+        assert!(!default.has_in_order());
+        assert!(!default.has_order_platform());
+
+        // The feature bits that don't affect the queue are ignored.
+        q.set_features(
+            1 << VIRTIO_F_RING_EVENT_IDX
+                | 1 << VIRTIO_F_IN_ORDER
+                | 1 << VIRTIO_F_ORDER_PLATFORM
+                | 1 << 32
+                | 1,
+        );
+        let features = q.features();
+        assert_eq!(
+            features,
+            QueueFeatures::from_bits(
+                QueueFeatures::RING_EVENT_IDX
+                    | QueueFeatures::IN_ORDER
+                    | QueueFeatures::ORDER_PLATFORM
+            )
+        );
+        assert!(features.has_event_idx());
+        assert!(features.has_in_order() && q.in_order());
+        assert!(features.has_order_platform() && q.order_platform());
+        assert!(!features.has_indirect());
+
+        // Head 0 refers to an indirect table, which is rejected without VIRTIO_F_INDIRECT_DESC.
+        let table = GuestAddress(0x2000);
+        m.write_obj(Descriptor::new(0x4000, 0x10, 0, 0), table)
+            .unwrap();
+        vq.dtable(0).set(table.0, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        vq.dtable(1).set(0x5000, 0x10, 0, 0);
+        vq.avail.ring(0).store(0);
+        vq.avail.ring(1).store(1);
+        vq.avail.idx().store(2);
+        let mut c = q.pop().unwrap().unwrap();
+        match c.next_descriptor() {
+            Some(Err(Error::InvalidIndirectDescriptor)) => (),
+            _ => panic!("expected an invalid indirect descriptor error"),
         }
 
-        pub fn flags(&self) -> VolatileRef<u16> {
-            self.desc.get_ref(offset_of!(Descriptor, flags)).unwrap()
+        // With VIRTIO_F_IN_ORDER, the heads must be used in order.
+        match q.add_used(1, 0x10) {
+            Err(Error::UsedOutOfOrder) => (),
+            _ => panic!("expected an out of order error"),
         }
+        q.add_used(0, 0).unwrap();
+        // With VIRTIO_F_RING_EVENT_IDX, the first check always notifies, and then `used_event`
+        // suppresses the notifications.
+        assert!(q.needs_notification().unwrap());
+        vq.avail.event().store(4);
+
+        // Indirect descriptors are accepted once the feature is negotiated.
+        q.set_features(1 << VIRTIO_F_INDIRECT_DESC | 1 << VIRTIO_F_RING_EVENT_IDX);
+        assert_eq!(
+            q.features().bits(),
+            QueueFeatures::INDIRECT_DESC | QueueFeatures::RING_EVENT_IDX
+        );
+        assert!(!q.in_order());
+        q.next_avail = Wrapping(0);
+        let mut c = q.pop().unwrap().unwrap();
+        assert_eq!(
+            c.next_descriptor().unwrap().unwrap().addr(),
+            GuestAddress(0x4000)
+        );
+        // Keeping VIRTIO_F_RING_EVENT_IDX enabled keeps the notification state.
+        q.add_used(1, 0x10).unwrap();
+        assert!(!q.needs_notification().unwrap());
 
-        pub fn next(&self) -> VolatileRef<u16> {
-            self.desc.get_ref(offset_of!(Descriptor, next)).unwrap()
+        // Without VIRTIO_F_RING_EVENT_IDX, every used element notifies the driver.
+        q.set_features(0);
+        q.add_used(0, 0x10).unwrap();
+        assert!(q.needs_notification().unwrap());
+        assert!(q.needs_notification().unwrap());
+
+        q.reset();
+        assert_eq!(q.features(), default);
+    }
+
+    #[test]
+    fn test_notification_coalescing() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        assert_eq!(q.notification_coalescing(), 0);
+        q.set_notification_coalescing(3);
+        assert_eq!(q.notification_coalescing(), 3);
+
+        // Without EVENT_IDX, the driver is notified once for every 3 used elements.
+        for i in 0..6 {
+            q.add_used(i, 0x10).unwrap();
+            assert_eq!(q.would_notify().unwrap(), i % 3 == 2);
+            assert_eq!(q.needs_notification().unwrap(), i % 3 == 2);
+        }
+        // Reaching the threshold doesn't override the flags of the available ring.
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        for i in 6..9 {
+            q.add_used(i, 0x10).unwrap();
+            assert!(!q.needs_notification().unwrap());
         }
+        vq.avail.flags().store(0);
 
-        pub fn set(&self, addr: u64, len: u32, flags: u16, next: u16) {
-            self.addr().store(addr);
-            self.len().store(len);
-            self.flags().store(flags);
-            self.next().store(next);
+        // With EVENT_IDX, the first notification happens when the threshold is reached.
+        q.set_next_used(3);
+        q.set_event_idx(true);
+        q.add_used(0, 0x10).unwrap();
+        assert!(!q.needs_notification().unwrap());
+        q.add_used(1, 0x10).unwrap();
+        q.add_used(2, 0x10).unwrap();
+        assert!(q.needs_notification().unwrap());
+
+        // `used_event` (6) is crossed by the first withheld element, and the driver is
+        // notified once the threshold is reached.
+        vq.avail.event().store(6);
+        for i in 3..5 {
+            q.add_used(i, 0x10).unwrap();
+            assert!(!q.needs_notification().unwrap());
         }
+        q.add_used(5, 0x10).unwrap();
+        assert!(q.needs_notification().unwrap());
 
-        pub fn dtable_len(nelem: u16) -> usize {
-            16 * nelem as usize
+        // The threshold alone doesn't notify if `used_event` isn't crossed.
+        for i in 6..9 {
+            q.add_used(i, 0x10).unwrap();
         }
+        assert!(!q.needs_notification().unwrap());
+        assert_eq!(q.next_used(), 12);
+        vq.avail.event().store(12);
+        q.add_used(9, 0x10).unwrap();
+        assert!(q.needs_notification().unwrap());
+
+        // Disabling the coalescing lets a device flush a withheld notification, for example
+        // on a timeout.
+        vq.avail.event().store(13);
+        q.add_used(10, 0x10).unwrap();
+        assert!(!q.needs_notification().unwrap());
+        q.set_notification_coalescing(0);
+        assert!(q.needs_notification().unwrap());
+
+        q.set_notification_coalescing(3);
+        q.reset();
+        assert_eq!(q.notification_coalescing(), 3);
     }
 
-    // Represents a virtio queue ring. The only difference between the used and available rings,
-    // is the ring element type.
-    pub struct VirtqRing<'a, T> {
-        ring: VolatileSlice<'a>,
-        start: GuestAddress,
-        qsize: u16,
-        _marker: PhantomData<*const T>,
+    #[test]
+    fn test_add_used_and_check_notify() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+
+        // Without EVENT_IDX, the driver is notified unless it sets VIRTQ_AVAIL_F_NO_INTERRUPT.
+        assert!(q.add_used_and_check_notify(0, 0x10).unwrap());
+        assert_eq!(vq.used.idx().load(), 1);
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert!(!q.add_used_and_check_notify(1, 0x10).unwrap());
+        assert_eq!(vq.used.idx().load(), 2);
+        assert_eq!(vq.used.ring(1).load().id(), 1);
+        vq.avail.flags().store(0);
+
+        // With EVENT_IDX, the driver is notified when `used_event` is crossed, and on the
+        // first check after enabling it.
+        q.set_event_idx(true);
+        vq.avail.event().store(4);
+        assert!(q.add_used_and_check_notify(2, 0x10).unwrap());
+        assert!(!q.add_used_and_check_notify(3, 0x10).unwrap());
+        assert!(q.add_used_and_check_notify(4, 0x10).unwrap());
+        assert!(!q.add_used_and_check_notify(5, 0x10).unwrap());
+        assert_eq!(vq.used.idx().load(), 6);
+
+        // Nothing is published when adding the element fails.
+        assert!(q.add_used_and_check_notify(16, 0x10).is_err());
+        assert_eq!(vq.used.idx().load(), 6);
     }
 
-    impl<'a, T> VirtqRing<'a, T>
-    where
-        T: vm_memory::ByteValued,
-    {
-        fn new(
-            start: GuestAddress,
-            mem: &'a GuestMemoryMmap,
-            qsize: u16,
-            alignment: GuestUsize,
-        ) -> Self {
-            assert_eq!(start.0 & (alignment - 1), 0);
+    #[test]
+    fn test_needs_notification() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let qsize = 16;
+        let vq = VirtQueue::new(GuestAddress(0), m, qsize);
+        let mut q = vq.create_queue(&m);
+        let avail_addr = vq.avail_start();
 
-            let (region, addr) = mem.to_region_addr(start).unwrap();
-            let size = Self::ring_len(qsize);
-            let ring = region.get_slice(addr, size).unwrap();
+        // It should always return true when EVENT_IDX isn't enabled.
+        for i in 0..qsize {
+            q.next_used = Wrapping(i);
+            assert_eq!(q.needs_notification().unwrap(), true);
+        }
 
-            let result = VirtqRing {
-                ring,
-                start,
-                qsize,
-                _marker: PhantomData,
-            };
+        m.write_obj::<u16>(4, avail_addr.unchecked_add(4 + qsize as u64 * 2))
+            .unwrap();
+        q.set_event_idx(true);
 
-            result.flags().store(0);
-            result.idx().store(0);
-            result.event().store(0);
-            result
-        }
+        // Incrementing up to this value causes an `u16` to wrap back to 0.
+        let wrap = u32::from(u16::MAX) + 1;
 
-        pub fn start(&self) -> GuestAddress {
-            self.start
+        for i in 0..wrap + 12 {
+            q.next_used = Wrapping(i as u16);
+            // Let's test wrapping around the maximum index value as well.
+            let expected = i == 5 || i == (5 + wrap) || q.signalled_used.is_none();
+            assert_eq!(q.needs_notification().unwrap(), expected);
         }
 
-        pub fn end(&self) -> GuestAddress {
-            self.start.unchecked_add(self.ring.len() as GuestUsize)
-        }
+        m.write_obj::<u16>(8, avail_addr.unchecked_add(4 + 16 * 2))
+            .unwrap();
 
-        pub fn flags(&self) -> VolatileRef<u16> {
-            self.ring.get_ref(0).unwrap()
-        }
+        // Returns `false` because `signalled_used` already passed this value.
+        assert_eq!(q.needs_notification().unwrap(), false);
 
-        pub fn idx(&self) -> VolatileRef<u16> {
-            self.ring.get_ref(2).unwrap()
-        }
+        m.write_obj::<u16>(15, avail_addr.unchecked_add(4 + 16 * 2))
+            .unwrap();
+        assert_eq!(q.needs_notification().unwrap(), false);
+        q.next_used = Wrapping(15);
+        assert_eq!(q.needs_notification().unwrap(), false);
+        q.next_used = Wrapping(0);
+        assert_eq!(q.needs_notification().unwrap(), true);
+        assert_eq!(q.needs_notification().unwrap(), false);
+    }
 
-        fn ring_offset(i: u16) -> usize {
-            4 + mem::size_of::<T>() * (i as usize)
-        }
+    #[test]
+    fn test_queue_setters() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = Queue::new(m, 16);
+
+        q.set_size(8).unwrap();
+        assert_eq!(q.size, 8);
+        // Invalid sizes are rejected.
+        assert_eq!(q.set_size(0), Err(QueueError::InvalidSize));
+        assert_eq!(q.set_size(32), Err(QueueError::InvalidSize));
+        assert_eq!(q.set_size(7), Err(QueueError::InvalidSize));
+        assert_eq!(q.size, 8);
+
+        q.set_desc_table_address(Some(0x1000), Some(0x2)).unwrap();
+        assert_eq!(q.desc_table, GuestAddress(0x2_0000_1000));
+        // A single half can be updated as well.
+        q.set_desc_table_address(Some(0xabcd_0000), None).unwrap();
+        assert_eq!(q.desc_table, GuestAddress(0x2_abcd_0000));
+        q.set_desc_table_address(None, Some(0x1)).unwrap();
+        assert_eq!(q.desc_table, GuestAddress(0x1_abcd_0000));
+        // Misaligned addresses are rejected.
+        assert_eq!(
+            q.set_desc_table_address(Some(0x1008), None),
+            Err(QueueError::DescTableMisaligned)
+        );
+        assert_eq!(q.desc_table, GuestAddress(0x1_abcd_0000));
 
-        pub fn ring(&self, i: u16) -> VolatileRef<T> {
-            assert!(i < self.qsize);
-            self.ring.get_ref(Self::ring_offset(i)).unwrap()
+        q.set_avail_ring_address(Some(0x2002), Some(0x3)).unwrap();
+        assert_eq!(q.avail_ring, GuestAddress(0x3_0000_2002));
+        assert_eq!(
+            q.set_avail_ring_address(Some(0x2001), None),
+            Err(QueueError::AvailRingMisaligned)
+        );
+        assert_eq!(q.avail_ring, GuestAddress(0x3_0000_2002));
+
+        q.set_used_ring_address(Some(0x3004), Some(0x4)).unwrap();
+        assert_eq!(q.used_ring, GuestAddress(0x4_0000_3004));
+        assert_eq!(
+            q.set_used_ring_address(Some(0x3002), None),
+            Err(QueueError::UsedRingMisaligned)
+        );
+        assert_eq!(q.used_ring, GuestAddress(0x4_0000_3004));
+    }
+
+    #[test]
+    fn test_set_ring_addresses_64bit() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = Queue::new(m, 16);
+
+        q.set_desc_table(GuestAddress(0x1_0000_1010)).unwrap();
+        assert_eq!(q.desc_table, GuestAddress(0x1_0000_1010));
+        q.set_avail_ring(GuestAddress(0x2_0000_2002)).unwrap();
+        assert_eq!(q.avail_ring, GuestAddress(0x2_0000_2002));
+        q.set_used_ring(GuestAddress(0x3_0000_3004)).unwrap();
+        assert_eq!(q.used_ring, GuestAddress(0x3_0000_3004));
+
+        // Misaligned addresses are rejected, and the current ones are kept.
+        for addr in [0x1_0000_1018, 0x1_0000_1001] {
+            match q.set_desc_table(GuestAddress(addr)) {
+                Err(QueueError::DescTableMisaligned) => (),
+                _ => panic!("expected a misaligned descriptor table error"),
+            }
+        }
+        assert_eq!(q.desc_table, GuestAddress(0x1_0000_1010));
+        match q.set_avail_ring(GuestAddress(0x2_0000_2003)) {
+            Err(QueueError::AvailRingMisaligned) => (),
+            _ => panic!("expected a misaligned available ring error"),
         }
+        assert_eq!(q.avail_ring, GuestAddress(0x2_0000_2002));
+        for addr in [0x3_0000_3002, 0x3_0000_3001] {
+            match q.set_used_ring(GuestAddress(addr)) {
+                Err(QueueError::UsedRingMisaligned) => (),
+                _ => panic!("expected a misaligned used ring error"),
+            }
+        }
+        assert_eq!(q.used_ring, GuestAddress(0x3_0000_3004));
+    }
 
-        pub fn event(&self) -> VolatileRef<u16> {
-            self.ring.get_ref(Self::ring_offset(self.qsize)).unwrap()
+    #[test]
+    fn test_from_parts() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        q.set_event_idx(true);
+
+        for _ in 0..3 {
+            build_chain(&vq, &[ChainDescSpec::default()]);
+        }
+        let heads: Vec<u16> = q.iter().unwrap().map(|c| c.head_index()).collect();
+        q.add_used(heads[0], 0x100).unwrap();
+
+        let restored = Queue::from_parts(
+            m,
+            q.max_size(),
+            q.size,
+            q.ready,
+            q.desc_table,
+            q.avail_ring,
+            q.used_ring,
+            q.next_avail(),
+            q.next_used(),
+            q.features.has_event_idx(),
+        )
+        .unwrap();
+        assert_eq!(restored.max_size(), q.max_size());
+        assert_eq!(restored.actual_size(), q.actual_size());
+        assert!(restored.is_valid());
+        assert_eq!(restored.dump().unwrap(), q.dump().unwrap());
+
+        // The restored queue picks up where the original one left off.
+        let mut restored = restored;
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        assert_eq!(restored.iter().unwrap().count(), 1);
+        assert_eq!(restored.add_used(heads[1], 0x100).unwrap(), 2);
+        assert_eq!(vq.used.idx().load(), 2);
+
+        match Queue::from_parts(
+            m,
+            16,
+            12,
+            true,
+            q.desc_table,
+            q.avail_ring,
+            q.used_ring,
+            0,
+            0,
+            false,
+        ) {
+            Err(QueueError::InvalidSize) => (),
+            _ => panic!("expected an invalid size error"),
         }
-
-        fn ring_len(qsize: u16) -> usize {
-            Self::ring_offset(qsize) + 2
+        match Queue::from_parts(
+            m,
+            24,
+            8,
+            true,
+            q.desc_table,
+            q.avail_ring,
+            q.used_ring,
+            0,
+            0,
+            false,
+        ) {
+            Err(QueueError::InvalidMaxSize) => (),
+            _ => panic!("expected an invalid maximum size error"),
         }
     }
 
-    pub type VirtqAvail<'a> = VirtqRing<'a, u16>;
-    pub type VirtqUsed<'a> = VirtqRing<'a, VirtqUsedElem>;
+    #[test]
+    fn test_from_registers() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let regs = QueueRegisters {
+            size: 8,
+            ready: 1,
+            desc_table_lo: 0x1000,
+            desc_table_hi: 0x1,
+            avail_ring_lo: 0x2002,
+            avail_ring_hi: 0x2,
+            used_ring_lo: 0x3004,
+            used_ring_hi: 0x3,
+        };
 
-    trait GuestAddressExt {
-        fn align_up(&self, x: GuestUsize) -> GuestAddress;
-    }
-    impl GuestAddressExt for GuestAddress {
-        fn align_up(&self, x: GuestUsize) -> GuestAddress {
-            Self((self.0 + (x - 1)) & !(x - 1))
-        }
+        let q = Queue::from_registers(m, 16, regs);
+        assert_eq!(q.max_size(), 16);
+        assert_eq!(q.size, 8);
+        assert!(q.ready);
+        assert_eq!(q.desc_table, GuestAddress(0x1_0000_1000));
+        assert_eq!(q.avail_ring, GuestAddress(0x2_0000_2002));
+        assert_eq!(q.used_ring, GuestAddress(0x3_0000_3004));
+
+        // Invalid values are ignored.
+        let regs = QueueRegisters {
+            size: 32,
+            ready: 2,
+            desc_table_lo: 0x1001,
+            ..regs
+        };
+        let q = Queue::from_registers(m, 16, regs);
+        assert_eq!(q.size, 16);
+        assert!(!q.ready);
+        assert_eq!(q.desc_table, GuestAddress(0));
+        assert_eq!(q.avail_ring, GuestAddress(0x2_0000_2002));
     }
 
-    pub struct VirtQueue<'a> {
-        start: GuestAddress,
-        dtable: VolatileSlice<'a>,
-        pub avail: VirtqAvail<'a>,
-        pub used: VirtqUsed<'a>,
+    #[test]
+    fn test_ring_addresses() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let q = vq.create_queue(m);
+
+        let addrs = q.ring_addresses();
+        assert_eq!(addrs.size, 16);
+        assert_eq!(addrs.desc_table, vq.dtable_start());
+        assert_eq!(addrs.avail_ring, vq.avail_start());
+        assert_eq!(addrs.used_ring, vq.used_start());
+
+        let mut q2 = Queue::new(m, 16);
+        q2.set_ring_addresses(addrs);
+        assert_eq!(q2.size, q.size);
+        assert_eq!(q2.desc_table, q.desc_table);
+        assert_eq!(q2.avail_ring, q.avail_ring);
+        assert_eq!(q2.used_ring, q.used_ring);
+        assert_eq!(q2.ring_addresses(), addrs);
     }
 
-    impl<'a> VirtQueue<'a> {
-        // We try to make sure things are aligned properly :-s
-        pub fn new(start: GuestAddress, mem: &'a GuestMemoryMmap, qsize: u16) -> Self {
-            // power of 2?
-            assert!(qsize > 0 && qsize & (qsize - 1) == 0);
-
-            let (region, addr) = mem.to_region_addr(start).unwrap();
-            let dtable = region
-                .get_slice(addr, VirtqDesc::dtable_len(qsize))
-                .unwrap();
+    #[test]
+    fn test_config_hash() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        let mut q2 = vq.create_queue(m);
+        let hash = q.config_hash();
+        assert_eq!(q2.config_hash(), hash);
+
+        // The positions in the rings and the contents of guest memory are not covered.
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        let head = q2.pop().unwrap().unwrap().head_index();
+        q2.add_used(head, 0).unwrap();
+        assert_eq!(q2.config_hash(), hash);
+
+        let changes: [fn(&mut Queue<&GuestMemoryMmap>); 9] = [
+            |q| q.set_size(8).unwrap(),
+            |q| q.ready = false,
+            |q| q.desc_table = GuestAddress(0x1000),
+            |q| q.avail_ring = GuestAddress(0x2000),
+            |q| q.used_ring = GuestAddress(0x3000),
+            |q| q.set_event_idx(true),
+            |q| q.set_order_platform(true),
+            |q| q.set_in_order(true),
+            |q| q.set_features(0),
+        ];
+        for change in changes.iter() {
+            let mut q3 = q.clone();
+            change(&mut q3);
+            assert_ne!(q3.config_hash(), hash);
+        }
 
-            const AVAIL_ALIGN: GuestUsize = 2;
+        // Only the maximum size differs.
+        let mut q3 = Queue::new(m, 32);
+        q3.set_ring_addresses(q.ring_addresses());
+        q3.ready = true;
+        assert_ne!(q3.config_hash(), hash);
 
-            let avail_addr = start
-                .unchecked_add(VirtqDesc::dtable_len(qsize) as GuestUsize)
-                .align_up(AVAIL_ALIGN);
-            let avail = VirtqAvail::new(avail_addr, mem, qsize, AVAIL_ALIGN);
+        // The hash doesn't depend on how the configuration was reached.
+        q.set_event_idx(true);
+        q.set_event_idx(false);
+        assert_eq!(q.config_hash(), hash);
+    }
 
-            const USED_ALIGN: GuestUsize = 4;
+    #[test]
+    fn test_take_last_error() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-            let used_addr = avail.end().align_up(USED_ALIGN);
-            let used = VirtqUsed::new(used_addr, mem, qsize, USED_ALIGN);
+        assert!(q.take_last_error().is_none());
 
-            VirtQueue {
-                start,
-                dtable,
-                avail,
-                used,
-            }
-        }
+        // Move the available ring so that its `idx` field is the last `u16` in guest memory,
+        // and the first ring entry can't be read.
+        q.avail_ring = GuestAddress(0xfffc);
+        m.write_obj::<u16>(1, GuestAddress(0xfffe)).unwrap();
+        assert!(q.iter().unwrap().next().is_none());
+        assert_eq!(q.take_last_error(), Some(ErrorKind::GuestMemory));
+        assert!(q.take_last_error().is_none());
+        q.avail_ring = vq.avail_start();
 
-        pub fn size(&self) -> u16 {
-            (self.dtable.len() / VirtqDesc::dtable_len(1)) as u16
-        }
+        // Errors are both returned and recorded by `add_used`.
+        assert!(q.add_used(16, 0x1000).is_err());
+        assert_eq!(q.take_last_error(), Some(ErrorKind::InvalidDescriptorIndex));
+        assert!(q.take_last_error().is_none());
 
-        pub fn dtable(&self, i: u16) -> VirtqDesc {
-            VirtqDesc::new(&self.dtable, i)
-        }
+        // Successful calls don't record anything, and `reset` clears the error.
+        q.add_used(1, 0x1000).unwrap();
+        assert!(q.take_last_error().is_none());
+        assert!(q.add_used_batch(&[(1, 0x1000), (16, 0x1000)]).is_err());
+        q.reset();
+        assert!(q.take_last_error().is_none());
+    }
 
-        pub fn dtable_start(&self) -> GuestAddress {
-            self.start
-        }
+    #[test]
+    fn test_try_iter() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        pub fn avail_start(&self) -> GuestAddress {
-            self.avail.start()
+        // The available chains are yielded as `Ok`s.
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        build_chain(&vq, &[ChainDescSpec::default()]);
+        let iter = q.try_iter();
+        assert_eq!(iter.size_hint(), (0, Some(2)));
+        let heads: Vec<_> = iter.map(|chain| chain.unwrap().head_index()).collect();
+        assert_eq!(heads, [0, 1]);
+        assert!(q.try_iter().next().is_none());
+
+        // An available ring in unmapped memory produces an error instead of an empty iterator.
+        q.avail_ring = GuestAddress(0x20000);
+        let mut iter = q.try_iter();
+        match iter.next() {
+            Some(Err(Error::GuestMemory(_))) => (),
+            _ => panic!("expected a guest memory error"),
         }
-
-        pub fn used_start(&self) -> GuestAddress {
-            self.used.start()
+        assert!(iter.next().is_none());
+        assert_eq!(q.next_avail(), 2);
+        assert!(q.take_last_error().is_none());
+
+        // The same goes for a head index that can't be read, which stops the iteration without
+        // consuming anything.
+        q.avail_ring = GuestAddress(0xfffc);
+        q.set_next_avail(0);
+        m.write_obj::<u16>(2, GuestAddress(0xfffe)).unwrap();
+        let mut iter = q.try_iter();
+        match iter.next() {
+            Some(Err(Error::GuestMemory(_))) => (),
+            _ => panic!("expected a guest memory error"),
         }
+        assert!(iter.next().is_none());
+        assert_eq!(q.next_avail(), 0);
+        assert!(q.take_last_error().is_none());
 
-        // Creates a new Queue, using the underlying memory regions represented by the VirtQueue.
-        pub fn create_queue(&self, mem: &'a GuestMemoryMmap) -> Queue<&'a GuestMemoryMmap> {
-            let mut q = Queue::new(mem, self.size());
+        // The lenient `iter` still records the error instead.
+        assert!(q.iter().unwrap().next().is_none());
+        assert_eq!(q.take_last_error(), Some(ErrorKind::GuestMemory));
+    }
 
-            q.size = self.size();
-            q.ready = true;
-            q.desc_table = self.dtable_start();
-            q.avail_ring = self.avail_start();
-            q.used_ring = self.used_start();
+    #[test]
+    fn test_iter_with_memory() {
+        use std::sync::Arc;
 
-            q
-        }
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = Queue::from_parts(
+            Arc::new(m.clone()),
+            16,
+            16,
+            true,
+            vq.dtable_start(),
+            vq.avail_start(),
+            vq.used_start(),
+            0,
+            0,
+            false,
+        )
+        .unwrap();
 
-        pub fn start(&self) -> GuestAddress {
-            self.dtable_start()
+        for len in 1..4 {
+            build_chain(&vq, &vec![ChainDescSpec::default(); len]);
         }
 
-        pub fn end(&self) -> GuestAddress {
-            self.used.end()
-        }
+        // The chains borrow the memory, instead of holding a clone of the `Arc`.
+        let mem = q.memory();
+        let chains: Vec<DescriptorChain<&GuestMemoryMmap>> =
+            q.iter_with_memory(&*mem).unwrap().collect();
+        assert_eq!(Arc::strong_count(&mem), 2);
+        let lens: Vec<_> = chains
+            .iter()
+            .map(|chain| (chain.head_index(), chain.clone().count()))
+            .collect();
+        assert_eq!(lens, [(0, 1), (1, 2), (3, 3)]);
+        drop(chains);
+
+        // The chains are consumed like with `iter`.
+        assert_eq!(q.next_avail(), 3);
+        assert_eq!(q.iter().unwrap().count(), 0);
+        q.set_next_avail(1);
+        let chain = q.iter().unwrap().next().unwrap();
+        assert_eq!(Arc::strong_count(&mem), 3);
+        assert_eq!(chain.head_index(), 1);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_used_overflow_check() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-    use test_utils::*;
+        // The check is disabled by default.
+        assert!(!q.used_overflow_check());
+        q.set_event_idx(true);
+        for _ in 0..17 {
+            q.add_used(1, 0x1000).unwrap();
+        }
 
-    use vm_memory::{GuestAddress, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress};
+        let mut q = vq.create_queue(m);
+        q.set_event_idx(true);
+        q.set_used_overflow_check(true);
+        assert!(q.used_overflow_check());
 
-    #[test]
-    pub fn test_offset() {
-        assert_eq!(offset_of!(Descriptor, addr), 0);
-        assert_eq!(offset_of!(Descriptor, len), 8);
-        assert_eq!(offset_of!(Descriptor, flags), 12);
-        assert_eq!(offset_of!(Descriptor, next), 14);
+        // The driver makes no progress, so `used_event` stays at 0.
+        vq.avail.event().store(0);
+        for _ in 0..16 {
+            q.add_used(1, 0x1000).unwrap();
+        }
+        match q.add_used(1, 0x1000) {
+            Err(Error::UsedRingOverflow) => (),
+            _ => panic!("expected a used ring overflow error"),
+        }
+        // The used ring wasn't touched.
+        assert_eq!(vq.used.idx().load(), 16);
+
+        // Once the driver consumes some elements, there's room for as many new ones.
+        vq.avail.event().store(2);
+        q.add_used_batch(&[(1, 0x1000), (1, 0x1000)]).unwrap();
+        match q.add_used_batch(&[(1, 0x1000)]) {
+            Err(Error::UsedRingOverflow) => (),
+            _ => panic!("expected a used ring overflow error"),
+        }
+        assert_eq!(vq.used.idx().load(), 18);
+
+        // The check doesn't apply without EVENT_IDX.
+        q.set_event_idx(false);
+        q.add_used(1, 0x1000).unwrap();
     }
 
     #[test]
-    fn test_checked_new_descriptor_chain() {
+    fn test_validate_avail_idx() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        assert!(vq.end().0 < 0x1000);
+        q.set_next_avail(0xfff8);
+        vq.avail.idx().store(0xfff8u16.wrapping_add(16));
+        q.validate_avail_idx().unwrap();
 
-        // index >= queue_size
-        assert!(
-            DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 16)
-                .next()
-                .is_none()
-        );
+        vq.avail.idx().store(0xfff8u16.wrapping_add(16 + 1));
+        match q.validate_avail_idx() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        #[cfg(feature = "strict")]
+        match q.iter() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        assert_eq!(q.next_avail(), 0xfff8);
+    }
 
-        // desc_table address is way off
-        assert!(
-            DescriptorChain::<&GuestMemoryMmap>::new(m, GuestAddress(0x00ff_ffff_ffff), 16, 0)
-                .next()
-                .is_none()
-        );
+    #[test]
+    fn test_avail_overrun() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert_eq!(q.avail_overrun(), AvailOverrun::Clamp);
 
+        for i in 0..16 {
+            vq.dtable(i).set(0x1000 * u64::from(i + 1), 0x100, 0, 0);
+            vq.avail.ring(i).store(i);
+        }
+        q.set_next_avail(0xfff0);
+        vq.avail.idx().store(0xfff0u16.wrapping_add(16 + 3));
+
+        // Only the next 16 entries are processed.
+        assert_eq!(q.available_descriptor_chains().unwrap(), 16);
+        assert_eq!(q.peek().unwrap().unwrap().head_index(), 0);
+        assert_eq!(q.refresh_avail_idx().unwrap(), Wrapping(0));
+        // With the `strict` feature, `iter` rejects the index regardless of the setting.
+        #[cfg(not(feature = "strict"))]
         {
-            // the first desc has a normal len, and the next_descriptor flag is set
-            vq.dtable(0).addr().store(0x1000);
-            vq.dtable(0).len().store(0x1000);
-            vq.dtable(0).flags().store(VIRTQ_DESC_F_NEXT);
-            //..but the the index of the next descriptor is too large
-            vq.dtable(0).next().store(16);
+            assert_eq!(q.iter().unwrap().count(), 16);
+            assert_eq!(q.next_avail(), 0);
+        }
 
-            let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
-            c.next().unwrap();
-            assert!(c.next().is_none());
+        q.set_next_avail(0xfff0);
+        q.set_avail_overrun(AvailOverrun::Error);
+        assert_eq!(q.avail_overrun(), AvailOverrun::Error);
+        match q.available_descriptor_chains() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
         }
+        match q.iter() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        match q.pop() {
+            Err(Error::InvalidChain) => (),
+            _ => panic!("expected an invalid chain error"),
+        }
+        assert_eq!(q.next_avail(), 0xfff0);
 
-        // finally, let's test an ok chain
-        {
-            vq.dtable(0).next().store(1);
-            vq.dtable(1).set(0x2000, 0x1000, 0, 0);
+        // Exactly 16 chains are fine.
+        vq.avail.idx().store(0);
+        assert_eq!(q.iter().unwrap().count(), 16);
+    }
 
-            let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+    #[test]
+    fn test_max_indirect_table_len() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
+        assert_eq!(q.max_indirect_table_len(), u16::MAX);
 
-            assert_eq!(
-                c.memory() as *const GuestMemoryMmap,
-                m as *const GuestMemoryMmap
-            );
-            assert_eq!(c.desc_table, vq.dtable_start());
-            assert_eq!(c.queue_size, 16);
-            assert_eq!(c.ttl, c.queue_size);
-            let desc = c.next().unwrap();
-            assert_eq!(desc.addr(), GuestAddress(0x1000));
-            assert_eq!(desc.len(), 0x1000);
-            assert_eq!(desc.flags(), VIRTQ_DESC_F_NEXT);
-            assert_eq!(desc.next, 1);
+        // The first chain has an indirect table with 4 descriptors, and the second one with 2.
+        let spec = ChainDescSpec {
+            len: 0x100,
+            writable: false,
+            indirect: true,
+        };
+        build_chain(&vq, &[spec; 4]);
+        build_chain(&vq, &[spec; 2]);
 
-            assert!(c.next().is_some());
-            assert!(c.next().is_none());
+        q.set_max_indirect_table_len(2);
+        assert_eq!(q.max_indirect_table_len(), 2);
+        let mut chains = q.iter().unwrap();
+
+        let mut c = chains.next().unwrap();
+        match c.next_descriptor() {
+            Some(Err(Error::InvalidIndirectDescriptorTable)) => (),
+            _ => panic!("expected an invalid indirect descriptor table error"),
         }
+        // Restarting from the head doesn't bypass the limit.
+        match c.clone_from_head().next_descriptor() {
+            Some(Err(Error::InvalidIndirectDescriptorTable)) => (),
+            _ => panic!("expected an invalid indirect descriptor table error"),
+        }
+
+        let c = chains.next().unwrap();
+        assert_eq!(c.count(), 2);
     }
 
     #[test]
-    fn test_new_from_indirect_descriptor() {
+    fn test_indirect_alias_check() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        // create a chain with two descriptor pointing to an indirect tables
-        let desc = vq.dtable(0);
-        desc.set(0x1000, 0x1000, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT, 1);
-        let desc = vq.dtable(1);
-        desc.set(0x2000, 0x1000, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT, 2);
-        let desc = vq.dtable(2);
-        desc.set(0x3000, 0x1000, 0, 0);
+        // The first chain points to an (aligned) indirect table that overlaps the used ring,
+        // while the second one points to a table in a separate memory area.
+        let table_addr = (vq.used_start().0 + 0x1f) & !0xf;
+        vq.dtable(0).set(table_addr, 0x20, VIRTQ_DESC_F_INDIRECT, 0);
+        vq.dtable(1).set(0x8000, 0x10, VIRTQ_DESC_F_INDIRECT, 0);
+        m.write_obj(Descriptor::new(0x9000, 0x100, 0, 0), GuestAddress(0x8000))
+            .unwrap();
+        vq.avail.ring(0).store(0);
+        vq.avail.ring(1).store(1);
+        vq.avail.idx().store(2);
+
+        // The check is disabled by default.
+        assert!(!q.indirect_alias_check());
+        let mut c = q.peek().unwrap().unwrap();
+        assert!(c.next_descriptor().unwrap().is_ok());
+
+        q.set_indirect_alias_check(true);
+        assert!(q.indirect_alias_check());
+        let mut chains = q.iter().unwrap();
+
+        let mut c = chains.next().unwrap();
+        match c.next_descriptor() {
+            Some(Err(Error::InvalidIndirectDescriptorTable)) => (),
+            _ => panic!("expected an invalid indirect descriptor table error"),
+        }
+        // Restarting from the head doesn't bypass the check.
+        match c.clone_from_head().next_descriptor() {
+            Some(Err(Error::InvalidIndirectDescriptorTable)) => (),
+            _ => panic!("expected an invalid indirect descriptor table error"),
+        }
 
-        let mut c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 0);
+        let mut c = chains.next().unwrap();
+        assert_eq!(
+            c.next_descriptor().unwrap().unwrap().addr(),
+            GuestAddress(0x9000)
+        );
+        assert!(c.next_descriptor().is_none());
+    }
 
-        // The chain logic hasn't parsed the indirect descriptor yet.
-        assert!(!c.is_indirect);
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        let region = m.find_region(GuestAddress(0)).unwrap();
-        let dtable = region
-            .get_slice(MemoryRegionAddress(0x1000u64), VirtqDesc::dtable_len(4))
-            .unwrap();
-        // create an indirect table with 4 chained descriptors
-        let mut indirect_table = Vec::with_capacity(4_usize);
-        for j in 0..4 {
-            let desc = VirtqDesc::new(&dtable, j);
-            if j < 3 {
-                desc.set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, (j + 1) as u16);
-            } else {
-                desc.set(0x1000, 0x1000, 0, 0_u16);
-            }
-            indirect_table.push(desc);
+        let spec = ChainDescSpec {
+            len: 0x100,
+            writable: true,
+            indirect: false,
+        };
+        for _ in 0..3 {
+            build_chain(&vq, &[spec, spec]);
         }
 
-        let dtable2 = region
-            .get_slice(MemoryRegionAddress(0x2000u64), VirtqDesc::dtable_len(1))
+        // Peeking and snapshots don't count as processing.
+        q.peek().unwrap().unwrap();
+        q.snapshot_available().unwrap();
+        assert_eq!(q.metrics().chains_processed(), 0);
+
+        let heads: Vec<_> = q
+            .iter()
+            .unwrap()
+            .map(|chain| {
+                assert_eq!(chain.clone().count(), 2);
+                chain.head_index()
+            })
+            .collect();
+        assert_eq!(q.metrics().chains_processed(), 3);
+
+        // Chains left in the available ring by `process` are not counted.
+        build_chain(&vq, &[spec]);
+        for _ in 0..2 {
+            assert!(q.process(|_| Err(Error::InvalidChain)).is_err());
+        }
+        assert_eq!(q.metrics().chains_processed(), 3);
+        assert_eq!(q.process(|_| Ok(())).unwrap(), 1);
+        assert_eq!(q.metrics().chains_processed(), 4);
+
+        // The same goes for the chain rejected by `take_while_chain`.
+        build_chain(&vq, &[spec]);
+        let iter = q.iter().unwrap().take_while_chain(|_| false);
+        assert_eq!(iter.count(), 0);
+        assert_eq!(q.metrics().chains_processed(), 4);
+        assert_eq!(q.iter().unwrap().count(), 1);
+        assert_eq!(q.metrics().chains_processed(), 5);
+
+        q.add_used(heads[0], 0x100).unwrap();
+        q.add_used_batch(&[(heads[1], 0x100), (heads[2], 0x100)])
             .unwrap();
-        let desc2 = VirtqDesc::new(&dtable2, 0);
-        desc2.set(0x8000, 0x1000, 0, 0);
-
-        assert_eq!(c.head_index(), 0);
-        // try to iterate through the first indirect descriptor chain
-        for j in 0..4 {
-            let desc = c.next().unwrap();
-            assert!(c.is_indirect);
-            if j < 3 {
-                assert_eq!(desc.flags(), VIRTQ_DESC_F_NEXT);
-                assert_eq!(desc.next, j + 1);
-            }
+        // Failed calls are not counted.
+        assert!(q.add_used(16, 0x100).is_err());
+        assert_eq!(q.metrics().used_elements(), 3);
+
+        assert!(q.needs_notification().unwrap());
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert!(!q.needs_notification().unwrap());
+        vq.avail.flags().store(0);
+        assert!(q.needs_notification().unwrap());
+        assert_eq!(q.metrics().notifications_requested(), 2);
+        assert_eq!(q.metrics().suppressed_notifications(), 0);
+
+        // A chain whose second descriptor points to an indirect table outside guest memory.
+        let head = build_chain(&vq, &[spec, spec]);
+        let second = vq.dtable(head).next().load();
+        vq.dtable(second)
+            .set(0x1_0000, 0x100, VIRTQ_DESC_F_INDIRECT, 0);
+        let mut chain = q.pop().unwrap().unwrap();
+        assert!(chain.next_descriptor().unwrap().is_ok());
+        assert_eq!(q.metrics().desc_read_errors(), 0);
+        match chain.next_descriptor() {
+            Some(Err(Error::GuestMemory(_))) => (),
+            _ => panic!("expected a guest memory error"),
         }
+        assert_eq!(q.metrics().chains_processed(), 6);
+        assert_eq!(q.metrics().desc_read_errors(), 1);
     }
 
+    #[cfg(feature = "metrics")]
     #[test]
-    fn test_indirect_descriptor_err() {
-        {
-            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-            let vq = VirtQueue::new(GuestAddress(0), m, 16);
-
-            // create a chain with a descriptor pointing to an indirect table
-            let desc = vq.dtable(0);
-            desc.set(0x1001, 0x1000, VIRTQ_DESC_F_INDIRECT, 0);
-
-            let mut c: DescriptorChain<&GuestMemoryMmap> =
-                DescriptorChain::new(m, vq.start(), 16, 0);
-
-            assert!(c.next().is_none());
-        }
+    fn test_suppressed_notifications() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        {
-            let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-            let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        // Notifications are never counted as suppressed without EVENT_IDX.
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert!(!q.needs_notification().unwrap());
+        assert_eq!(q.metrics().suppressed_notifications(), 0);
 
-            // create a chain with a descriptor pointing to an indirect table
-            let desc = vq.dtable(0);
-            desc.set(0x1000, 0x1001, VIRTQ_DESC_F_INDIRECT, 0);
+        q.set_event_idx(true);
+        vq.avail.event().store(3);
 
-            let mut c: DescriptorChain<&GuestMemoryMmap> =
-                DescriptorChain::new(m, vq.start(), 16, 0);
+        // The first call always notifies.
+        assert!(q.needs_notification().unwrap());
+        assert_eq!(q.metrics().suppressed_notifications(), 0);
 
-            assert!(c.next().is_none());
+        // `used_event` isn't crossed for the next three used elements.
+        for i in 1..4 {
+            q.next_used = Wrapping(i);
+            assert!(!q.needs_notification().unwrap());
+            assert_eq!(q.metrics().suppressed_notifications(), u64::from(i));
         }
+
+        // Crossing `used_event` notifies again and leaves the counter untouched.
+        q.next_used = Wrapping(4);
+        assert!(q.needs_notification().unwrap());
+        assert_eq!(q.metrics().suppressed_notifications(), 3);
+
+        // Nothing new was used, so this is suppressed as well.
+        assert!(!q.needs_notification().unwrap());
+        assert_eq!(q.metrics().suppressed_notifications(), 4);
+
+        // Cloned queues share the counters.
+        let mut clone = q.clone();
+        assert!(!clone.needs_notification().unwrap());
+        assert_eq!(q.metrics().suppressed_notifications(), 5);
     }
 
+    #[cfg(feature = "eventfd")]
     #[test]
-    fn test_queue_and_iterator() {
+    fn test_signal_used() {
+        use vmm_sys_util::eventfd::EFD_NONBLOCK;
+
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
-
         let mut q = vq.create_queue(m);
 
-        // q is currently valid
-        assert!(q.is_valid());
-
-        // shouldn't be valid when not marked as ready
-        q.ready = false;
-        assert!(!q.is_valid());
-        q.ready = true;
-
-        // or when size > max_size
-        q.size = q.max_size << 1;
-        assert!(!q.is_valid());
-        q.size = q.max_size;
-
-        // or when size is 0
-        q.size = 0;
-        assert!(!q.is_valid());
-        q.size = q.max_size;
-
-        // or when size is not a power of 2
-        q.size = 11;
-        assert!(!q.is_valid());
-        q.size = q.max_size;
+        // Nothing happens without an event.
+        q.signal_used().unwrap();
 
-        // or if the various addresses are off
-
-        q.desc_table = GuestAddress(0xffff_ffff);
-        assert!(!q.is_valid());
-        q.desc_table = GuestAddress(0x1001);
-        assert!(!q.is_valid());
-        q.desc_table = vq.dtable_start();
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        q.set_interrupt_evt(evt.try_clone().unwrap());
 
-        q.avail_ring = GuestAddress(0xffff_ffff);
-        assert!(!q.is_valid());
-        q.avail_ring = GuestAddress(0x1001);
-        assert!(!q.is_valid());
-        q.avail_ring = vq.avail_start();
+        q.add_used(1, 0x1000).unwrap();
+        q.signal_used().unwrap();
+        assert_eq!(evt.read().unwrap(), 1);
 
-        q.used_ring = GuestAddress(0xffff_ffff);
-        assert!(!q.is_valid());
-        q.used_ring = GuestAddress(0x1001);
-        assert!(!q.is_valid());
-        q.used_ring = vq.used_start();
+        // The driver doesn't want to be interrupted.
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        q.add_used(1, 0x1000).unwrap();
+        q.signal_used().unwrap();
+        assert!(evt.read().is_err());
+        vq.avail.flags().store(0);
 
-        {
-            // an invalid queue should return an iterator with no next
-            q.ready = false;
-            let mut i = q.iter().unwrap();
-            assert!(i.next().is_none());
+        // With EVENT_IDX, the event is written exactly when a notification is needed.
+        q.set_event_idx(true);
+        vq.avail.event().store(3);
+        for i in 0..6u16 {
+            q.add_used(1, 0x1000).unwrap();
+            let expected = q.clone().needs_notification().unwrap();
+            q.signal_used().unwrap();
+            assert_eq!(evt.read().is_ok(), expected, "iteration {}", i);
         }
+    }
 
-        q.ready = true;
-
-        // now let's create two simple descriptor chains
+    #[test]
+    fn test_used_ring_handle() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        {
-            for j in 0..5 {
-                vq.dtable(j).set(
-                    0x1000 * (j + 1) as u64,
-                    0x1000,
-                    VIRTQ_DESC_F_NEXT,
-                    (j + 1) as u16,
-                );
-            }
+        let heads: Vec<u16> = (0..4)
+            .map(|_| build_chain(&vq, &[ChainDescSpec::default()]))
+            .collect();
+        assert_eq!(q.pop().unwrap().unwrap().head_index(), heads[0]);
+        q.add_used(heads[0], 0x10).unwrap();
+
+        // The used ring picks up from the position of the queue.
+        let (mut avail, mut used) = q.split();
+        assert_eq!(used.next_used(), 1);
+        assert_eq!(avail.next_avail(), 1);
+
+        // Used buffers are published from another thread, while the available ring is
+        // processed.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(used.add_used(heads[1], 0x20).unwrap(), 2);
+                used.add_used_batch(&[(heads[2], 0x30), (heads[3], 0x40)])
+                    .unwrap();
+                match used.add_used(16, 0) {
+                    Err(Error::InvalidDescriptorIndex) => (),
+                    _ => panic!("expected an invalid descriptor index error"),
+                }
+            });
+            assert_eq!(avail.iter().unwrap().count(), 3);
+        });
+        assert_eq!(used.next_used(), 4);
+        assert_eq!(vq.used.idx().load(), 4);
+        for (i, &head) in heads.iter().enumerate() {
+            let elem = vq.used.ring(i as u16).load();
+            assert_eq!(elem.id(), u32::from(head));
+            assert_eq!(elem.len(), 0x10 * (i as u32 + 1));
+        }
 
-            // the chains are (0, 1) and (2, 3, 4)
-            vq.dtable(1).flags().store(0);
-            vq.dtable(4).flags().store(0);
-            vq.avail.ring(0).store(0);
-            vq.avail.ring(1).store(2);
-            vq.avail.idx().store(2);
+        // Notifications follow the same rules as for the queue.
+        assert!(used.needs_notification().unwrap());
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert!(!used.needs_notification().unwrap());
 
-            let mut i = q.iter().unwrap();
+        // Joining the halves gives back a queue with the updates made through both.
+        let mut q = avail.join(used);
+        assert_eq!(q.next_avail(), 4);
+        assert_eq!(q.next_used(), 4);
+        assert_eq!(q.in_flight, 0);
+        assert_eq!(q.add_used(heads[0], 0).unwrap(), 5);
 
-            {
-                let mut c = i.next().unwrap();
-                assert_eq!(c.head_index(), 0);
+        q.set_event_idx(true);
+        let (_, mut used) = q.split();
+        vq.avail.event().store(7);
+        assert!(used.needs_notification().unwrap());
+        used.add_used(heads[0], 0).unwrap();
+        assert!(!used.needs_notification().unwrap());
+        vq.avail.event().store(6);
+        used.add_used(heads[1], 0).unwrap();
+        assert!(used.needs_notification().unwrap());
+    }
 
-                c.next().unwrap();
-                assert!(c.next().is_some());
-                assert!(c.next().is_none());
-                assert_eq!(c.head_index(), 0);
-            }
+    #[test]
+    fn test_used_ring_handle_settings() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-            {
-                let mut c = i.next().unwrap();
-                assert_eq!(c.head_index(), 2);
+        // The heads 3, 5 and 7 are made available, in this order.
+        for (i, &head) in [3u16, 5, 7].iter().enumerate() {
+            vq.avail.ring(i as u16).store(head);
+        }
+        vq.avail.idx().store(3);
 
-                c.next().unwrap();
-                c.next().unwrap();
-                c.next().unwrap();
-                assert!(c.next().is_none());
-                assert_eq!(c.head_index(), 2);
-            }
+        // The handle inherits VIRTIO_F_IN_ORDER from the queue.
+        q.set_in_order(true);
+        let (_, mut used) = q.split();
+        match used.add_used(5, 0x100) {
+            Err(Error::UsedOutOfOrder) => (),
+            _ => panic!("expected an out of order error"),
         }
+        used.add_used_batch(&[(3, 0x100), (5, 0x200), (7, 0x300)])
+            .unwrap();
+        assert_eq!(vq.used.idx().load(), 3);
+        assert_eq!(vq.used.ring(0).load().id(), 0);
+        assert_eq!(vq.used.ring(2).load().id(), 7);
 
-        // also test go_to_previous_position() works as expected
-        {
-            assert!(q.iter().unwrap().next().is_none());
-            q.go_to_previous_position();
-            let mut c = q.iter().unwrap().next().unwrap();
-            c.next().unwrap();
-            c.next().unwrap();
-            c.next().unwrap();
-            assert!(c.next().is_none());
+        // Notifications are coalesced like for the queue.
+        let mut q = vq.create_queue(m);
+        q.set_notification_coalescing(2);
+        let (_, mut used) = q.split();
+        used.add_used(3, 0).unwrap();
+        assert!(!used.needs_notification().unwrap());
+        used.add_used(5, 0).unwrap();
+        assert!(used.needs_notification().unwrap());
+
+        // The used ring overflow check applies as well.
+        let mut q = vq.create_queue(m);
+        q.set_event_idx(true);
+        q.set_used_overflow_check(true);
+        vq.avail.event().store(0);
+        let (_, mut used) = q.split();
+        used.add_used_batch(&[(1, 0); 16]).unwrap();
+        match used.add_used(1, 0) {
+            Err(Error::UsedRingOverflow) => (),
+            _ => panic!("expected a used ring overflow error"),
         }
+        assert_eq!(used.next_used(), 16);
     }
 
     #[test]
-    fn test_descriptor_and_iterator() {
+    fn test_dump() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
-
         let mut q = vq.create_queue(m);
 
-        // q is currently valid
-        assert!(q.is_valid());
-
-        for j in 0..7 {
-            vq.dtable(j).set(
-                0x1000 * (j + 1) as u64,
-                0x1000,
-                VIRTQ_DESC_F_NEXT,
-                (j + 1) as u16,
-            );
+        for _ in 0..3 {
+            build_chain(&vq, &[ChainDescSpec::default()]);
         }
+        let head = q.pop().unwrap().unwrap().head_index();
+        q.add_used(head, 0).unwrap();
+        q.pop().unwrap().unwrap();
+        q.set_event_idx(true);
 
-        // the chains are (0, 1), (2, 3, 4) and (5, 6)
-        vq.dtable(1).flags().store(0);
-        vq.dtable(2)
-            .flags()
-            .store(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
-        vq.dtable(4).flags().store(VIRTQ_DESC_F_WRITE);
-        vq.dtable(5)
-            .flags()
-            .store(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
-        vq.dtable(6).flags().store(0);
-        vq.avail.ring(0).store(0);
-        vq.avail.ring(1).store(2);
-        vq.avail.ring(2).store(5);
-        vq.avail.idx().store(3);
-
-        let mut i = q.iter().unwrap();
-
-        {
-            let c = i.next().unwrap();
-            assert_eq!(c.head_index(), 0);
+        let dump = q.dump().unwrap();
+        assert_eq!(dump.addresses, q.ring_addresses());
+        assert_eq!(dump.avail_idx, 3);
+        assert_eq!(dump.used_idx, 1);
+        assert_eq!(dump.next_avail, 2);
+        assert_eq!(dump.next_used, 1);
+        assert!(dump.event_idx_enabled);
+        assert_eq!(
+            format!("{}", dump),
+            format!(
+                "size 16, desc_table {:#x}, avail_ring {:#x}, used_ring {:#x}, \
+                 avail idx 3 (next_avail 2), used idx 1 (next_used 1), event_idx enabled",
+                vq.start().raw_value(),
+                vq.avail_start().raw_value(),
+                vq.used_start().raw_value()
+            )
+        );
 
-            let mut iter = c;
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_none());
-            assert!(iter.next().is_none());
+        // The rings have to be mapped.
+        q.used_ring = GuestAddress(0x20000);
+        match q.dump() {
+            Err(Error::GuestMemory(_)) => (),
+            _ => panic!("expected a guest memory error"),
         }
+    }
 
-        {
-            let c = i.next().unwrap();
-            assert_eq!(c.head_index(), 2);
+    #[test]
+    fn test_would_notify() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-            let mut iter = c.writable();
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_none());
-            assert!(iter.next().is_none());
-        }
+        let check = |q: &mut Queue<&GuestMemoryMmap>| {
+            let expected = q.would_notify().unwrap();
+            // Asking again doesn't change the answer.
+            assert_eq!(q.would_notify().unwrap(), expected);
+            assert_eq!(q.needs_notification().unwrap(), expected);
+            expected
+        };
 
-        {
-            let c = i.next().unwrap();
-            assert_eq!(c.head_index(), 5);
+        assert!(check(&mut q));
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert!(!check(&mut q));
+        vq.avail.flags().store(0);
 
-            let mut iter = c.readable();
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_none());
-            assert!(iter.next().is_none());
+        q.set_event_idx(true);
+        vq.avail.event().store(3);
+        let mut notified = Vec::new();
+        for i in 0..8u16 {
+            q.next_used = Wrapping(i);
+            if check(&mut q) {
+                notified.push(i);
+            }
+            if i == 4 {
+                vq.avail.event().store(6);
+            }
         }
+        assert_eq!(notified, [0, 4, 7]);
+
+        // The used ring index is not remembered.
+        q.next_used = Wrapping(9);
+        vq.avail.event().store(8);
+        assert!(q.would_notify().unwrap());
+        assert!(q.would_notify().unwrap());
+        assert!(q.needs_notification().unwrap());
+        assert!(!q.would_notify().unwrap());
     }
 
     #[test]
-    fn test_add_used() {
+    fn test_needs_notification_no_interrupt() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
-
         let mut q = vq.create_queue(m);
-        assert_eq!(vq.used.idx().load(), 0);
 
-        //index too large
-        assert!(q.add_used(16, 0x1000).is_err());
-        assert_eq!(vq.used.idx().load(), 0);
+        // The flag is clear.
+        assert!(q.needs_notification().unwrap());
 
-        //should be ok
+        // The flag is set.
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert!(!q.needs_notification().unwrap());
         q.add_used(1, 0x1000).unwrap();
-        assert_eq!(q.next_used, Wrapping(1));
-        assert_eq!(vq.used.idx().load(), 1);
-        let x = vq.used.ring(0).load();
-        assert_eq!(x.id, 1);
-        assert_eq!(x.len, 0x1000);
+        assert!(!q.needs_notification().unwrap());
+
+        // The flag is cleared again.
+        vq.avail.flags().store(0);
+        assert!(q.needs_notification().unwrap());
+
+        // The flag is ignored when VIRTIO_F_EVENT_IDX is negotiated.
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        q.set_event_idx(true);
+        assert!(!q.honor_avail_flags_with_event_idx());
+        assert!(q.needs_notification().unwrap());
     }
 
     #[test]
-    fn test_reset_queue() {
+    fn test_honor_avail_flags_with_event_idx() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = VirtQueue::new(GuestAddress(0), m, 16);
-
         let mut q = vq.create_queue(m);
-        q.size = 8;
-        q.ready = true;
-        q.reset();
-        assert_eq!(q.size, 16);
-        assert_eq!(q.ready, false);
+        q.set_event_idx(true);
+        q.set_honor_avail_flags_with_event_idx(true);
+        assert!(q.honor_avail_flags_with_event_idx());
+
+        // The flag suppresses notifications, even if `used_event` asks for one.
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        vq.avail.event().store(0);
+        q.add_used(1, 0x1000).unwrap();
+        assert!(!q.needs_notification().unwrap());
+        q.add_used(1, 0x1000).unwrap();
+        assert!(!q.needs_notification().unwrap());
+
+        // Once the flag is cleared, `used_event` governs notifications again.
+        vq.avail.flags().store(0);
+        assert!(q.needs_notification().unwrap());
+        q.add_used(1, 0x1000).unwrap();
+        assert!(!q.needs_notification().unwrap());
+        vq.avail.event().store(3);
+        q.add_used(1, 0x1000).unwrap();
+        assert!(q.needs_notification().unwrap());
     }
 
     #[test]
-    fn test_needs_notification() {
+    fn test_avail_flags() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let qsize = 16;
-        let vq = VirtQueue::new(GuestAddress(0), m, qsize);
-        let mut q = vq.create_queue(&m);
-        let avail_addr = vq.avail_start();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        // It should always return true when EVENT_IDX isn't enabled.
-        for i in 0..qsize {
-            q.next_used = Wrapping(i);
-            assert_eq!(q.needs_notification().unwrap(), true);
+        assert_eq!(q.avail_flags(Ordering::Acquire).unwrap(), 0);
+        vq.avail.flags().store(VIRTQ_AVAIL_F_NO_INTERRUPT);
+        assert_eq!(
+            q.avail_flags(Ordering::Acquire).unwrap(),
+            VIRTQ_AVAIL_F_NO_INTERRUPT
+        );
+        // Unknown flags are returned as they are.
+        vq.avail.flags().store(0x8001);
+        assert_eq!(q.avail_flags(Ordering::Relaxed).unwrap(), 0x8001);
+
+        q.avail_ring = GuestAddress(0x10_0000);
+        match q.avail_flags(Ordering::Acquire) {
+            Err(Error::GuestMemory(_)) => (),
+            _ => panic!("expected a guest memory error"),
         }
+    }
 
-        m.write_obj::<u16>(4, avail_addr.unchecked_add(4 + qsize as u64 * 2))
-            .unwrap();
-        q.set_event_idx(true);
-
-        // Incrementing up to this value causes an `u16` to wrap back to 0.
-        let wrap = u32::from(u16::MAX) + 1;
-
-        for i in 0..wrap + 12 {
-            q.next_used = Wrapping(i as u16);
-            // Let's test wrapping around the maximum index value as well.
-            let expected = i == 5 || i == (5 + wrap) || q.signalled_used.is_none();
-            assert_eq!(q.needs_notification().unwrap(), expected);
-        }
+    #[test]
+    fn test_interrupts_masked() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue(m);
 
-        m.write_obj::<u16>(8, avail_addr.unchecked_add(4 + 16 * 2))
-            .unwrap();
+        assert!(!q.interrupts_masked().unwrap());
+        q.disable_notification().unwrap();
+        assert!(q.interrupts_masked().unwrap());
+        q.enable_notification().unwrap();
+        assert!(!q.interrupts_masked().unwrap());
 
-        // Returns `false` because `signalled_used` already passed this value.
-        assert_eq!(q.needs_notification().unwrap(), false);
+        // The value is read from guest memory.
+        vq.used.flags().store(VIRTQ_USED_F_NO_NOTIFY);
+        assert!(q.interrupts_masked().unwrap());
 
-        m.write_obj::<u16>(15, avail_addr.unchecked_add(4 + 16 * 2))
-            .unwrap();
-        assert_eq!(q.needs_notification().unwrap(), false);
-        q.next_used = Wrapping(15);
-        assert_eq!(q.needs_notification().unwrap(), false);
-        q.next_used = Wrapping(0);
-        assert_eq!(q.needs_notification().unwrap(), true);
-        assert_eq!(q.needs_notification().unwrap(), false);
+        // The flag is meaningless with EVENT_IDX.
+        q.set_event_idx(true);
+        assert!(!q.interrupts_masked().unwrap());
+
+        // Errors accessing the used ring are propagated.
+        q.set_event_idx(false);
+        q.used_ring = GuestAddress(0x10_0000);
+        match q.interrupts_masked() {
+            Err(Error::GuestMemory(_)) => (),
+            _ => panic!("expected a guest memory error"),
+        }
     }
 
     #[test]
@@ -1405,7 +9139,7 @@ mod tests {
         let mut q = vq.create_queue(&m);
         let used_addr = vq.used_start();
 
-        assert_eq!(q.event_idx_enabled, false);
+        assert_eq!(q.features.has_event_idx(), false);
 
         q.enable_notification().unwrap();
         let v = m.read_obj::<u16>(used_addr).unwrap();